@@ -1,5 +1,38 @@
 use crate::{stereo_sample::StereoSample, traits::AudioProcessor};
 
+// How far a curve amount of +-1 bends a stage away from a straight line.
+const CURVE_STEEPNESS: f32 = 6.0;
+
+// Retrigger time is to avoid pops, when a voice is stolen.
+// On the retrigger, you may go from being in the middle of a envelope
+// to the start of the attack phase, a sharp change in envelope, causing a pop.
+const RETRIGGER_S: f32 = 0.01; // 10ms
+
+/// Maps linear progress through a stage (0..1) onto a curved one (also 0..1).
+/// Positive amounts bend exponentially (slow start, fast finish), negative amounts
+/// logarithmically (fast start, slow finish) and zero is linear.
+fn curve(progress: f32, amount: f32) -> f32 {
+    let k = amount * CURVE_STEEPNESS;
+
+    if k.abs() < 1e-3 {
+        return progress;
+    }
+
+    ((k * progress).exp() - 1.0) / (k.exp() - 1.0)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AdsrStage {
+    /// Silent, waiting for a trigger.
+    Idle,
+    /// Fading out whatever was playing before a retrigger, before the attack starts.
+    Retrigger,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
 #[derive(Clone)]
 pub struct AdsrEnvelope {
     // Envelope parameters (_s = in seconds)
@@ -8,12 +41,25 @@ pub struct AdsrEnvelope {
     sustain_level: f32, // Note: sustain isn't time based
     release_s: f32,
 
-    // Current time in seconds since note-on
-    current_time_s: f32,
+    // Modulation of the attack, decay and release times, as a multiple of their lengths
+    time_scales: [f32; 3],
+
+    // Curve amount per stage (-1 = logarithmic, 0 = linear, 1 = exponential)
+    attack_curve: f32,
+    decay_curve: f32,
+    release_curve: f32,
+
+    // When set, the release decays like a discharging capacitor instead of following
+    // `release_curve`, reaching `release_threshold` after `release_s` and cutting to silence there.
+    rc_release: bool,
+    // Linear amplitude relative to the level the release started at
+    release_threshold: f32,
+
+    stage: AdsrStage,
+    // How far through the current stage we are (0..1). Progress rather than time, so changing a
+    // stage's length halfway through carries on from the same level instead of jumping.
+    stage_progress: f32,
 
-    pub is_released: bool,
-    // The time (in seconds) when release started
-    release_start_time_s: f32,
     // The amplitude at the exact moment release started.
     release_start_amp: f32,
 
@@ -31,9 +77,14 @@ impl AdsrEnvelope {
             decay_s,
             sustain_level,
             release_s,
-            current_time_s: 0.0,
-            is_released: false,
-            release_start_time_s: 0.0,
+            time_scales: [1.0; 3],
+            attack_curve: 0.0,
+            decay_curve: 0.0,
+            release_curve: 0.0,
+            rc_release: false,
+            release_threshold: 0.001, // -60dB
+            stage: AdsrStage::Idle,
+            stage_progress: 0.0,
             release_start_amp: 0.0,
             retrigger_start_amp: 0.0,
             sample_rate,
@@ -43,18 +94,14 @@ impl AdsrEnvelope {
     /// Called when the note first starts (Either first trigger, or retrigger).
     pub fn trigger(&mut self) {
         self.retrigger_start_amp = self.get_amplitude();
-        self.current_time_s = 0.0;
-        self.is_released = false;
-        self.release_start_time_s = 0.0;
-        self.release_start_amp = 0.0;
+        self.enter_stage(AdsrStage::Retrigger);
     }
 
     /// Called when the note ends (begin release stage).
     pub fn release(&mut self) {
-        if !self.is_released {
-            self.release_start_time_s = self.current_time_s;
+        if !matches!(self.stage, AdsrStage::Idle | AdsrStage::Release) {
             self.release_start_amp = self.get_amplitude();
-            self.is_released = true;
+            self.enter_stage(AdsrStage::Release);
         }
     }
 
@@ -74,69 +121,146 @@ impl AdsrEnvelope {
         self.release_s = release_s;
     }
 
+    /// Stretches (above 1) or shortens (below 1) the attack, decay and release, on top of their
+    /// own lengths.
+    pub fn set_time_scales(&mut self, attack: f32, decay: f32, release: f32) {
+        self.time_scales = [attack, decay, release];
+    }
+
+    pub fn set_attack_curve(&mut self, amount: f32) {
+        self.attack_curve = amount;
+    }
+
+    pub fn set_decay_curve(&mut self, amount: f32) {
+        self.decay_curve = amount;
+    }
+
+    pub fn set_release_curve(&mut self, amount: f32) {
+        self.release_curve = amount;
+    }
+
+    pub fn set_rc_release(&mut self, rc_release: bool) {
+        self.rc_release = rc_release;
+    }
+
+    /// Sets the (linear) level, relative to the start of the release, at which an RC release
+    /// is cut off.
+    pub fn set_release_threshold(&mut self, threshold: f32) {
+        self.release_threshold = threshold;
+    }
+
+    pub fn stage(&self) -> AdsrStage {
+        self.stage
+    }
+
+    /// Returns `true` from the moment the note is released until the envelope is triggered again
+    /// or has finished.
+    pub fn is_released(&self) -> bool {
+        self.stage == AdsrStage::Release
+    }
+
     /// Return the current amplitude (0..1).
     pub fn get_amplitude(&self) -> f32 {
-        if self.is_released {
-            // Time since release triggered
-            let time_releasing_s = self.current_time_s - self.release_start_time_s;
-            // Fade from release_start_amp to 0 over 'release' seconds. Ensure we check that we don't go over 0.
-            let ratio_released = time_releasing_s / self.release_s;
-
-            if ratio_released > 1.0 {
-                return 0.0;
-            }
+        let progress = self.stage_progress;
 
-            return self.release_start_amp * (1.0 - ratio_released);
-        }
-
-        // Retrigger time is to avoid pops, when a voice is stolen.
-        // On the retrigger, you may go from being in the middle of a envelope
-        // to the start of the attack phase, a sharp change in envelope, causing a pop.
-        // TODO: Something that would be really good is if the retrigger wasn't fixed, and rather was based on how
-        // close to 0 it is, this may reduce latency.
-        let retrigger_s = 0.01; // 10ms
-        
-        if self.current_time_s <= retrigger_s {
-            let ratio_retriggered =  self.current_time_s / retrigger_s;
-    
-            if ratio_retriggered > 1.0 {
-                panic!("ratio_retriggered of '{}' should not be more than 1, we are likely in the wrong stage", ratio_retriggered);
+        match self.stage {
+            AdsrStage::Idle => 0.0,
+            AdsrStage::Retrigger => self.retrigger_start_amp * (1.0 - progress),
+            AdsrStage::Attack => curve(progress, self.attack_curve),
+            AdsrStage::Decay => {
+                // Decay: amplitude from 1..sustain
+                self.sustain_level + (1.0 - self.sustain_level) * curve(1.0 - progress, self.decay_curve)
+            }
+            AdsrStage::Sustain => self.sustain_level,
+            AdsrStage::Release => {
+                if self.rc_release {
+                    // exp(-t / tau), with tau chosen so the threshold is reached right at the end
+                    self.release_start_amp * self.release_threshold.powf(progress)
+                } else {
+                    self.release_start_amp * curve(1.0 - progress, self.release_curve)
+                }
             }
-            
-            return self.retrigger_start_amp * (1.0 - ratio_retriggered);
         }
-        
-        let attack_end_time_s = retrigger_s + self.attack_s;
+    }
+
+    /// Returns `true` if the envelope has completely finished its release.
+    pub fn is_done(&self) -> bool {
+        self.stage == AdsrStage::Idle
+    }
 
-        if self.current_time_s <= attack_end_time_s {
-            // Get how far through attack we are in seconds.
-            let time_attacking_s = self.current_time_s - retrigger_s;
-            // Get a ratio so we can calculate progress
-            return time_attacking_s / self.attack_s;
+    /// Returns `true` if the envelope could reach the end of its release, and fall silent, within
+    /// the next `samples` samples, as long as nothing changes in the meantime.
+    pub fn may_finish_within(&self, samples: usize) -> bool {
+        if self.stage != AdsrStage::Release {
+            return false;
         }
 
-        let decay_end_time_s = attack_end_time_s + self.decay_s;
+        let Some(length_s) = self.stage_length_s(self.stage).filter(|&length_s| length_s > 0.0) else {
+            return true;
+        };
 
-        if self.current_time_s <= decay_end_time_s {
-            // Decay: amplitude from 1..sustain
-            let time_decaying = self.current_time_s - attack_end_time_s;
-            let decay_ratio = time_decaying / self.decay_s; // 0..1
+        // Steps through exactly as `advance()` would, so rounding can't put the end a sample out
+        let increment = 1.0 / (length_s * self.sample_rate);
+        let mut progress = self.stage_progress;
+        (0..samples).any(|_| {
+            progress += increment;
+            progress >= 1.0
+        })
+    }
 
-            if decay_ratio > 1.0 {
-                panic!("dec_ratio of '{}' should not be more than 1, we are likely in the wrong stage", decay_ratio);
-            }
+    /// How long the given stage lasts in seconds, or `None` if it only ends on a trigger or
+    /// release.
+    fn stage_length_s(&self, stage: AdsrStage) -> Option<f32> {
+        match stage {
+            AdsrStage::Idle | AdsrStage::Sustain => None,
+            // Nothing to fade out when the envelope was already silent
+            AdsrStage::Retrigger if self.retrigger_start_amp <= 0.0 => Some(0.0),
+            AdsrStage::Retrigger => Some(RETRIGGER_S),
+            AdsrStage::Attack => Some(self.attack_s * self.time_scales[0]),
+            AdsrStage::Decay => Some(self.decay_s * self.time_scales[1]),
+            AdsrStage::Release => Some(self.release_s * self.time_scales[2]),
+        }
+    }
 
-            return 1.0 - (1.0 - self.sustain_level) * decay_ratio;
+    fn next_stage(stage: AdsrStage) -> AdsrStage {
+        match stage {
+            AdsrStage::Idle => AdsrStage::Idle,
+            AdsrStage::Retrigger => AdsrStage::Attack,
+            AdsrStage::Attack => AdsrStage::Decay,
+            AdsrStage::Decay => AdsrStage::Sustain,
+            AdsrStage::Sustain => AdsrStage::Sustain,
+            AdsrStage::Release => AdsrStage::Idle,
         }
+    }
 
-        self.sustain_level
+    /// Moves to `stage`, passing straight through any stages with no length.
+    fn enter_stage(&mut self, stage: AdsrStage) {
+        self.stage = stage;
+        self.stage_progress = 0.0;
+
+        // A NaN length is skipped too, rather than getting stuck in that stage
+        while self.stage_length_s(self.stage).is_some_and(|length_s| length_s.is_nan() || length_s <= 0.0) {
+            self.stage = Self::next_stage(self.stage);
+        }
     }
 
-    /// Returns `true` if the envelope has completely finished its release.
-    pub fn is_done(&self) -> bool {
-        // The envelope is done if we're in the released phase AND
-        // the time since release started exceeds the release duration.
-        self.is_released && (self.current_time_s - self.release_start_time_s) >= self.release_s
+    /// Moves the envelope on by one sample.
+    fn advance(&mut self) {
+        let Some(length_s) = self.stage_length_s(self.stage) else {
+            return;
+        };
+
+        // Recomputed every sample so parameter changes take effect mid-stage
+        if length_s > 0.0 {
+            self.stage_progress += 1.0 / (length_s * self.sample_rate);
+        } else {
+            // Shortened to nothing (or NaN) halfway through, so it ends right away
+            self.stage_progress = 1.0;
+        }
+
+        if self.stage_progress >= 1.0 {
+            self.enter_stage(Self::next_stage(self.stage));
+        }
     }
 }
 
@@ -145,9 +269,232 @@ impl AudioProcessor for AdsrEnvelope {
         // Get current amplitude
         let amp = self.get_amplitude();
 
-        // Advance time by one sample
-        self.current_time_s += 1.0 / self.sample_rate;
+        // Advance by one sample
+        self.advance();
 
         StereoSample { left: (input.left * amp), right: (input.right * amp) }
     }
+
+    fn process_block(&mut self, block: &mut [StereoSample]) {
+        // Idle and sustain only end on a trigger or release, so one amplitude covers the block
+        if matches!(self.stage, AdsrStage::Idle | AdsrStage::Sustain) {
+            let amp = self.get_amplitude();
+
+            for sample in block.iter_mut() {
+                sample.left *= amp;
+                sample.right *= amp;
+            }
+            return;
+        }
+
+        for sample in block.iter_mut() {
+            *sample = self.process_sample(*sample);
+        }
+    }
+
+    fn reset(&mut self) {
+        // Back to the state of a freshly created envelope, waiting for its first trigger.
+        self.stage = AdsrStage::Idle;
+        self.stage_progress = 0.0;
+        self.release_start_amp = 0.0;
+        self.retrigger_start_amp = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    /// Runs the envelope for `samples` samples, returning its amplitude at each.
+    fn run(env: &mut AdsrEnvelope, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| env.process_sample(StereoSample::from_mono(1.0)).left).collect()
+    }
+
+    fn max_step(amplitudes: &[f32]) -> f32 {
+        amplitudes.windows(2).map(|pair| (pair[1] - pair[0]).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn zero_length_stages_are_instant() {
+        let mut env = AdsrEnvelope::new(SAMPLE_RATE, 0.0, 0.0, 0.6, 0.0);
+
+        env.trigger();
+        assert_eq!(env.stage(), AdsrStage::Sustain);
+        assert_eq!(run(&mut env, 1), vec![0.6]);
+
+        env.release();
+        assert!(env.is_done());
+        assert_eq!(run(&mut env, 1), vec![0.0]);
+    }
+
+    #[test]
+    fn all_zero_times_never_produce_nan() {
+        let mut env = AdsrEnvelope::new(SAMPLE_RATE, 0.0, 0.0, 0.0, 0.0);
+        env.set_rc_release(true);
+
+        for _ in 0..3 {
+            env.trigger();
+            let mut amplitudes = run(&mut env, 10);
+            env.release();
+            amplitudes.extend(run(&mut env, 10));
+            assert!(amplitudes.iter().all(|amp| amp.is_finite()), "{amplitudes:?}");
+        }
+
+        // Time scales of zero (or NaN) shorten every stage to nothing as well
+        let mut env = AdsrEnvelope::new(SAMPLE_RATE, 0.01, 0.01, 0.5, 0.01);
+        env.set_time_scales(0.0, f32::NAN, 0.0);
+        env.trigger();
+        env.release();
+        assert!(run(&mut env, 10).iter().all(|amp| amp.is_finite()));
+        assert!(env.is_done());
+    }
+
+    #[test]
+    fn stage_follows_the_envelope() {
+        // 10 samples of attack, 20 of decay and 10 of release
+        let samples = |count: f32| count / SAMPLE_RATE;
+        let mut env = AdsrEnvelope::new(SAMPLE_RATE, samples(10.0), samples(20.0), 0.5, samples(10.0));
+        assert_eq!(env.stage(), AdsrStage::Idle);
+
+        // Starting from silence there's nothing to fade out first
+        env.trigger();
+        assert_eq!(env.stage(), AdsrStage::Attack);
+        run(&mut env, 12);
+        assert_eq!(env.stage(), AdsrStage::Decay);
+        run(&mut env, 20);
+        assert_eq!(env.stage(), AdsrStage::Sustain);
+
+        env.release();
+        assert_eq!(env.stage(), AdsrStage::Release);
+        assert!(env.is_released());
+        run(&mut env, 12);
+        assert_eq!(env.stage(), AdsrStage::Idle);
+        assert!(env.is_done());
+
+        env.trigger();
+        run(&mut env, 5);
+        // Retriggered while still sounding, so it fades out first
+        env.trigger();
+        assert_eq!(env.stage(), AdsrStage::Retrigger);
+    }
+
+    #[test]
+    fn changing_times_mid_stage_stays_continuous() {
+        let mut env = AdsrEnvelope::new(SAMPLE_RATE, 0.1, 0.1, 0.2, 0.1);
+        env.trigger();
+
+        // Halfway into the attack, stretched to several times its length, then cut short
+        let mut amplitudes = run(&mut env, 2400);
+        env.set_attack(0.5);
+        amplitudes.extend(run(&mut env, 2400));
+        env.set_attack(0.01);
+        amplitudes.extend(run(&mut env, 2400));
+        assert_eq!(env.stage(), AdsrStage::Decay);
+
+        // The same again through the decay and release, with the mod matrix's time scales
+        env.set_time_scales(1.0, 4.0, 1.0);
+        amplitudes.extend(run(&mut env, 2400));
+        env.set_decay(0.3);
+        amplitudes.extend(run(&mut env, 48000));
+        env.release();
+        amplitudes.extend(run(&mut env, 1200));
+        env.set_time_scales(1.0, 1.0, 0.25);
+        amplitudes.extend(run(&mut env, 1200));
+
+        // Nothing more than the fastest stage (the shortened attack) moves by itself in a sample
+        assert!(max_step(&amplitudes) <= 1.0 / (0.01 * SAMPLE_RATE) + 1e-6, "{}", max_step(&amplitudes));
+    }
+
+    #[test]
+    fn curves_bend_every_stage_between_the_same_end_points() {
+        // 1000 samples per stage
+        let length_s = 1000.0 / SAMPLE_RATE;
+
+        // Each stage's level halfway through, for log, linear and exp curves
+        let mut midpoints = Vec::new();
+        for amount in [-1.0, 0.0, 1.0] {
+            let mut env = AdsrEnvelope::new(SAMPLE_RATE, length_s, length_s, 0.5, length_s);
+            env.set_attack_curve(amount);
+            env.set_decay_curve(amount);
+            env.set_release_curve(amount);
+
+            env.trigger();
+            let mut amplitudes = run(&mut env, 500);
+            let attack = env.get_amplitude();
+            while env.stage() == AdsrStage::Attack {
+                amplitudes.extend(run(&mut env, 1));
+            }
+            // Each stage ends exactly where the next one starts
+            assert_eq!(env.get_amplitude(), 1.0, "curve {amount}");
+            assert!(amplitudes.windows(2).all(|pair| pair[1] >= pair[0]), "curve {amount}");
+
+            amplitudes.extend(run(&mut env, 500));
+            let decay = env.get_amplitude();
+            while env.stage() == AdsrStage::Decay {
+                amplitudes.extend(run(&mut env, 1));
+            }
+            assert_eq!(env.get_amplitude(), 0.5, "curve {amount}");
+
+            env.release();
+            amplitudes.extend(run(&mut env, 500));
+            let release = env.get_amplitude();
+            while env.stage() == AdsrStage::Release {
+                amplitudes.extend(run(&mut env, 1));
+            }
+            assert_eq!(env.get_amplitude(), 0.0, "curve {amount}");
+
+            // However steep the curve, nothing jumps
+            assert!(max_step(&amplitudes) < 0.01, "curve {amount}: {}", max_step(&amplitudes));
+            midpoints.push([attack, decay, release]);
+        }
+
+        // Linear is halfway between each stage's end points
+        let [log, linear, exp] = [midpoints[0], midpoints[1], midpoints[2]];
+        for (actual, expected) in linear.iter().zip([0.5, 0.75, 0.25]) {
+            assert!((actual - expected).abs() < 1e-3, "{actual} != {expected}");
+        }
+        // Log moves fast and then slowly, so it's ahead of linear rising and behind it falling,
+        // and exp the other way round
+        assert!(log[0] > linear[0] + 0.1 && exp[0] < linear[0] - 0.1, "{midpoints:?}");
+        for stage in [1, 2] {
+            assert!(log[stage] > linear[stage] + 0.05, "{midpoints:?}");
+            assert!(exp[stage] < linear[stage] - 0.05, "{midpoints:?}");
+        }
+    }
+
+    #[test]
+    fn rc_release_decays_exponentially_to_the_threshold() {
+        for (release_s, threshold) in [(0.1, 0.001), (0.05, 0.01)] {
+            let mut env = AdsrEnvelope::new(SAMPLE_RATE, 0.0, 0.0, 0.8, release_s);
+            env.set_rc_release(true);
+            env.set_release_threshold(threshold);
+            // Ignored by the RC release
+            env.set_release_curve(1.0);
+            env.trigger();
+            env.release();
+
+            let mut amplitudes = Vec::new();
+            while !env.is_done() {
+                amplitudes.extend(run(&mut env, 1));
+            }
+            let release_len = release_s * SAMPLE_RATE;
+            assert!((amplitudes.len() as f32 - release_len).abs() <= 1.0, "{}", amplitudes.len());
+
+            // Falling by the same ratio every sample
+            let ratio = threshold.powf(1.0 / release_len);
+            for pair in amplitudes.windows(2) {
+                assert!((pair[1] / pair[0] - ratio).abs() < 1e-4, "{pair:?}");
+            }
+            assert_eq!(amplitudes[0], 0.8);
+            let last = *amplitudes.last().unwrap();
+            assert!((last / (0.8 * threshold) - 1.0).abs() < 0.01, "{last}");
+
+            // Cut straight to silence from there, a step no bigger than the threshold itself
+            assert_eq!(run(&mut env, 1), vec![0.0]);
+            assert!(last <= 0.8 * threshold * 1.01, "{last}");
+            assert_eq!(env.stage(), AdsrStage::Idle);
+        }
+    }
 }
@@ -1,27 +1,140 @@
 use nih_plug::nih_error;
-use nih_plug::prelude::Editor;
+use nih_plug::prelude::{AsyncExecutor, Editor, Param};
 use nih_plug_vizia::vizia::prelude::*;
 use nih_plug_vizia::widgets::*;
 use nih_plug_vizia::{assets, create_vizia_editor, ViziaState, ViziaTheming};
 use std::sync::Arc;
 
-use crate::PolySynthParams;
+use crate::fm::NUM_OPERATORS;
+use crate::lfo::NUM_LFOS;
+use crate::mod_matrix::NUM_MOD_SLOTS;
+use crate::oscillator_mixer::NUM_OSCILLATORS;
+use crate::{PolySynthParams, PolySynthPlugin, Task};
 
 #[derive(Lens)]
 struct Data {
     params: Arc<PolySynthParams>,
+    // As typed into the editor, which is only saved to the parameters once it's submitted
+    wavetable_path: String,
+    #[lens(ignore)]
+    async_executor: AsyncExecutor<PolySynthPlugin>,
 }
 
-impl Model for Data {}
+enum EditorEvent {
+    /// Loads the wavetable file at this path, or the built-in shapes if it's empty.
+    SetWavetablePath(String),
+}
+
+impl Model for Data {
+    fn event(&mut self, _cx: &mut EventContext, event: &mut Event) {
+        event.map(|editor_event, _| match editor_event {
+            EditorEvent::SetWavetablePath(path) => {
+                self.wavetable_path = path.trim().to_owned();
+                *self.params.wavetable_path.write().unwrap() = self.wavetable_path.clone();
+                self.async_executor.execute_background(Task::LoadWavetable);
+            }
+        });
+    }
+}
 
 // Makes sense to also define this here, makes it a bit easier to keep track of
 pub(crate) fn default_state() -> Arc<ViziaState> {
-    ViziaState::new(|| (500, 400))
+    ViziaState::new(|| (1200, 900))
+}
+
+/// A labelled slider for a single parameter.
+fn param_slider<P, FMap>(cx: &mut Context, label: &'static str, params_to_param: FMap)
+where
+    P: Param + 'static,
+    FMap: Fn(&Arc<PolySynthParams>) -> &P + Copy + 'static,
+{
+    VStack::new(cx, |cx| {
+        Label::new(cx, label);
+        ParamSlider::new(cx, Data::params, params_to_param);
+    })
+    .height(Auto);
+}
+
+/// A row with every control of the oscillator at `index`.
+fn oscillator_row(cx: &mut Context, index: usize) {
+    const LABELS: [&str; NUM_OSCILLATORS] = ["Osc 1", "Osc 2", "Osc 3"];
+
+    HStack::new(cx, move |cx| {
+        Label::new(cx, LABELS[index]).width(Pixels(60.0));
+        param_slider(cx, "Waveform", move |params| &params.oscillators[index].waveform);
+        param_slider(cx, "Octave", move |params| &params.oscillators[index].octave);
+        param_slider(cx, "Semitone", move |params| &params.oscillators[index].semitone);
+        param_slider(cx, "Fine", move |params| &params.oscillators[index].fine);
+        param_slider(cx, "Level", move |params| &params.oscillators[index].level);
+        param_slider(cx, "Pan", move |params| &params.oscillators[index].pan);
+
+        // Oscillator 1 is the master the others sync to and are ring modulated by
+        if index > 0 {
+            param_slider(cx, "Sync", move |params| &params.oscillators[index].sync);
+            param_slider(cx, "Ring", move |params| &params.oscillators[index].ring);
+        }
+    })
+    .col_between(Pixels(10.0));
+}
+
+/// A row with every control of the LFO at `index`.
+fn lfo_row(cx: &mut Context, index: usize) {
+    const LABELS: [&str; NUM_LFOS] = ["LFO 1", "LFO 2"];
+
+    HStack::new(cx, move |cx| {
+        Label::new(cx, LABELS[index]).width(Pixels(60.0));
+        param_slider(cx, "Shape", move |params| &params.lfos[index].shape);
+        param_slider(cx, "Rate", move |params| &params.lfos[index].rate);
+        param_slider(cx, "Sync", move |params| &params.lfos[index].sync);
+        param_slider(cx, "Division", move |params| &params.lfos[index].division);
+        param_slider(cx, "Phase", move |params| &params.lfos[index].phase);
+        param_slider(cx, "Fade In", move |params| &params.lfos[index].fade_in);
+        param_slider(cx, "Retrigger", move |params| &params.lfos[index].retrigger);
+        param_slider(cx, "Mode", move |params| &params.lfos[index].mode);
+    })
+    .class("compact")
+    .col_between(Pixels(10.0));
+}
+
+/// The controls of mod matrix slot `index`.
+fn mod_slot(cx: &mut Context, index: usize) {
+    const LABELS: [&str; NUM_MOD_SLOTS] = [
+        "Mod 1", "Mod 2", "Mod 3", "Mod 4", "Mod 5", "Mod 6", "Mod 7", "Mod 8", "Mod 9", "Mod 10",
+        "Mod 11", "Mod 12", "Mod 13", "Mod 14", "Mod 15", "Mod 16",
+    ];
+
+    Label::new(cx, LABELS[index]).width(Pixels(60.0));
+    param_slider(cx, "Source", move |params| &params.mod_slots[index].source);
+    param_slider(cx, "Destination", move |params| &params.mod_slots[index].destination);
+    param_slider(cx, "Amount", move |params| &params.mod_slots[index].amount);
+    param_slider(cx, "Via", move |params| &params.mod_slots[index].via);
+}
+
+/// A row with every control of the FM operator at `index`.
+fn operator_row(cx: &mut Context, index: usize) {
+    const LABELS: [&str; NUM_OPERATORS] = ["Op 1", "Op 2", "Op 3", "Op 4", "Op 5", "Op 6"];
+
+    HStack::new(cx, move |cx| {
+        Label::new(cx, LABELS[index]).width(Pixels(60.0));
+        param_slider(cx, "Ratio", move |params| &params.operators[index].ratio);
+        param_slider(cx, "Fixed", move |params| &params.operators[index].fixed);
+        param_slider(cx, "Fixed Freq", move |params| &params.operators[index].fixed_frequency);
+        param_slider(cx, "Level", move |params| &params.operators[index].level);
+        param_slider(cx, "Feedback", move |params| &params.operators[index].feedback);
+        param_slider(cx, "Velocity", move |params| &params.operators[index].velocity_sensitivity);
+        param_slider(cx, "Attack", move |params| &params.operators[index].attack);
+        param_slider(cx, "Decay", move |params| &params.operators[index].decay);
+        param_slider(cx, "Sustain", move |params| &params.operators[index].sustain);
+        param_slider(cx, "Release", move |params| &params.operators[index].release);
+    })
+    .class("compact")
+    .col_between(Pixels(10.0));
 }
 
 pub(crate) fn create(
     params: Arc<PolySynthParams>,
     editor_state: Arc<ViziaState>,
+    async_executor: AsyncExecutor<PolySynthPlugin>,
 ) -> Option<Box<dyn Editor>> {
     create_vizia_editor(editor_state, ViziaTheming::Custom, move |cx, _| {
         assets::register_noto_sans_light(cx);
@@ -33,6 +146,8 @@ pub(crate) fn create(
 
         Data {
             params: params.clone(),
+            wavetable_path: params.wavetable_path.read().unwrap().clone(),
+            async_executor: async_executor.clone(),
         }
         .build(cx);
 
@@ -43,31 +158,131 @@ pub(crate) fn create(
                     .font_family(vec![FamilyOwned::Name(String::from(assets::NOTO_SANS))])
                     .font_weight(FontWeightKeyword::Thin)
                     .font_size(20.0);
-            });
+            })
+            .height(Auto);
 
-            VStack::new(cx, |cx| {
-                Label::new(cx, "Attack");
-                ParamSlider::new(cx, Data::params, |params| &params.attack);
-            });
+            // Far more controls than fit on a screen, so everything below the title scrolls
+            ScrollView::new(cx, 0.0, 0.0, false, true, |cx| {
+                VStack::new(cx, |cx| {
+                    HStack::new(cx, |cx| {
+                        param_slider(cx, "Attack", |params| &params.attack);
+                        param_slider(cx, "Decay", |params| &params.decay);
+                        param_slider(cx, "Sustain", |params| &params.sustain);
+                        param_slider(cx, "Release", |params| &params.release);
+                    })
+                    .col_between(Pixels(10.0));
 
-            VStack::new(cx, |cx| {
-                Label::new(cx, "Decay");
-                ParamSlider::new(cx, Data::params, |params| &params.decay);
-            });
+                    HStack::new(cx, |cx| {
+                        param_slider(cx, "Attack Curve", |params| &params.attack_curve);
+                        param_slider(cx, "Decay Curve", |params| &params.decay_curve);
+                        param_slider(cx, "Release Curve", |params| &params.release_curve);
+                        param_slider(cx, "RC Release", |params| &params.rc_release);
+                        param_slider(cx, "Threshold", |params| &params.release_threshold);
+                    })
+                    .col_between(Pixels(10.0));
 
-            VStack::new(cx, |cx| {
-                Label::new(cx, "Sustain");
-                ParamSlider::new(cx, Data::params, |params| &params.sustain);
-            });
+                    HStack::new(cx, |cx| {
+                        param_slider(cx, "Filter", |params| &params.filter_type);
+                        param_slider(cx, "Mode", |params| &params.filter_mode);
+                        param_slider(cx, "Slope", |params| &params.filter_slope);
+                        param_slider(cx, "Cutoff", |params| &params.filter_cutoff);
+                        param_slider(cx, "Resonance", |params| &params.filter_resonance);
+                        param_slider(cx, "Drive", |params| &params.filter_drive);
+                    })
+                    .col_between(Pixels(10.0));
 
-            VStack::new(cx, |cx| {
-                Label::new(cx, "Release");
-                ParamSlider::new(cx, Data::params, |params| &params.release);
-            });
+                    HStack::new(cx, |cx| {
+                        param_slider(cx, "Filter Attack", |params| &params.filter_attack);
+                        param_slider(cx, "Filter Decay", |params| &params.filter_decay);
+                        param_slider(cx, "Filter Sustain", |params| &params.filter_sustain);
+                        param_slider(cx, "Filter Release", |params| &params.filter_release);
+                        param_slider(cx, "Env Amount", |params| &params.filter_env_amount);
+                        param_slider(cx, "Key Track", |params| &params.filter_key_tracking);
+                        param_slider(cx, "Velocity", |params| &params.filter_velocity);
+                    })
+                    .col_between(Pixels(10.0));
 
-            VStack::new(cx, |cx| {
-                Label::new(cx, "Glide");
-                ParamSlider::new(cx, Data::params, |params| &params.glide);
+                    for index in 0..NUM_LFOS {
+                        lfo_row(cx, index);
+                    }
+
+                    HStack::new(cx, |cx| {
+                        param_slider(cx, "Engine", |params| &params.engine);
+                        param_slider(cx, "FM Algorithm", |params| &params.fm_algorithm);
+                    })
+                    .col_between(Pixels(10.0));
+
+                    for index in 0..NUM_OPERATORS {
+                        operator_row(cx, index);
+                    }
+
+                    for index in 0..NUM_OSCILLATORS {
+                        oscillator_row(cx, index);
+                    }
+
+                    HStack::new(cx, |cx| {
+                        Label::new(cx, "Sub").width(Pixels(60.0));
+                        param_slider(cx, "Waveform", |params| &params.sub_waveform);
+                        param_slider(cx, "Octave", |params| &params.sub_octave);
+                        param_slider(cx, "Level", |params| &params.sub_level);
+                        Label::new(cx, "Noise").width(Pixels(60.0));
+                        param_slider(cx, "Colour", |params| &params.noise_colour);
+                        param_slider(cx, "Stereo", |params| &params.noise_stereo);
+                        param_slider(cx, "Level", |params| &params.noise_level);
+                    })
+                    .col_between(Pixels(10.0));
+
+                    HStack::new(cx, |cx| {
+                        param_slider(cx, "Unison", |params| &params.unison);
+                        param_slider(cx, "Detune", |params| &params.unison_detune);
+                        param_slider(cx, "Detune Curve", |params| &params.unison_detune_curve);
+                        param_slider(cx, "Spread", |params| &params.unison_spread);
+                        param_slider(cx, "Blend", |params| &params.unison_blend);
+                    })
+                    .col_between(Pixels(10.0));
+
+                    HStack::new(cx, |cx| {
+                        Label::new(cx, "Wavetable").width(Pixels(60.0));
+                        // Empty for the built-in shapes
+                        Textbox::new(cx, Data::wavetable_path)
+                            .on_submit(|cx, path, _| cx.emit(EditorEvent::SetWavetablePath(path)))
+                            .width(Pixels(600.0));
+                    })
+                    .col_between(Pixels(10.0));
+
+                    HStack::new(cx, |cx| {
+                        param_slider(cx, "Pulse Width", |params| &params.pulse_width);
+                        param_slider(cx, "WT Position", |params| &params.wavetable_position);
+                        param_slider(cx, "Glide", |params| &params.glide);
+                        param_slider(cx, "Bend Range", |params| &params.pitch_bend_range);
+                        param_slider(cx, "Quality", |params| &params.osc_quality);
+                        param_slider(cx, "Velocity", |params| &params.velocity_depth);
+                        param_slider(cx, "Velocity Curve", |params| &params.velocity_curve);
+                    })
+                    .col_between(Pixels(10.0));
+
+                    HStack::new(cx, |cx| {
+                        param_slider(cx, "Voices", |params| &params.voices);
+                        param_slider(cx, "Voice Stealing", |params| &params.voice_stealing);
+                        param_slider(cx, "Play Mode", |params| &params.play_mode);
+                        param_slider(cx, "Note Priority", |params| &params.note_priority);
+                    })
+                    .col_between(Pixels(10.0));
+
+                    // Two slots to a row
+                    for row in 0..NUM_MOD_SLOTS / 2 {
+                        HStack::new(cx, move |cx| {
+                            mod_slot(cx, 2 * row);
+                            mod_slot(cx, 2 * row + 1);
+                        })
+                        .class("compact")
+                        .col_between(Pixels(10.0));
+                    }
+                })
+                .height(Auto)
+                .row_between(Pixels(10.0))
+                .child_left(Stretch(1.0))
+                .child_right(Stretch(1.0));
             });
         })
         .child_left(Stretch(1.0))
@@ -75,6 +290,6 @@ pub(crate) fn create(
         .top(Units::Pixels(40.0))
         .bottom(Units::Pixels(40.0));
 
-        // ResizeHandle::new(cx);
+        ResizeHandle::new(cx);
     })
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+/// In-place iterative radix-2 FFT. `re` and `im` hold the real and imaginary parts and must
+/// have the same power-of-two length.
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two() && im.len() == n);
+
+    // Bit-reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f64::consts::PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// The inverse of [`fft()`], including the 1/n scaling.
+pub fn ifft(re: &mut [f64], im: &mut [f64]) {
+    // Conjugate, transform, conjugate again
+    im.iter_mut().for_each(|x| *x = -*x);
+    fft(re, im);
+
+    let scale = 1.0 / re.len() as f64;
+    re.iter_mut().for_each(|x| *x *= scale);
+    im.iter_mut().for_each(|x| *x *= -scale);
+}
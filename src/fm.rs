@@ -0,0 +1,368 @@
+use nih_plug::prelude::Enum;
+
+use crate::adsr_envelope::AdsrEnvelope;
+use crate::sine_wave::SineWave;
+use crate::stereo_sample::StereoSample;
+use crate::traits::{AudioProcessor, AudioSource};
+
+/// How many operators the FM engine has.
+pub const NUM_OPERATORS: usize = 6;
+
+// How far a modulator at full level pushes the phase of the operators it modulates, in cycles.
+const MODULATION_DEPTH: f32 = 1.0;
+
+// How far full feedback pushes an operator's phase, in cycles.
+const FEEDBACK_DEPTH: f32 = 0.5;
+
+/// How the operators modulate each other, numbered after the DX7 algorithms they copy. Operators
+/// only ever modulate lower numbered ones, `a > b` meaning `a` modulates `b`.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FmAlgorithm {
+    /// 2 > 1 and 6 > 5 > 4 > 3.
+    #[id = "dx1"]
+    #[name = "1"]
+    Dx1,
+    /// 3 > 2 > 1 and 6 > 5 > 4.
+    #[id = "dx3"]
+    #[name = "3"]
+    Dx3,
+    /// Three pairs: 2 > 1, 4 > 3 and 6 > 5.
+    #[id = "dx5"]
+    #[name = "5"]
+    Dx5,
+    /// 2 > 1, and 4 and 6 > 5 both into 3.
+    #[id = "dx7"]
+    #[name = "7"]
+    Dx7,
+    /// A single carrier: 2, 4 > 3 and 6 > 5 all into 1.
+    #[id = "dx16"]
+    #[name = "16"]
+    Dx16,
+    /// 2 > 1, and 6 into 3, 4 and 5.
+    #[id = "dx22"]
+    #[name = "22"]
+    Dx22,
+    /// 6 > 5, everything else unmodulated.
+    #[id = "dx31"]
+    #[name = "31"]
+    Dx31,
+    /// No modulation at all, six sines added together.
+    #[id = "dx32"]
+    #[name = "32"]
+    Dx32,
+}
+
+/// Which operators modulate which, as bit masks (bit `n` = operator `n + 1`).
+#[derive(Clone, Copy)]
+struct Routing {
+    // The operators modulating each operator
+    modulators: [u8; NUM_OPERATORS],
+    // The operators that are heard
+    carriers: u8,
+}
+
+impl FmAlgorithm {
+    fn routing(self) -> Routing {
+        const fn op(number: usize) -> u8 {
+            1 << (number - 1)
+        }
+
+        let (modulators, carriers) = match self {
+            FmAlgorithm::Dx1 => ([op(2), 0, op(4), op(5), op(6), 0], op(1) | op(3)),
+            FmAlgorithm::Dx3 => ([op(2), op(3), 0, op(5), op(6), 0], op(1) | op(4)),
+            FmAlgorithm::Dx5 => ([op(2), 0, op(4), 0, op(6), 0], op(1) | op(3) | op(5)),
+            FmAlgorithm::Dx7 => ([op(2), 0, op(4) | op(5), 0, op(6), 0], op(1) | op(3)),
+            FmAlgorithm::Dx16 => ([op(2) | op(3) | op(5), 0, op(4), 0, op(6), 0], op(1)),
+            FmAlgorithm::Dx22 => {
+                ([op(2), 0, op(6), op(6), op(6), 0], op(1) | op(3) | op(4) | op(5))
+            }
+            FmAlgorithm::Dx31 => ([0, 0, 0, 0, op(6), 0], op(1) | op(2) | op(3) | op(4) | op(5)),
+            FmAlgorithm::Dx32 => ([0; NUM_OPERATORS], 0b11_1111),
+        };
+
+        Routing { modulators, carriers }
+    }
+}
+
+/// A sine oscillator with its own envelope, as either a carrier or a modulator.
+#[derive(Clone)]
+struct FmOperator {
+    osc: SineWave,
+    env: AdsrEnvelope,
+    // Frequency relative to the note, unless `fixed` is set
+    ratio: f32,
+    fixed: bool,
+    fixed_frequency: f32,
+    level: f32,
+    feedback: f32,
+    // How much velocity affects the level of a modulator (0 = not at all, 1 = fully). Carriers
+    // ignore it, the voice already scales what's heard by velocity.
+    velocity_sensitivity: f32,
+    // The last two outputs. Feedback uses their average, like the DX7, which keeps high
+    // feedback from breaking up into noise.
+    previous: [f32; 2],
+}
+
+impl FmOperator {
+    fn new(sample_rate: u32, level: f32) -> Self {
+        Self {
+            osc: SineWave::new(sample_rate, 220.0),
+            env: AdsrEnvelope::new(sample_rate as f32, 0.0, 0.0, 1.0, 0.5),
+            ratio: 1.0,
+            fixed: false,
+            fixed_frequency: 440.0,
+            level,
+            feedback: 0.0,
+            velocity_sensitivity: 0.0,
+            previous: [0.0; 2],
+        }
+    }
+
+    fn update_frequency(&mut self, note_frequency: f32) {
+        let freq = if self.fixed { self.fixed_frequency } else { note_frequency * self.ratio };
+        self.osc.set_frequency(freq);
+    }
+
+    /// Renders the next sample, with the phase pushed on by `modulation` cycles. `velocity` is
+    /// 1 for carriers, see `velocity_sensitivity`.
+    fn next(&mut self, modulation: f32, velocity: f32) -> f32 {
+        let velocity_gain = 1.0 - self.velocity_sensitivity + self.velocity_sensitivity * velocity;
+        let amp = self.env.process_sample(StereoSample::from_mono(self.level * velocity_gain)).left;
+
+        let feedback = self.feedback * FEEDBACK_DEPTH * 0.5 * (self.previous[0] + self.previous[1]);
+        let output = self.osc.next_modulated(modulation + feedback) * amp;
+        self.previous = [output, self.previous[0]];
+
+        output
+    }
+}
+
+/// Phase modulation synthesis: sine operators modulating each other's phase, routed by an
+/// [`FmAlgorithm`].
+#[derive(Clone)]
+pub struct FmEngine {
+    operators: [FmOperator; NUM_OPERATORS],
+    routing: Routing,
+    // Note-on velocity (0..1), for each modulator's velocity sensitivity
+    velocity: f32,
+
+    pub frequency: f32,
+}
+
+impl FmEngine {
+    pub fn new(sample_rate: u32, freq: f32) -> Self {
+        // Operator 2 gently modulating operator 1 by default, the rest are silent
+        let mut engine = Self {
+            operators: std::array::from_fn(|index| match index {
+                0 => FmOperator::new(sample_rate, 1.0),
+                1 => FmOperator::new(sample_rate, 0.5),
+                _ => FmOperator::new(sample_rate, 0.0),
+            }),
+            routing: FmAlgorithm::Dx1.routing(),
+            velocity: 1.0,
+            frequency: freq,
+        };
+        engine.set_frequency(freq);
+        engine
+    }
+
+    /// Starts every operator's envelope, for a note played with `velocity` (0..1).
+    pub fn trigger(&mut self, velocity: f32) {
+        self.velocity = velocity;
+        self.operators.iter_mut().for_each(|op| op.env.trigger());
+    }
+
+    pub fn release(&mut self) {
+        self.operators.iter_mut().for_each(|op| op.env.release());
+    }
+
+    pub fn set_algorithm(&mut self, algorithm: FmAlgorithm) {
+        self.routing = algorithm.routing();
+    }
+
+    /// Sets the frequency of operator `index` relative to the note.
+    pub fn set_ratio(&mut self, index: usize, ratio: f32) {
+        self.operators[index].ratio = ratio;
+        self.operators[index].update_frequency(self.frequency);
+    }
+
+    /// Makes operator `index` ignore the note and play its fixed frequency instead.
+    pub fn set_fixed(&mut self, index: usize, fixed: bool) {
+        self.operators[index].fixed = fixed;
+        self.operators[index].update_frequency(self.frequency);
+    }
+
+    pub fn set_fixed_frequency(&mut self, index: usize, freq: f32) {
+        self.operators[index].fixed_frequency = freq;
+        self.operators[index].update_frequency(self.frequency);
+    }
+
+    pub fn set_level(&mut self, index: usize, level: f32) {
+        self.operators[index].level = level;
+    }
+
+    pub fn set_feedback(&mut self, index: usize, feedback: f32) {
+        self.operators[index].feedback = feedback;
+    }
+
+    /// Sets how much velocity brings in operator `index` when it's a modulator, which makes
+    /// harder notes brighter. Only modulators respond, so velocity isn't applied to the level
+    /// twice: the voice scales its whole output by velocity already.
+    pub fn set_velocity_sensitivity(&mut self, index: usize, sensitivity: f32) {
+        self.operators[index].velocity_sensitivity = sensitivity;
+    }
+
+    pub fn set_attack(&mut self, index: usize, attack_s: f32) {
+        self.operators[index].env.set_attack(attack_s);
+    }
+
+    pub fn set_decay(&mut self, index: usize, decay_s: f32) {
+        self.operators[index].env.set_decay(decay_s);
+    }
+
+    pub fn set_sustain(&mut self, index: usize, sustain_level: f32) {
+        self.operators[index].env.set_sustain(sustain_level);
+    }
+
+    pub fn set_release(&mut self, index: usize, release_s: f32) {
+        self.operators[index].env.set_release(release_s);
+    }
+}
+
+impl AudioSource for FmEngine {
+    fn next_sample(&mut self) -> StereoSample {
+        let routing = self.routing;
+        let mut outputs = [0.0; NUM_OPERATORS];
+        let mut mix = 0.0;
+
+        // Modulators always have higher numbers than what they modulate, so going from the top
+        // down renders every modulator before it's needed
+        for index in (0..NUM_OPERATORS).rev() {
+            let mut modulation = 0.0;
+            for (modulator, output) in outputs.iter().enumerate().skip(index + 1) {
+                if routing.modulators[index] & (1 << modulator) != 0 {
+                    modulation += output;
+                }
+            }
+
+            let is_carrier = routing.carriers & (1 << index) != 0;
+            let velocity = if is_carrier { 1.0 } else { self.velocity };
+            outputs[index] = self.operators[index].next(modulation * MODULATION_DEPTH, velocity);
+
+            if is_carrier {
+                mix += outputs[index];
+            }
+        }
+
+        // More carriers shouldn't mean louder notes
+        let sample = mix / routing.carriers.count_ones() as f32;
+
+        StereoSample { left: sample, right: sample }
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        self.frequency = freq;
+        self.operators.iter_mut().for_each(|op| op.update_frequency(freq));
+    }
+
+    fn reset(&mut self) {
+        for op in self.operators.iter_mut() {
+            op.osc.reset();
+            op.env.reset();
+            op.previous = [0.0; 2];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    const ALGORITHMS: [FmAlgorithm; 8] = [
+        FmAlgorithm::Dx1,
+        FmAlgorithm::Dx3,
+        FmAlgorithm::Dx5,
+        FmAlgorithm::Dx7,
+        FmAlgorithm::Dx16,
+        FmAlgorithm::Dx22,
+        FmAlgorithm::Dx31,
+        FmAlgorithm::Dx32,
+    ];
+
+    // An engine playing a note at `velocity`, routed by `algorithm` with only the operators in
+    // `levels` turned up
+    fn engine(algorithm: FmAlgorithm, levels: &[(usize, f32)], velocity: f32) -> FmEngine {
+        let mut engine = FmEngine::new(SAMPLE_RATE, 440.0);
+        engine.set_algorithm(algorithm);
+        (0..NUM_OPERATORS).for_each(|index| engine.set_level(index, 0.0));
+        levels.iter().for_each(|&(index, level)| engine.set_level(index, level));
+        engine.trigger(velocity);
+        engine
+    }
+
+    fn render(engine: &mut FmEngine, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| engine.next_sample().left).collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn every_operator_is_heard_or_modulates_a_lower_one() {
+        for algorithm in ALGORITHMS {
+            let routing = algorithm.routing();
+            assert_ne!(routing.carriers, 0, "{algorithm:?}");
+
+            let mut modulating = 0;
+            for (index, &modulators) in routing.modulators.iter().enumerate() {
+                // Rendering from the top down relies on this
+                assert_eq!(modulators & ((1 << (index + 1)) - 1), 0, "{algorithm:?}");
+                modulating |= modulators;
+            }
+            assert_eq!(modulating | routing.carriers, 0b11_1111, "{algorithm:?}");
+        }
+    }
+
+    #[test]
+    fn a_lone_carrier_plays_a_sine_at_the_note() {
+        let mut engine = engine(FmAlgorithm::Dx32, &[(0, 1.0)], 1.0);
+        let mut sine = SineWave::new(SAMPLE_RATE, 440.0);
+
+        // Six carriers share the output, so one on its own plays at a sixth of the level
+        for sample in render(&mut engine, 1000) {
+            let expected = sine.next_sample().left / NUM_OPERATORS as f32;
+            assert!((sample - expected).abs() < 1e-4, "{sample} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn modulators_change_the_sound_without_being_heard() {
+        // Operator 2 only modulates operator 1 in algorithm 1, and is heard in algorithm 32
+        assert_eq!(peak(&render(&mut engine(FmAlgorithm::Dx1, &[(1, 1.0)], 1.0), 1000)), 0.0);
+        assert!(peak(&render(&mut engine(FmAlgorithm::Dx32, &[(1, 1.0)], 1.0), 1000)) > 0.1);
+
+        let plain = render(&mut engine(FmAlgorithm::Dx1, &[(0, 1.0)], 1.0), 1000);
+        let modulated = render(&mut engine(FmAlgorithm::Dx1, &[(0, 1.0), (1, 1.0)], 1.0), 1000);
+        assert_ne!(plain, modulated);
+        // Phase modulation moves the carrier around without making it any louder
+        assert!(peak(&modulated) <= peak(&plain) + 1e-4);
+    }
+
+    #[test]
+    fn velocity_only_scales_modulators() {
+        let play = |velocity: f32| {
+            let mut engine = engine(FmAlgorithm::Dx1, &[(0, 1.0), (1, 1.0)], velocity);
+            (0..NUM_OPERATORS).for_each(|index| engine.set_velocity_sensitivity(index, 1.0));
+            render(&mut engine, 1000)
+        };
+
+        // A silent modulator leaves the carrier playing a plain sine at its full level
+        let soft = play(0.0);
+        let plain = render(&mut engine(FmAlgorithm::Dx1, &[(0, 1.0)], 1.0), 1000);
+        assert_eq!(soft, plain);
+        assert_ne!(play(1.0), plain);
+    }
+}
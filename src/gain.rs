@@ -11,10 +11,21 @@ impl Gain {
             amount
         }
     }
+
+    pub fn set_amount(&mut self, amount: f32) {
+        self.amount = amount;
+    }
 }
 
 impl AudioProcessor for Gain {
     fn process_sample(&mut self, input: StereoSample) -> StereoSample {
         StereoSample {  left: (input.left * self.amount),  right: (input.right * self.amount) }
     }
+
+    fn process_block(&mut self, block: &mut [StereoSample]) {
+        for sample in block.iter_mut() {
+            sample.left *= self.amount;
+            sample.right *= self.amount;
+        }
+    }
 }
\ No newline at end of file
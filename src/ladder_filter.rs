@@ -0,0 +1,279 @@
+use nih_plug::prelude::Enum;
+
+use crate::smoothed_cutoff::SmoothedCutoff;
+use crate::{stereo_sample::StereoSample, traits::AudioProcessor};
+
+// Feedback at full resonance. A lossless ladder starts to self-oscillate at 4, a little more
+// makes sure it keeps going, with the saturation holding the level in check.
+const MAX_FEEDBACK: f32 = 4.2;
+
+// Where the saturation levels off. Leaves full scale input at unity drive mostly clean, and sets
+// how loud the self-oscillation gets.
+const SATURATION_LEVEL: f32 = 2.0;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LadderSlope {
+    /// Output taken after the second pole.
+    #[id = "12db"]
+    #[name = "12 dB"]
+    Db12,
+    /// Output taken after all four poles.
+    #[id = "24db"]
+    #[name = "24 dB"]
+    Db24,
+}
+
+/// The four one-pole stages for one channel.
+#[derive(Clone, Copy, Default)]
+struct LadderState {
+    stages: [f32; 4],
+}
+
+/// A 4-pole transistor ladder lowpass with a saturating feedback loop. Each pole is a
+/// zero-delay-feedback (trapezoidal) one-pole and the loop around them is solved without a sample
+/// of delay, so it stays in tune and stable at any cutoff and sample rate. The saturation sits
+/// where the input and the feedback meet, the way it does in the transistors at the bottom of an
+/// analog ladder.
+#[derive(Clone)]
+pub struct LadderFilter {
+    slope: LadderSlope,
+    cutoff: SmoothedCutoff,
+    resonance: f32,
+    // Added to `resonance`, without smoothing
+    resonance_modulation: f32,
+    // Input gain into the saturation
+    drive: f32,
+    sample_rate: f32,
+
+    // Coefficients, only worked out again when the cutoff or resonance moves
+    k: f32,
+    // A single stage's gain from its input (G) and from its state (1 - G)
+    g: f32,
+    state_gain: f32,
+    // 1 / (1 + k * G^4), from solving the feedback loop
+    feedback_scale: f32,
+
+    left: LadderState,
+    right: LadderState,
+}
+
+impl LadderFilter {
+    pub fn new(sample_rate: f32, cutoff_hz: f32, resonance: f32) -> Self {
+        let mut filter = Self {
+            slope: LadderSlope::Db24,
+            cutoff: SmoothedCutoff::new(sample_rate, cutoff_hz),
+            resonance: 0.0,
+            resonance_modulation: 0.0,
+            drive: 1.0,
+            sample_rate,
+            k: 0.0,
+            g: 0.0,
+            state_gain: 1.0,
+            feedback_scale: 1.0,
+            left: LadderState::default(),
+            right: LadderState::default(),
+        };
+        filter.set_resonance(resonance);
+        filter
+    }
+
+    pub fn set_slope(&mut self, slope: LadderSlope) {
+        self.slope = slope;
+    }
+
+    /// Sets the cutoff frequency in Hz. The filter glides there over a couple of milliseconds
+    /// rather than jumping.
+    pub fn set_cutoff(&mut self, cutoff_hz: f32) {
+        self.cutoff.set(cutoff_hz);
+    }
+
+    /// Moves the cutoff `octaves` away from the one set by `set_cutoff()`, without smoothing.
+    pub fn set_cutoff_modulation(&mut self, octaves: f32) {
+        self.cutoff.set_modulation(octaves);
+    }
+
+    /// Sets the resonance (0 = none, 1 = self-oscillating).
+    pub fn set_resonance(&mut self, resonance: f32) {
+        self.resonance = resonance;
+        self.update_coefficients();
+    }
+
+    /// Moves the resonance `amount` away from the one set by `set_resonance()`.
+    pub fn set_resonance_modulation(&mut self, amount: f32) {
+        if amount != self.resonance_modulation {
+            self.resonance_modulation = amount;
+            self.update_coefficients();
+        }
+    }
+
+    /// Sets the gain into the saturation (1 = unity). Turning it up makes the filter louder and
+    /// dirtier.
+    pub fn set_drive(&mut self, drive: f32) {
+        self.drive = drive.max(0.0);
+    }
+
+    fn update_coefficients(&mut self) {
+        self.k = (self.resonance + self.resonance_modulation).clamp(0.0, 1.0) * MAX_FEEDBACK;
+
+        let g = (std::f32::consts::PI * self.cutoff.hz() / self.sample_rate).tan();
+        self.g = g / (1.0 + g);
+        self.state_gain = 1.0 - self.g;
+        self.feedback_scale = 1.0 / (1.0 + self.k * self.g.powi(4));
+    }
+
+    fn tick(&self, state: &mut LadderState, input: f32) -> f32 {
+        let g = self.g;
+        let s = &mut state.stages;
+
+        // What the last stage would output with no input, from the stages' states alone
+        let from_states = self.state_gain * (g * (g * (g * s[0] + s[1]) + s[2]) + s[3]);
+
+        // Solve for the ladder's input with the feedback included. Feeding back the input as well
+        // as the output (k * (out - in)) keeps the passband at unity however much resonance
+        // there is, instead of thinning out as the resonance goes up.
+        let x = input * self.drive;
+        let u = ((1.0 + self.k) * x - self.k * from_states) * self.feedback_scale;
+        let u = SATURATION_LEVEL * (u / SATURATION_LEVEL).tanh();
+
+        let mut stage_in = u;
+        let mut outputs = [0.0; 4];
+        for (stage, output) in s.iter_mut().zip(outputs.iter_mut()) {
+            let v = (stage_in - *stage) * g;
+            *output = v + *stage;
+            *stage = *output + v;
+            stage_in = *output;
+        }
+
+        match self.slope {
+            LadderSlope::Db12 => outputs[1],
+            LadderSlope::Db24 => outputs[3],
+        }
+    }
+}
+
+impl AudioProcessor for LadderFilter {
+    fn process_sample(&mut self, input: StereoSample) -> StereoSample {
+        if self.cutoff.step() {
+            self.update_coefficients();
+        }
+
+        let mut left = self.left;
+        let mut right = self.right;
+        let output = StereoSample {
+            left: self.tick(&mut left, input.left),
+            right: self.tick(&mut right, input.right),
+        };
+        self.left = left;
+        self.right = right;
+
+        output
+    }
+
+    fn reset(&mut self) {
+        self.left = LadderState::default();
+        self.right = LadderState::default();
+        self.cutoff.snap();
+        self.update_coefficients();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Random;
+
+    const SAMPLE_RATES: [f32; 4] = [44100.0, 48000.0, 96000.0, 192000.0];
+
+    /// Rings the filter with a single click and returns the next `seconds` of output.
+    fn ring(filter: &mut LadderFilter, seconds: f32) -> Vec<f32> {
+        let mut output = vec![filter.process_sample(StereoSample::from_mono(1.0)).left];
+        let len = (seconds * filter.sample_rate) as usize;
+        output.extend((0..len).map(|_| filter.process_sample(StereoSample::from_mono(0.0)).left));
+        output
+    }
+
+    /// The frequency of a steady oscillation, from the time between its first and last upward
+    /// zero crossings.
+    fn measure_frequency(signal: &[f32], sample_rate: f32) -> f32 {
+        let mut crossings = signal
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+            // Where between the two samples the signal crosses zero
+            .map(|(index, pair)| index as f32 + pair[0] / (pair[0] - pair[1]));
+        let first = crossings.next().unwrap();
+        let (count, last) = crossings.fold((0, first), |(count, _), crossing| (count + 1, crossing));
+
+        count as f32 * sample_rate / (last - first)
+    }
+
+    #[test]
+    fn self_oscillates_at_the_cutoff() {
+        for sample_rate in SAMPLE_RATES {
+            for cutoff in [100.0, 440.0, 1000.0, 4000.0] {
+                let mut filter = LadderFilter::new(sample_rate, cutoff, 1.0);
+                let output = ring(&mut filter, 2.0);
+
+                // Skip the first second, while the oscillation builds up and settles
+                let settled = &output[sample_rate as usize..];
+                let peak = settled.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+                assert!(peak > 0.1, "no self-oscillation at {cutoff} Hz ({sample_rate} Hz)");
+
+                let frequency = measure_frequency(settled, sample_rate);
+                let cents = 1200.0 * (frequency / cutoff).log2();
+                assert!(
+                    cents.abs() < 1.0,
+                    "oscillated at {frequency} Hz for a {cutoff} Hz cutoff ({sample_rate} Hz)"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dies_away_below_full_resonance() {
+        let mut filter = LadderFilter::new(48000.0, 1000.0, 0.9);
+        let output = ring(&mut filter, 1.0);
+
+        assert!(output[output.len() - 100..].iter().all(|sample| sample.abs() < 1e-4));
+    }
+
+    #[test]
+    fn stays_stable_under_extreme_settings() {
+        for sample_rate in SAMPLE_RATES {
+            for slope in [LadderSlope::Db12, LadderSlope::Db24] {
+                let mut filter = LadderFilter::new(sample_rate, 20000.0, 1.0);
+                filter.set_slope(slope);
+                filter.set_drive(16.0);
+                let mut random = Random::new(1);
+
+                // Loud noise while the cutoff jumps between the bottom and the top of its range
+                for index in 0..sample_rate as usize {
+                    if index % 512 == 0 {
+                        filter.set_cutoff(if index % 1024 == 0 { 20.0 } else { 1e6 });
+                    }
+                    let input = StereoSample::from_mono(4.0 * random.next_bipolar());
+                    let output = filter.process_sample(input);
+                    assert!(output.left.is_finite() && output.left.abs() < 10.0);
+                    assert!(output.right.is_finite() && output.right.abs() < 10.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn passband_gain_is_compensated() {
+        for resonance in [0.0, 0.5, 0.9] {
+            for slope in [LadderSlope::Db12, LadderSlope::Db24] {
+                let mut filter = LadderFilter::new(48000.0, 5000.0, resonance);
+                filter.set_slope(slope);
+
+                // A quiet constant input, kept out of the saturation
+                let mut output = 0.0;
+                for _ in 0..48000 {
+                    output = filter.process_sample(StereoSample::from_mono(0.1)).left;
+                }
+                assert!((output - 0.1).abs() < 1e-3, "DC gain {} at resonance {resonance}", output / 0.1);
+            }
+        }
+    }
+}
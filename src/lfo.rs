@@ -0,0 +1,435 @@
+use nih_plug::prelude::Enum;
+
+use crate::random::Random;
+
+/// How many LFOs there are, each either shared by all voices or one per voice.
+pub const NUM_LFOS: usize = 2;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LfoShape {
+    #[id = "sine"]
+    Sine,
+    #[id = "triangle"]
+    Triangle,
+    /// Rising from -1 to 1.
+    #[id = "saw"]
+    Saw,
+    #[id = "square"]
+    Square,
+    /// A new random value every cycle, held until the next one.
+    #[id = "sample_and_hold"]
+    #[name = "S&H"]
+    SampleAndHold,
+    /// A new random value every cycle, glided to smoothly.
+    #[id = "smooth_random"]
+    #[name = "Smooth Random"]
+    SmoothRandom,
+}
+
+/// Whether an LFO is shared by every voice or each voice has its own.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LfoMode {
+    /// One LFO for the whole synth, every voice moves together.
+    #[id = "global"]
+    Global,
+    /// Every voice has its own LFO, restarted (if key retrigger is on) and faded in per note.
+    #[id = "per_voice"]
+    #[name = "Per Voice"]
+    PerVoice,
+}
+
+/// How long one cycle lasts when the rate is synced to the host's tempo, in note lengths.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LfoDivision {
+    #[id = "4_1"]
+    #[name = "4 Bars"]
+    FourBars,
+    #[id = "2_1"]
+    #[name = "2 Bars"]
+    TwoBars,
+    #[id = "1_1"]
+    #[name = "1/1"]
+    Whole,
+    #[id = "1_2"]
+    #[name = "1/2"]
+    Half,
+    #[id = "1_4"]
+    #[name = "1/4"]
+    Quarter,
+    #[id = "1_4d"]
+    #[name = "1/4 D"]
+    DottedQuarter,
+    #[id = "1_4t"]
+    #[name = "1/4 T"]
+    TripletQuarter,
+    #[id = "1_8"]
+    #[name = "1/8"]
+    Eighth,
+    #[id = "1_8d"]
+    #[name = "1/8 D"]
+    DottedEighth,
+    #[id = "1_8t"]
+    #[name = "1/8 T"]
+    TripletEighth,
+    #[id = "1_16"]
+    #[name = "1/16"]
+    Sixteenth,
+    #[id = "1_32"]
+    #[name = "1/32"]
+    ThirtySecond,
+}
+
+impl LfoDivision {
+    /// The length of one cycle, in beats (quarter notes).
+    fn beats(self) -> f32 {
+        match self {
+            LfoDivision::FourBars => 16.0,
+            LfoDivision::TwoBars => 8.0,
+            LfoDivision::Whole => 4.0,
+            LfoDivision::Half => 2.0,
+            LfoDivision::Quarter => 1.0,
+            LfoDivision::DottedQuarter => 1.5,
+            LfoDivision::TripletQuarter => 2.0 / 3.0,
+            LfoDivision::Eighth => 0.5,
+            LfoDivision::DottedEighth => 0.75,
+            LfoDivision::TripletEighth => 1.0 / 3.0,
+            LfoDivision::Sixteenth => 0.25,
+            LfoDivision::ThirtySecond => 0.125,
+        }
+    }
+
+    /// The LFO rate in Hz for this division at `tempo` beats per minute.
+    pub fn frequency(self, tempo: f64) -> f32 {
+        tempo as f32 / 60.0 / self.beats()
+    }
+
+    /// How many cycles fit into the first `beats` beats of the song, for lining the LFO up with
+    /// the host's transport.
+    pub fn cycles(self, beats: f64) -> f64 {
+        beats / self.beats() as f64
+    }
+}
+
+/// A low frequency oscillator. Its output is a control value between -1 and 1, one per sample,
+/// for modulating other parameters rather than for listening to.
+#[derive(Clone)]
+pub struct Lfo {
+    shape: LfoShape,
+    mode: LfoMode,
+    // Where the cycle starts (0..1), so e.g. a retriggered sine can start at its peak
+    phase_offset: f32,
+    retrigger: bool,
+    seed: u32,
+    random: Random,
+    sample_rate: f32,
+
+    phase: f32,
+    phase_increment: f32,
+    // Which cycle the LFO is in, counted from the start of the song when following the host's
+    // transport
+    cycle: f64,
+    // Following the host's transport rather than running freely, see `set_transport_position()`
+    follows_transport: bool,
+
+    // The random values the random shapes move from and to over the current cycle
+    previous_random: f32,
+    next_random: f32,
+
+    // How far through the fade-in we are (0..1), and how far each sample moves it on
+    fade: f32,
+    fade_increment: f32,
+}
+
+impl Lfo {
+    pub fn new(sample_rate: f32, seed: u32) -> Self {
+        let mut lfo = Self {
+            shape: LfoShape::Sine,
+            mode: LfoMode::PerVoice,
+            phase_offset: 0.0,
+            retrigger: true,
+            seed,
+            random: Random::new(seed),
+            sample_rate,
+            phase: 0.0,
+            phase_increment: 0.0,
+            cycle: 0.0,
+            follows_transport: false,
+            previous_random: 0.0,
+            next_random: 0.0,
+            fade: 1.0,
+            fade_increment: 1.0,
+        };
+        lfo.set_rate(1.0);
+        lfo.reset();
+        lfo
+    }
+
+    pub fn set_shape(&mut self, shape: LfoShape) {
+        self.shape = shape;
+    }
+
+    pub fn set_mode(&mut self, mode: LfoMode) {
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> LfoMode {
+        self.mode
+    }
+
+    /// Sets the seed the random shapes are drawn from, and restarts them from it.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+        self.reset();
+    }
+
+    /// Sets the rate in Hz. See [`LfoDivision::frequency()`] for rates synced to the tempo.
+    pub fn set_rate(&mut self, rate_hz: f32) {
+        self.phase_increment = rate_hz / self.sample_rate;
+    }
+
+    /// Sets where in its cycle the LFO starts when retriggered (0..1).
+    pub fn set_phase_offset(&mut self, offset: f32) {
+        self.phase_offset = offset.rem_euclid(1.0);
+    }
+
+    /// Sets how long the LFO takes to fade in from nothing after being triggered, in seconds.
+    pub fn set_fade_in(&mut self, fade_in_s: f32) {
+        self.fade_increment = if fade_in_s > 0.0 { 1.0 / (fade_in_s * self.sample_rate) } else { 1.0 };
+    }
+
+    /// When set, every trigger restarts the cycle. Otherwise the LFO runs freely and triggers
+    /// only restart the fade-in.
+    pub fn set_retrigger(&mut self, retrigger: bool) {
+        self.retrigger = retrigger;
+    }
+
+    /// Lines the LFO up with the host's transport, `cycles` cycles into the song, so it follows
+    /// loops and jumps. Moving into another cycle draws new random values, just as running into
+    /// it does. `None` leaves the LFO running freely from wherever it is.
+    pub fn set_transport_position(&mut self, cycles: Option<f64>) {
+        self.follows_transport = cycles.is_some();
+        let Some(cycles) = cycles else {
+            return;
+        };
+
+        let cycle = cycles.floor();
+        self.phase = (cycles - cycle) as f32;
+        if cycle != self.cycle {
+            self.cycle = cycle;
+            self.next_random_values();
+        }
+    }
+
+    /// Starts the fade-in again, and the cycle too if key retrigger is on and the LFO isn't
+    /// following the host's transport.
+    pub fn trigger(&mut self) {
+        if self.retrigger && !self.follows_transport {
+            self.phase = 0.0;
+        }
+        self.fade = 0.0;
+    }
+
+    /// Returns the LFO to how it was when created, with the random shapes starting over from
+    /// the seed.
+    pub fn reset(&mut self) {
+        self.random = Random::new(self.seed);
+        self.previous_random = self.random.next_bipolar();
+        self.next_random = self.random.next_bipolar();
+        self.phase = 0.0;
+        self.cycle = 0.0;
+        self.fade = 1.0;
+    }
+
+    fn next_random_values(&mut self) {
+        self.previous_random = self.next_random;
+        self.next_random = self.random.next_bipolar();
+    }
+
+    /// Returns the current value (-1..1) and moves on by one sample.
+    pub fn next(&mut self) -> f32 {
+        let phase = (self.phase + self.phase_offset).fract();
+
+        let value = match self.shape {
+            LfoShape::Sine => (std::f32::consts::TAU * phase).sin(),
+            // Starts at zero and rises, like the sine
+            LfoShape::Triangle => 4.0 * ((phase + 0.75).fract() - 0.5).abs() - 1.0,
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleAndHold => self.next_random,
+            LfoShape::SmoothRandom => {
+                // Cosine interpolation, so there are no corners where one cycle meets the next
+                let t = 0.5 - 0.5 * (std::f32::consts::PI * self.phase).cos();
+                self.previous_random + (self.next_random - self.previous_random) * t
+            }
+        };
+        // Faded before being applied, so without a fade-in the first value comes through in full
+        self.fade = (self.fade + self.fade_increment).min(1.0);
+        let value = value * self.fade;
+
+        self.phase += self.phase_increment;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            self.cycle += 1.0;
+            self.next_random_values();
+        }
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    const SHAPES: [LfoShape; 6] = [
+        LfoShape::Sine,
+        LfoShape::Triangle,
+        LfoShape::Saw,
+        LfoShape::Square,
+        LfoShape::SampleAndHold,
+        LfoShape::SmoothRandom,
+    ];
+
+    fn lfo(shape: LfoShape, rate_hz: f32) -> Lfo {
+        let mut lfo = Lfo::new(SAMPLE_RATE, 1);
+        lfo.set_shape(shape);
+        lfo.set_rate(rate_hz);
+        lfo
+    }
+
+    fn render(lfo: &mut Lfo, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| lfo.next()).collect()
+    }
+
+    #[test]
+    fn every_shape_stays_in_range_and_starts_where_expected() {
+        for shape in SHAPES {
+            let output = render(&mut lfo(shape, 7.0), SAMPLE_RATE as usize);
+            assert!(output.iter().all(|value| (-1.0..=1.0).contains(value)), "{shape:?}");
+            // Reaching both ends, bar the random shapes which only get a few draws
+            let (min, max) = output.iter().fold((1.0f32, -1.0f32), |(min, max), &value| {
+                (min.min(value), max.max(value))
+            });
+            if !matches!(shape, LfoShape::SampleAndHold | LfoShape::SmoothRandom) {
+                assert!(min < -0.99 && max > 0.99, "{shape:?}: {min}..{max}");
+            }
+        }
+
+        assert_eq!(lfo(LfoShape::Sine, 1.0).next(), 0.0);
+        assert_eq!(lfo(LfoShape::Triangle, 1.0).next(), 0.0);
+        assert_eq!(lfo(LfoShape::Saw, 1.0).next(), -1.0);
+        assert_eq!(lfo(LfoShape::Square, 1.0).next(), 1.0);
+
+        // Sine and triangle rise from zero
+        let mut sine = lfo(LfoShape::Sine, 1.0);
+        sine.next();
+        assert!(sine.next() > 0.0);
+        let mut triangle = lfo(LfoShape::Triangle, 1.0);
+        triangle.next();
+        assert!(triangle.next() > 0.0);
+    }
+
+    #[test]
+    fn fades_in_over_the_fade_in_time() {
+        // Slow enough to stay at the top of the square for the whole fade
+        let mut square = lfo(LfoShape::Square, 0.1);
+        let fade_len = (0.1 * SAMPLE_RATE) as usize;
+        square.set_fade_in(0.1);
+        square.trigger();
+
+        let output = render(&mut square, 2 * fade_len);
+        assert!(output[0] > 0.0 && output[0] < 0.001, "{}", output[0]);
+        assert!((output[fade_len / 2 - 1] - 0.5).abs() < 0.001, "{}", output[fade_len / 2 - 1]);
+        assert!(output[fade_len - 2] < 1.0 && output[fade_len - 1] > 0.999);
+        assert!(output[fade_len..].iter().all(|&value| value == 1.0));
+
+        // Without a fade-in, nothing is faded at all
+        square.set_fade_in(0.0);
+        square.trigger();
+        assert_eq!(square.next(), 1.0);
+    }
+
+    #[test]
+    fn triggers_restart_the_cycle_only_with_key_retrigger() {
+        let mut retriggered = lfo(LfoShape::Saw, 3.0);
+        render(&mut retriggered, 1000);
+        let mut free_running = retriggered.clone();
+        free_running.set_retrigger(false);
+        let mut untouched = retriggered.clone();
+
+        retriggered.trigger();
+        assert_eq!(retriggered.next(), -1.0);
+
+        free_running.trigger();
+        assert_eq!(free_running.next(), untouched.next());
+        assert_eq!(render(&mut free_running, 100), render(&mut untouched, 100));
+    }
+
+    #[test]
+    fn random_shapes_follow_from_the_seed() {
+        for shape in [LfoShape::SampleAndHold, LfoShape::SmoothRandom] {
+            let first = render(&mut lfo(shape, 50.0), 4800);
+            assert_eq!(render(&mut lfo(shape, 50.0), 4800), first, "{shape:?}");
+
+            let mut reseeded = lfo(shape, 50.0);
+            reseeded.set_seed(2);
+            assert_ne!(render(&mut reseeded, 4800), first, "{shape:?}");
+
+            // A reset starts over from the seed
+            reseeded.set_seed(1);
+            render(&mut reseeded, 1234);
+            reseeded.reset();
+            assert_eq!(render(&mut reseeded, 4800), first, "{shape:?}");
+        }
+    }
+
+    #[test]
+    fn divisions_turn_the_tempo_into_a_rate() {
+        assert_eq!(LfoDivision::Quarter.frequency(120.0), 2.0);
+        assert_eq!(LfoDivision::Whole.frequency(120.0), 0.5);
+        assert_eq!(LfoDivision::FourBars.frequency(120.0), 0.125);
+        assert_eq!(LfoDivision::Sixteenth.frequency(120.0), 8.0);
+        assert!((LfoDivision::DottedQuarter.frequency(120.0) - 4.0 / 3.0).abs() < 1e-6);
+        assert!((LfoDivision::TripletEighth.frequency(120.0) - 6.0).abs() < 1e-5);
+
+        assert_eq!(LfoDivision::Eighth.cycles(3.0), 6.0);
+        assert_eq!(LfoDivision::TwoBars.cycles(4.0), 0.5);
+    }
+
+    #[test]
+    fn follows_the_transport_through_jumps_and_triggers() {
+        let mut sine = lfo(LfoShape::Sine, 1.0);
+        sine.set_retrigger(true);
+
+        sine.set_transport_position(Some(5.25));
+        assert!((sine.next() - 1.0).abs() < 1e-6);
+        sine.set_transport_position(Some(2.75));
+        assert!((sine.next() + 1.0).abs() < 1e-6);
+
+        // The song position decides the phase, not the notes
+        sine.trigger();
+        assert!(sine.phase > 0.75);
+
+        // Back to running freely, triggers restart the cycle again
+        sine.set_transport_position(None);
+        sine.trigger();
+        assert_eq!(sine.phase, 0.0);
+
+        // Moving into another cycle draws a new value, staying in one keeps it
+        let mut held = lfo(LfoShape::SampleAndHold, 1.0);
+        held.set_transport_position(Some(3.1));
+        let value = held.next();
+        held.set_transport_position(Some(3.6));
+        assert_eq!(held.next(), value);
+        held.set_transport_position(Some(0.6));
+        assert_ne!(held.next(), value);
+    }
+}
@@ -1,27 +1,84 @@
 use nih_plug::{buffer::ChannelSamples, prelude::*};
 use nih_plug_vizia::ViziaState;
  use traits::AudioSource;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 
  mod sine_wave;
  mod saw_wave;
  mod square_wave;
+ mod triangle_wave;
+ mod poly_blep;
+ use poly_blep::OscillatorQuality;
+ mod fft;
+ mod noise;
+ use noise::NoiseColour;
+ mod random;
+ mod oscillator;
+ use oscillator::Waveform;
+ mod oscillator_mixer;
+ use oscillator_mixer::{SubOctave, NUM_OSCILLATORS};
+ mod unison;
+ use unison::MAX_UNISON;
+ mod wavetable;
+ mod fm;
+ use fm::{FmAlgorithm, NUM_OPERATORS};
+ use wavetable::{Wavetable, DEFAULT_WAV_FRAME_SIZE};
  mod adsr_envelope;
+ mod state_variable_filter;
+ use state_variable_filter::FilterMode;
+ mod ladder_filter;
+ use ladder_filter::LadderSlope;
+ mod smoothed_cutoff;
+ mod lfo;
+ use lfo::{LfoDivision, LfoMode, LfoShape, NUM_LFOS};
+ mod mod_matrix;
+ use mod_matrix::{ModDestination, ModSource, NUM_MOD_SLOTS};
  mod traits;
 
  mod polysynth;
- use polysynth::PolySynth;
+ use polysynth::{PlayMode, PolySynth, SynthEvent, SynthHost, VoiceStealing, MAX_BLOCK_SIZE, MAX_VOICES};
+ mod note_stack;
+ use note_stack::NotePriority;
 
  mod voice;
+ use voice::{FilterType, NoteId, VoiceEngine};
  mod gain;
  mod ramp_envelope;
  mod stereo_sample;
+ use stereo_sample::StereoSample;
+ mod velocity;
+ use velocity::VelocityCurve;
 
 mod editor;
 
+// MIDI CC numbers
+const MOD_WHEEL_CC: u8 = 1;
+const SUSTAIN_PEDAL_CC: u8 = 64;
+const SOSTENUTO_PEDAL_CC: u8 = 66;
+
+/// Work done off the audio thread.
+pub enum Task {
+    /// Loads the wavetable file the parameters point to, for the audio thread to pick up.
+    LoadWavetable,
+}
+
+/// Passes wavetables loaded in the background to the audio thread, and the ones they replace
+/// back again, so a wavetable is never freed on the audio thread.
+#[derive(Default)]
+struct WavetableHandoff {
+    loaded: Option<Arc<Wavetable>>,
+    retired: Option<Arc<Wavetable>>,
+}
+
 pub struct PolySynthPlugin {
     params: Arc<PolySynthParams>,
-    poly_synth: PolySynth
+    poly_synth: PolySynth,
+    // The wavetable the synth is playing
+    wavetable: Arc<Wavetable>,
+    wavetable_handoff: Arc<Mutex<WavetableHandoff>>,
+    // The synth renders into this before it's copied to the output channels, long enough for the
+    // host's largest buffer
+    render_buffer: Vec<StereoSample>,
 }
 
 #[derive(Params)]
@@ -31,6 +88,12 @@ struct PolySynthParams {
     #[persist = "editor-state"]
     editor_state: Arc<ViziaState>,
 
+    /// A single-cycle wavetable WAV file to load instead of the built-in shapes, or empty for
+    /// the built-in ones. Set from the editor and loaded in the background, never on the audio
+    /// thread.
+    #[persist = "wavetable-path"]
+    pub wavetable_path: RwLock<String>,
+
     #[id = "attack"]
     pub attack: FloatParam,
 
@@ -43,15 +106,466 @@ struct PolySynthParams {
     #[id = "release"]
     pub release: FloatParam,
 
+    #[id = "attack_curve"]
+    pub attack_curve: FloatParam,
+
+    #[id = "decay_curve"]
+    pub decay_curve: FloatParam,
+
+    #[id = "release_curve"]
+    pub release_curve: FloatParam,
+
+    #[id = "rc_release"]
+    pub rc_release: BoolParam,
+
+    #[id = "release_threshold"]
+    pub release_threshold: FloatParam,
+
     #[id = "glide"]
     pub glide: FloatParam,
+
+    /// How far the pitch bend wheel bends at either end.
+    #[id = "pitch_bend_range"]
+    pub pitch_bend_range: IntParam,
+
+    #[id = "filter_type"]
+    pub filter_type: EnumParam<FilterType>,
+
+    #[id = "filter_mode"]
+    pub filter_mode: EnumParam<FilterMode>,
+
+    #[id = "filter_slope"]
+    pub filter_slope: EnumParam<LadderSlope>,
+
+    #[id = "filter_cutoff"]
+    pub filter_cutoff: FloatParam,
+
+    #[id = "filter_resonance"]
+    pub filter_resonance: FloatParam,
+
+    #[id = "filter_drive"]
+    pub filter_drive: FloatParam,
+
+    #[id = "filter_attack"]
+    pub filter_attack: FloatParam,
+
+    #[id = "filter_decay"]
+    pub filter_decay: FloatParam,
+
+    #[id = "filter_sustain"]
+    pub filter_sustain: FloatParam,
+
+    #[id = "filter_release"]
+    pub filter_release: FloatParam,
+
+    #[id = "filter_env_amount"]
+    pub filter_env_amount: FloatParam,
+
+    #[id = "filter_key_tracking"]
+    pub filter_key_tracking: FloatParam,
+
+    #[id = "filter_velocity"]
+    pub filter_velocity: FloatParam,
+
+    #[id = "engine"]
+    pub engine: EnumParam<VoiceEngine>,
+
+    #[id = "fm_algorithm"]
+    pub fm_algorithm: EnumParam<FmAlgorithm>,
+
+    #[nested(array, group = "Operator")]
+    pub operators: [OperatorParams; NUM_OPERATORS],
+
+    #[nested(array, group = "Oscillator")]
+    pub oscillators: [OscillatorParams; NUM_OSCILLATORS],
+
+    #[nested(array, group = "LFO")]
+    pub lfos: [LfoParams; NUM_LFOS],
+
+    #[nested(array, group = "Mod Slot")]
+    pub mod_slots: [ModSlotParams; NUM_MOD_SLOTS],
+
+    #[id = "sub_waveform"]
+    pub sub_waveform: EnumParam<Waveform>,
+
+    #[id = "sub_octave"]
+    pub sub_octave: EnumParam<SubOctave>,
+
+    #[id = "sub_level"]
+    pub sub_level: FloatParam,
+
+    #[id = "noise_level"]
+    pub noise_level: FloatParam,
+
+    #[id = "noise_colour"]
+    pub noise_colour: EnumParam<NoiseColour>,
+
+    #[id = "noise_stereo"]
+    pub noise_stereo: BoolParam,
+
+    #[id = "unison"]
+    pub unison: IntParam,
+
+    #[id = "unison_detune"]
+    pub unison_detune: FloatParam,
+
+    #[id = "unison_detune_curve"]
+    pub unison_detune_curve: FloatParam,
+
+    #[id = "unison_spread"]
+    pub unison_spread: FloatParam,
+
+    #[id = "unison_blend"]
+    pub unison_blend: FloatParam,
+
+    #[id = "pulse_width"]
+    pub pulse_width: FloatParam,
+
+    #[id = "wavetable_position"]
+    pub wavetable_position: FloatParam,
+
+    #[id = "osc_quality"]
+    pub osc_quality: EnumParam<OscillatorQuality>,
+
+    #[id = "velocity_depth"]
+    pub velocity_depth: FloatParam,
+
+    #[id = "velocity_curve"]
+    pub velocity_curve: EnumParam<VelocityCurve>,
+
+    #[id = "voices"]
+    pub voices: IntParam,
+
+    #[id = "voice_stealing"]
+    pub voice_stealing: EnumParam<VoiceStealing>,
+
+    #[id = "play_mode"]
+    pub play_mode: EnumParam<PlayMode>,
+
+    #[id = "note_priority"]
+    pub note_priority: EnumParam<NotePriority>,
+}
+
+/// The settings of one of the oscillators in every voice.
+#[derive(Params)]
+struct OscillatorParams {
+    #[id = "waveform"]
+    pub waveform: EnumParam<Waveform>,
+
+    #[id = "octave"]
+    pub octave: IntParam,
+
+    #[id = "semitone"]
+    pub semitone: IntParam,
+
+    #[id = "fine"]
+    pub fine: FloatParam,
+
+    #[id = "level"]
+    pub level: FloatParam,
+
+    #[id = "pan"]
+    pub pan: FloatParam,
+
+    /// Hard sync to oscillator 1. Does nothing on oscillator 1 itself.
+    #[id = "sync"]
+    pub sync: BoolParam,
+
+    /// Ring modulation by oscillator 1. Does nothing on oscillator 1 itself.
+    #[id = "ring"]
+    pub ring: FloatParam,
+}
+
+impl OscillatorParams {
+    fn new(index: usize) -> Self {
+        let number = index + 1;
+
+        Self {
+            waveform: EnumParam::new(format!("Osc {number} Waveform"), Waveform::Saw),
+            octave: IntParam::new(
+                format!("Osc {number} Octave"),
+                0,
+                IntRange::Linear { min: -3, max: 3 },
+            ),
+            semitone: IntParam::new(
+                format!("Osc {number} Semitone"),
+                0,
+                IntRange::Linear { min: -12, max: 12 },
+            )
+            .with_unit(" st"),
+            fine: FloatParam::new(
+                format!("Osc {number} Fine"),
+                0.0,
+                FloatRange::Linear { min: -100.0, max: 100.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.1)
+            .with_unit(" ct"),
+            // Only the first oscillator is heard by default
+            level: FloatParam::new(
+                format!("Osc {number} Level"),
+                if index == 0 { 1.0 } else { 0.0 },
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01),
+            pan: FloatParam::new(
+                format!("Osc {number} Pan"),
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01),
+            sync: BoolParam::new(format!("Osc {number} Sync"), false),
+            ring: FloatParam::new(
+                format!("Osc {number} Ring"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01),
+        }
+    }
+}
+
+/// The settings of one of the FM engine's operators.
+#[derive(Params)]
+struct OperatorParams {
+    #[id = "ratio"]
+    pub ratio: FloatParam,
+
+    #[id = "fixed"]
+    pub fixed: BoolParam,
+
+    #[id = "fixed_frequency"]
+    pub fixed_frequency: FloatParam,
+
+    #[id = "level"]
+    pub level: FloatParam,
+
+    #[id = "feedback"]
+    pub feedback: FloatParam,
+
+    #[id = "velocity"]
+    pub velocity_sensitivity: FloatParam,
+
+    #[id = "attack"]
+    pub attack: FloatParam,
+
+    #[id = "decay"]
+    pub decay: FloatParam,
+
+    #[id = "sustain"]
+    pub sustain: FloatParam,
+
+    #[id = "release"]
+    pub release: FloatParam,
+}
+
+impl OperatorParams {
+    fn new(index: usize) -> Self {
+        let number = index + 1;
+
+        Self {
+            ratio: FloatParam::new(
+                format!("Op {number} Ratio"),
+                1.0,
+                FloatRange::Skewed { min: 0.5, max: 16.0, factor: FloatRange::skew_factor(-1.0) },
+            )
+            .with_step_size(0.01),
+            fixed: BoolParam::new(format!("Op {number} Fixed"), false),
+            fixed_frequency: FloatParam::new(
+                format!("Op {number} Fixed Frequency"),
+                440.0,
+                FloatRange::Skewed { min: 1.0, max: 20000.0, factor: FloatRange::skew_factor(-2.0) },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(10.0))
+            .with_step_size(0.1)
+            .with_unit(" Hz"),
+            // Operator 2 gently modulating operator 1 by default, the rest are silent
+            level: FloatParam::new(
+                format!("Op {number} Level"),
+                match index {
+                    0 => 1.0,
+                    1 => 0.5,
+                    _ => 0.0,
+                },
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01),
+            feedback: FloatParam::new(
+                format!("Op {number} Feedback"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01),
+            velocity_sensitivity: FloatParam::new(
+                format!("Op {number} Velocity"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01),
+            attack: FloatParam::new(
+                format!("Op {number} Attack"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01)
+            .with_unit("s"),
+            decay: FloatParam::new(
+                format!("Op {number} Decay"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01)
+            .with_unit("s"),
+            sustain: FloatParam::new(
+                format!("Op {number} Sustain"),
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01),
+            release: FloatParam::new(
+                format!("Op {number} Release"),
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01)
+            .with_unit("s"),
+        }
+    }
+}
+
+/// The settings of one of the LFOs.
+#[derive(Params)]
+struct LfoParams {
+    #[id = "shape"]
+    pub shape: EnumParam<LfoShape>,
+
+    #[id = "rate"]
+    pub rate: FloatParam,
+
+    /// Follow the host's tempo at `division` instead of `rate`.
+    #[id = "sync"]
+    pub sync: BoolParam,
+
+    #[id = "division"]
+    pub division: EnumParam<LfoDivision>,
+
+    #[id = "phase"]
+    pub phase: FloatParam,
+
+    #[id = "fade_in"]
+    pub fade_in: FloatParam,
+
+    #[id = "retrigger"]
+    pub retrigger: BoolParam,
+
+    #[id = "mode"]
+    pub mode: EnumParam<LfoMode>,
+}
+
+impl LfoParams {
+    fn new(index: usize) -> Self {
+        let number = index + 1;
+
+        // LFO 1 is the mod wheel's vibrato to begin with, so it starts at a vibrato rate
+        let rate = if index == 0 { 5.0 } else { 2.0 };
+
+        Self {
+            shape: EnumParam::new(format!("LFO {number} Shape"), LfoShape::Sine),
+            rate: FloatParam::new(
+                format!("LFO {number} Rate"),
+                rate,
+                FloatRange::Skewed { min: 0.01, max: 50.0, factor: FloatRange::skew_factor(-2.0) },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(20.0))
+            .with_step_size(0.01)
+            .with_unit(" Hz"),
+            sync: BoolParam::new(format!("LFO {number} Sync"), false),
+            division: EnumParam::new(format!("LFO {number} Division"), LfoDivision::Quarter),
+            phase: FloatParam::new(
+                format!("LFO {number} Phase"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 360.0 },
+            )
+            .with_step_size(1.0)
+            .with_unit("°"),
+            fade_in: FloatParam::new(
+                format!("LFO {number} Fade In"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 5.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01)
+            .with_unit("s"),
+            retrigger: BoolParam::new(format!("LFO {number} Retrigger"), true),
+            mode: EnumParam::new(format!("LFO {number} Mode"), LfoMode::PerVoice),
+        }
+    }
+}
+
+/// One routing in the mod matrix.
+#[derive(Params)]
+struct ModSlotParams {
+    #[id = "source"]
+    pub source: EnumParam<ModSource>,
+
+    #[id = "destination"]
+    pub destination: EnumParam<ModDestination>,
+
+    #[id = "amount"]
+    pub amount: FloatParam,
+
+    /// Another source the amount is scaled by, e.g. the mod wheel.
+    #[id = "via"]
+    pub via: EnumParam<ModSource>,
+}
+
+impl ModSlotParams {
+    fn new(index: usize) -> Self {
+        let number = index + 1;
+
+        // The first slot starts out as vibrato on the mod wheel: LFO 1 on the pitch, half a
+        // semitone either way with the wheel all the way up
+        let (source, destination, amount, via) = if index == 0 {
+            (ModSource::Lfo1, ModDestination::Pitch, 0.04, ModSource::ModWheel)
+        } else {
+            (ModSource::Off, ModDestination::Off, 0.0, ModSource::Off)
+        };
+
+        Self {
+            source: EnumParam::new(format!("Mod {number} Source"), source),
+            destination: EnumParam::new(format!("Mod {number} Destination"), destination),
+            amount: FloatParam::new(
+                format!("Mod {number} Amount"),
+                amount,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_step_size(0.01),
+            via: EnumParam::new(format!("Mod {number} Via"), via),
+        }
+    }
 }
 
 impl Default for PolySynthPlugin {
     fn default() -> Self {
         Self {
             params: Arc::new(PolySynthParams::default()),
-            poly_synth: PolySynth::new(48000, 3)
+            // Rebuilt with the host's sample rate in `initialize()`
+            poly_synth: PolySynth::new(48000, 8),
+            wavetable: Wavetable::basic_shapes(),
+            wavetable_handoff: Arc::new(Mutex::new(WavetableHandoff::default())),
+            // Sized for the host's buffers in `initialize()`
+            render_buffer: Vec::new(),
         }
     }
 }
@@ -60,6 +574,7 @@ impl Default for PolySynthParams {
     fn default() -> Self {
         Self {
             editor_state: editor::default_state(),
+            wavetable_path: RwLock::new(String::new()),
 
             attack: FloatParam::new(
                 "Attack",
@@ -92,6 +607,35 @@ impl Default for PolySynthParams {
             .with_smoother(SmoothingStyle::Linear(50.0))
             .with_step_size(0.01)
             .with_unit("s"),
+            attack_curve: FloatParam::new(
+                "Attack Curve",
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01),
+            decay_curve: FloatParam::new(
+                "Decay Curve",
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01),
+            release_curve: FloatParam::new(
+                "Release Curve",
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01),
+            rc_release: BoolParam::new("RC Release", false),
+            release_threshold: FloatParam::new(
+                "Release Threshold",
+                -60.0,
+                FloatRange::Linear { min: -96.0, max: -24.0 },
+            )
+            .with_step_size(1.0)
+            .with_unit(" dB"),
             glide: FloatParam::new(
                 "Glide",
                 0.1,
@@ -100,13 +644,382 @@ impl Default for PolySynthParams {
             .with_smoother(SmoothingStyle::Linear(50.0))
             .with_step_size(0.01)
             .with_unit("s"),
+            pitch_bend_range: IntParam::new(
+                "Pitch Bend Range",
+                2,
+                IntRange::Linear { min: 0, max: 24 },
+            )
+            .with_unit(" st"),
+            filter_type: EnumParam::new("Filter Type", FilterType::Svf),
+            filter_mode: EnumParam::new("Filter Mode", FilterMode::LowPass),
+            filter_slope: EnumParam::new("Filter Slope", LadderSlope::Db24),
+            filter_cutoff: FloatParam::new(
+                "Filter Cutoff",
+                20000.0,
+                FloatRange::Skewed { min: 20.0, max: 20000.0, factor: FloatRange::skew_factor(-2.0) },
+            )
+            .with_smoother(SmoothingStyle::Logarithmic(20.0))
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            filter_resonance: FloatParam::new(
+                "Filter Resonance",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_step_size(0.01),
+            filter_drive: FloatParam::new(
+                "Filter Drive",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 24.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_step_size(0.1)
+            .with_unit(" dB"),
+            filter_attack: FloatParam::new(
+                "Filter Attack",
+                0.01,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01)
+            .with_unit("s"),
+            filter_decay: FloatParam::new(
+                "Filter Decay",
+                0.3,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01)
+            .with_unit("s"),
+            filter_sustain: FloatParam::new(
+                "Filter Sustain",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01),
+            filter_release: FloatParam::new(
+                "Filter Release",
+                0.3,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01)
+            .with_unit("s"),
+            filter_env_amount: FloatParam::new(
+                "Filter Env Amount",
+                0.0,
+                FloatRange::Linear { min: -8.0, max: 8.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_step_size(0.01)
+            .with_unit(" oct"),
+            filter_key_tracking: FloatParam::new(
+                "Filter Key Tracking",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            filter_velocity: FloatParam::new(
+                "Filter Velocity",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 4.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(20.0))
+            .with_step_size(0.01)
+            .with_unit(" oct"),
+            engine: EnumParam::new("Engine", VoiceEngine::Oscillators),
+            fm_algorithm: EnumParam::new("FM Algorithm", FmAlgorithm::Dx1),
+            operators: std::array::from_fn(OperatorParams::new),
+            oscillators: std::array::from_fn(OscillatorParams::new),
+            lfos: std::array::from_fn(LfoParams::new),
+            mod_slots: std::array::from_fn(ModSlotParams::new),
+            sub_waveform: EnumParam::new("Sub Waveform", Waveform::Sine),
+            sub_octave: EnumParam::new("Sub Octave", SubOctave::One),
+            sub_level: FloatParam::new(
+                "Sub Level",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01),
+            noise_level: FloatParam::new(
+                "Noise Level",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01),
+            noise_colour: EnumParam::new("Noise Colour", NoiseColour::White),
+            noise_stereo: BoolParam::new("Stereo Noise", false),
+            unison: IntParam::new(
+                "Unison",
+                1,
+                IntRange::Linear { min: 1, max: MAX_UNISON as i32 },
+            ),
+            unison_detune: FloatParam::new(
+                "Unison Detune",
+                20.0,
+                FloatRange::Linear { min: 0.0, max: 100.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.1)
+            .with_unit(" ct"),
+            unison_detune_curve: FloatParam::new(
+                "Unison Detune Curve",
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01),
+            unison_spread: FloatParam::new(
+                "Unison Spread",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            unison_blend: FloatParam::new(
+                "Unison Blend",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01),
+            pulse_width: FloatParam::new(
+                "Pulse Width",
+                0.5,
+                FloatRange::Linear { min: 0.01, max: 0.99 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            wavetable_position: FloatParam::new(
+                "Wavetable Position",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01),
+            osc_quality: EnumParam::new("Oscillator Quality", OscillatorQuality::High),
+            velocity_depth: FloatParam::new(
+                "Velocity",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.01),
+            velocity_curve: EnumParam::new("Velocity Curve", VelocityCurve::Linear),
+            voices: IntParam::new(
+                "Voices",
+                8,
+                IntRange::Linear { min: 1, max: MAX_VOICES as i32 },
+            ),
+            voice_stealing: EnumParam::new("Voice Stealing", VoiceStealing::Oldest),
+            play_mode: EnumParam::new("Play Mode", PlayMode::Poly),
+            note_priority: EnumParam::new("Note Priority", NotePriority::Last),
+        }
+    }
+}
+
+/// Translates a note event from the host for the synth, or returns `None` for any it doesn't use.
+fn synth_event(event: NoteEvent<()>) -> Option<SynthEvent> {
+    let event = match event {
+        NoteEvent::NoteOn { timing: _, voice_id, channel, note, velocity } => {
+            SynthEvent::NoteOn { note_id: NoteId { note, channel, voice_id }, velocity }
+        }
+        NoteEvent::NoteOff { timing:_, voice_id, channel, note, velocity:_ } => {
+            SynthEvent::NoteOff { note_id: NoteId { note, channel, voice_id } }
+        }
+        NoteEvent::PolyPressure { timing:_, voice_id, channel, note, pressure } => {
+            SynthEvent::PolyPressure { note_id: NoteId { note, channel, voice_id }, pressure }
+        }
+        NoteEvent::MidiPitchBend { timing:_, channel:_, value } => {
+            // Sent as 0..1 with the wheel at rest in the middle
+            SynthEvent::PitchBend(value * 2.0 - 1.0)
+        }
+        NoteEvent::MidiChannelPressure { timing:_, channel:_, pressure } => {
+            SynthEvent::ChannelPressure(pressure)
+        }
+        NoteEvent::MidiCC { timing:_, channel:_, cc: MOD_WHEEL_CC, value } => SynthEvent::ModWheel(value),
+        // Pedals count as down from halfway, as the MIDI spec has it
+        NoteEvent::MidiCC { timing:_, channel:_, cc: SUSTAIN_PEDAL_CC, value } => {
+            SynthEvent::SustainPedal(value >= 0.5)
+        }
+        NoteEvent::MidiCC { timing:_, channel:_, cc: SOSTENUTO_PEDAL_CC, value } => {
+            SynthEvent::SostenutoPedal(value >= 0.5)
+        }
+        _ => return None,
+    };
+
+    Some(event)
+}
+
+/// Gives the synth the host's events and the parameters for one call to `process()`.
+struct PluginHost<'a, C: ProcessContext<PolySynthPlugin>> {
+    params: &'a PolySynthParams,
+    context: &'a mut C,
+}
+
+impl<C: ProcessContext<PolySynthPlugin>> SynthHost for PluginHost<'_, C> {
+    fn next_event(&mut self) -> Option<(usize, SynthEvent)> {
+        while let Some(event) = self.context.next_event() {
+            let timing = event.timing() as usize;
+            if let Some(event) = synth_event(event) {
+                return Some((timing, event));
+            }
         }
+
+        None
+    }
+
+    fn voice_terminated(&mut self, timing: usize, note_id: NoteId) {
+        self.context.send_event(NoteEvent::VoiceTerminated {
+            timing: timing as u32,
+            voice_id: note_id.voice_id,
+            channel: note_id.channel,
+            note: note_id.note,
+        });
+    }
+
+    fn update_voice_allocation(&mut self, synth: &mut PolySynth) {
+        let params = self.params;
+        synth.set_voice_count(params.voices.value() as usize);
+        synth.set_voice_stealing(params.voice_stealing.value());
+        synth.set_play_mode(params.play_mode.value());
+        synth.set_note_priority(params.note_priority.value());
+    }
+
+    fn update_params(&mut self, synth: &mut PolySynth, timing: usize) {
+        let params = self.params;
+        let block_len = MAX_BLOCK_SIZE as u32;
+        // The host's tempo, if it reports one, for tempo synced LFOs
+        let transport = self.context.transport();
+        let tempo = transport.tempo;
+        // Where the song is at `timing`, in beats, for lining tempo synced LFOs up with it
+        let beats = match (transport.playing, tempo, transport.pos_beats()) {
+            (true, Some(tempo), Some(beats)) => {
+                Some(beats + timing as f64 * tempo / 60.0 / transport.sample_rate as f64)
+            }
+            _ => None,
+        };
+
+        synth.set_attack(params.attack.smoothed.next_step(block_len));
+        synth.set_decay(params.decay.smoothed.next_step(block_len));
+        synth.set_sustain(params.sustain.smoothed.next_step(block_len));
+        synth.set_release(params.release.smoothed.next_step(block_len));
+        synth.set_attack_curve(params.attack_curve.smoothed.next_step(block_len));
+        synth.set_decay_curve(params.decay_curve.smoothed.next_step(block_len));
+        synth.set_release_curve(params.release_curve.smoothed.next_step(block_len));
+        synth.set_rc_release(params.rc_release.value());
+        synth.set_release_threshold(util::db_to_gain(params.release_threshold.value()));
+        synth.set_glide(params.glide.smoothed.next_step(block_len));
+        synth.set_pitch_bend_range(params.pitch_bend_range.value() as f32);
+        synth.set_filter_type(params.filter_type.value());
+        synth.set_filter_mode(params.filter_mode.value());
+        synth.set_filter_slope(params.filter_slope.value());
+        synth.set_filter_cutoff(params.filter_cutoff.smoothed.next_step(block_len));
+        synth.set_filter_resonance(params.filter_resonance.smoothed.next_step(block_len));
+        synth.set_filter_drive(util::db_to_gain(params.filter_drive.smoothed.next_step(block_len)));
+        synth.set_filter_attack(params.filter_attack.smoothed.next_step(block_len));
+        synth.set_filter_decay(params.filter_decay.smoothed.next_step(block_len));
+        synth.set_filter_sustain(params.filter_sustain.smoothed.next_step(block_len));
+        synth.set_filter_release(params.filter_release.smoothed.next_step(block_len));
+        synth.set_filter_env_amount(params.filter_env_amount.smoothed.next_step(block_len));
+        synth.set_key_tracking(params.filter_key_tracking.smoothed.next_step(block_len));
+        synth.set_filter_velocity_depth(params.filter_velocity.smoothed.next_step(block_len));
+        synth.set_voice_engine(params.engine.value());
+        synth.set_fm_algorithm(params.fm_algorithm.value());
+        for (index, op) in params.operators.iter().enumerate() {
+            synth.set_fm_ratio(index, op.ratio.value());
+            synth.set_fm_fixed(index, op.fixed.value());
+            synth.set_fm_fixed_frequency(index, op.fixed_frequency.smoothed.next_step(block_len));
+            synth.set_fm_level(index, op.level.smoothed.next_step(block_len));
+            synth.set_fm_feedback(index, op.feedback.smoothed.next_step(block_len));
+            synth.set_fm_velocity_sensitivity(index, op.velocity_sensitivity.smoothed.next_step(block_len));
+            synth.set_fm_attack(index, op.attack.smoothed.next_step(block_len));
+            synth.set_fm_decay(index, op.decay.smoothed.next_step(block_len));
+            synth.set_fm_sustain(index, op.sustain.smoothed.next_step(block_len));
+            synth.set_fm_release(index, op.release.smoothed.next_step(block_len));
+        }
+        for (index, slot) in params.mod_slots.iter().enumerate() {
+            synth.set_mod_source(index, slot.source.value());
+            synth.set_mod_destination(index, slot.destination.value());
+            synth.set_mod_amount(index, slot.amount.smoothed.next_step(block_len));
+            synth.set_mod_via(index, slot.via.value());
+        }
+        for (index, lfo) in params.lfos.iter().enumerate() {
+            // Falls back to the free running rate when the host doesn't say what its tempo is
+            let rate = lfo.rate.smoothed.next_step(block_len);
+            let rate = match tempo {
+                Some(tempo) if lfo.sync.value() => lfo.division.value().frequency(tempo),
+                _ => rate,
+            };
+            synth.set_lfo_rate(index, rate);
+            // While the host plays, global synced LFOs follow its song position
+            let division = lfo.division.value();
+            let cycles = beats.filter(|_| lfo.sync.value()).map(|beats| division.cycles(beats));
+            synth.set_lfo_transport_position(index, cycles);
+            synth.set_lfo_shape(index, lfo.shape.value());
+            synth.set_lfo_phase_offset(index, lfo.phase.value() / 360.0);
+            synth.set_lfo_fade_in(index, lfo.fade_in.smoothed.next_step(block_len));
+            synth.set_lfo_retrigger(index, lfo.retrigger.value());
+            synth.set_lfo_mode(index, lfo.mode.value());
+        }
+        for (index, osc) in params.oscillators.iter().enumerate() {
+            synth.set_osc_waveform(index, osc.waveform.value());
+            synth.set_osc_octave(index, osc.octave.value());
+            synth.set_osc_semitone(index, osc.semitone.value());
+            synth.set_osc_fine(index, osc.fine.smoothed.next_step(block_len));
+            synth.set_osc_level(index, osc.level.smoothed.next_step(block_len));
+            synth.set_osc_pan(index, osc.pan.smoothed.next_step(block_len));
+            synth.set_osc_sync(index, osc.sync.value());
+            synth.set_osc_ring(index, osc.ring.smoothed.next_step(block_len));
+        }
+        synth.set_sub_waveform(params.sub_waveform.value());
+        synth.set_sub_octave(params.sub_octave.value());
+        synth.set_sub_level(params.sub_level.smoothed.next_step(block_len));
+        synth.set_noise_level(params.noise_level.smoothed.next_step(block_len));
+        synth.set_noise_colour(params.noise_colour.value());
+        synth.set_noise_stereo(params.noise_stereo.value());
+        synth.set_unison(params.unison.value() as usize);
+        synth.set_unison_detune(params.unison_detune.smoothed.next_step(block_len));
+        synth.set_unison_detune_curve(params.unison_detune_curve.smoothed.next_step(block_len));
+        synth.set_unison_spread(params.unison_spread.smoothed.next_step(block_len));
+        synth.set_unison_blend(params.unison_blend.smoothed.next_step(block_len));
+        synth.set_pulse_width(params.pulse_width.smoothed.next_step(block_len));
+        synth.set_wavetable_position(params.wavetable_position.smoothed.next_step(block_len));
+        synth.set_oscillator_quality(params.osc_quality.value());
+        synth.set_velocity_depth(params.velocity_depth.smoothed.next_step(block_len));
+        synth.set_velocity_curve(params.velocity_curve.value());
     }
 }
 
-fn midi_note_to_frequency(note_number: u8) -> f32 {
-    // A4 = note 69 = 440 Hz
-    440.0 * 2.0_f32.powf((note_number as f32 - 69.0) / 12.0)
+/// Loads the wavetable WAV file at `path`, or the built-in shapes if there's no path. Falls back
+/// to the built-in shapes when it can't be read.
+fn load_wavetable(path: &str) -> Arc<Wavetable> {
+    if path.is_empty() {
+        return Wavetable::basic_shapes();
+    }
+
+    let wavetable = std::fs::read(path)
+        .map_err(|err| err.to_string())
+        .and_then(|bytes| Wavetable::from_wav(&bytes, DEFAULT_WAV_FRAME_SIZE).map_err(|err| err.to_string()));
+
+    match wavetable {
+        Ok(wavetable) => Arc::new(wavetable),
+        Err(err) => {
+            nih_error!("Failed to load wavetable '{path}': {err}");
+            Wavetable::basic_shapes()
+        }
+    }
 }
 
 impl Plugin for PolySynthPlugin {
@@ -132,32 +1045,64 @@ impl Plugin for PolySynthPlugin {
         },
     ];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     type SysExMessage = ();
-    type BackgroundTask = ();
+    type BackgroundTask = Task;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
-    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let params = self.params.clone();
+        let wavetable_handoff = self.wavetable_handoff.clone();
+
+        Box::new(move |task| match task {
+            Task::LoadWavetable => {
+                let path = params.wavetable_path.read().unwrap().clone();
+                let wavetable = load_wavetable(&path);
+
+                // The audio thread only retires a wavetable after picking up a loaded one, so
+                // clearing it here means it never has to drop one itself
+                let mut handoff = wavetable_handoff.lock().unwrap();
+                handoff.retired = None;
+                handoff.loaded = Some(wavetable);
+            }
+        })
+    }
+
+    fn editor(&mut self, async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         editor::create(
             self.params.clone(),
             self.params.editor_state.clone(),
+            async_executor,
         )
     }
 
     fn initialize(
         &mut self,
         _audio_io_layout: &AudioIOLayout,
-        _buffer_config: &BufferConfig,
+        buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
+        // Oscillator increments and envelope timings all depend on the sample rate, so start
+        // from a fresh engine rather than patching the old one.
+        self.poly_synth = PolySynth::new(
+            buffer_config.sample_rate as u32,
+            self.params.voices.value() as usize,
+        );
+        self.wavetable = load_wavetable(&self.params.wavetable_path.read().unwrap());
+        self.poly_synth.set_wavetable(self.wavetable.clone());
+        self.render_buffer = vec![StereoSample::from_mono(0.0); buffer_config.max_buffer_size as usize];
         true
     }
 
+    fn reset(&mut self) {
+        self.poly_synth.reset();
+    }
+
     fn process(
         &mut self,
         buffer: &mut Buffer,
@@ -165,49 +1110,37 @@ impl Plugin for PolySynthPlugin {
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
 
-        // Pull in note events
-        while let Some(event) = context.next_event() {
-
-            match event {
-                NoteEvent::NoteOn { timing: _, voice_id: _, channel:_, note, velocity:_ } => {
-                    let freq = midi_note_to_frequency(note);
-                    self.poly_synth.play(freq);
-                }
-                NoteEvent::NoteOff { timing:_, voice_id:_, channel:_, note, velocity:_ } => {
-                    let freq = midi_note_to_frequency(note);
-                    self.poly_synth.stop(freq);
-                }
-                _ => {}
+        // Picks up a newly loaded wavetable, unless it's being handed over right now
+        if let Ok(mut handoff) = self.wavetable_handoff.try_lock() {
+            if let Some(wavetable) = handoff.loaded.take() {
+                self.poly_synth.set_wavetable(wavetable.clone());
+                handoff.retired = Some(std::mem::replace(&mut self.wavetable, wavetable));
             }
         }
-    
+
+        let num_samples = buffer.samples();
+        let rendered = &mut self.render_buffer[..num_samples];
+        let mut host = PluginHost { params: &self.params, context };
+        self.poly_synth.process(rendered, &mut host);
+
         // Fill the audio buffer
-        // For each sample index, `channels` is a slice where `channels[0]` is the left channel,
-        // `channels[1]` is the right channel, etc.
-        for mut channels in buffer.iter_samples() {
-
-            self.poly_synth.set_attack(self.params.attack.smoothed.next());
-            self.poly_synth.set_decay(self.params.decay.smoothed.next());
-            self.poly_synth.set_sustain(self.params.sustain.smoothed.next());
-            self.poly_synth.set_release(self.params.release.smoothed.next());
-            self.poly_synth.set_glide(self.params.glide.smoothed.next());
-
-            // Get the next sample from your synth/oscillator
-            let next_out = self.poly_synth.next_sample(); 
-
-            match channels.len() {
-                1 => {
-                    // `get_mut(0)` gives you a `&mut f32` for the first channel
-                    *channels.get_mut(0).unwrap() = next_out.to_mono();
+        // `output[0]` is the left channel, `output[1]` is the right channel, etc.
+        let output = buffer.as_slice();
+        match output.len() {
+            1 => {
+                for (out, sample) in output[0].iter_mut().zip(rendered.iter()) {
+                    *out = sample.to_mono();
                 }
-                2 => {
-                    *channels.get_mut(0).unwrap() = next_out.left;
-                    *channels.get_mut(1).unwrap() = next_out.right;
-                }
-                _ => {
-                    // handle more channels (or do nothing)
+            }
+            2 => {
+                for (sample_idx, sample) in rendered.iter().enumerate() {
+                    output[0][sample_idx] = sample.left;
+                    output[1][sample_idx] = sample.right;
                 }
-            }            
+            }
+            _ => {
+                // handle more channels (or do nothing)
+            }
         }
 
         ProcessStatus::Normal
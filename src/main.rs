@@ -4,16 +4,33 @@ use audio_gen::PolySynthPlugin;
 mod sine_wave;
 mod saw_wave;
 mod square_wave;
+mod triangle_wave;
+mod poly_blep;
+mod fft;
+mod noise;
+mod random;
+mod oscillator;
+mod oscillator_mixer;
+mod unison;
+mod wavetable;
+mod fm;
 mod adsr_envelope;
+mod state_variable_filter;
+mod ladder_filter;
+mod smoothed_cutoff;
+mod lfo;
+mod mod_matrix;
 mod traits;
 
 mod polysynth;
+mod note_stack;
 
 mod voice;
 mod gain;
 mod stereo_sample;
 
 mod ramp_envelope;
+mod velocity;
 
 fn main() {
     nih_export_standalone::<crate::PolySynthPlugin>();
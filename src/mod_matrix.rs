@@ -0,0 +1,371 @@
+use nih_plug::prelude::Enum;
+
+use crate::lfo::NUM_LFOS;
+
+/// How many routings the matrix has room for.
+pub const NUM_MOD_SLOTS: usize = 16;
+
+// How far a slot at full amount moves each destination, in the destination's own units.
+const PITCH_RANGE_ST: f32 = 12.0;
+const CUTOFF_RANGE_OCTAVES: f32 = 8.0;
+const PULSE_WIDTH_RANGE: f32 = 0.5;
+// Envelope times are scaled in octaves of time, up to 16 times longer or shorter
+const ENVELOPE_TIME_RANGE_OCTAVES: f32 = 4.0;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ModSource {
+    #[id = "off"]
+    Off,
+    #[id = "amp_env"]
+    #[name = "Amp Env"]
+    AmpEnvelope,
+    #[id = "filter_env"]
+    #[name = "Filter Env"]
+    FilterEnvelope,
+    #[id = "lfo1"]
+    #[name = "LFO 1"]
+    Lfo1,
+    #[id = "lfo2"]
+    #[name = "LFO 2"]
+    Lfo2,
+    #[id = "velocity"]
+    Velocity,
+    /// The note played, -1..1 over five octaves either side of middle C.
+    #[id = "key"]
+    Key,
+    #[id = "mod_wheel"]
+    #[name = "Mod Wheel"]
+    ModWheel,
+    #[id = "aftertouch"]
+    Aftertouch,
+}
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ModDestination {
+    #[id = "off"]
+    Off,
+    /// Up to an octave either way.
+    #[id = "pitch"]
+    Pitch,
+    /// Up to eight octaves either way.
+    #[id = "cutoff"]
+    Cutoff,
+    #[id = "resonance"]
+    Resonance,
+    #[id = "pulse_width"]
+    #[name = "Pulse Width"]
+    PulseWidth,
+    #[id = "level"]
+    Level,
+    #[id = "pan"]
+    Pan,
+    #[id = "amp_attack"]
+    #[name = "Amp Attack"]
+    AmpAttack,
+    #[id = "amp_decay"]
+    #[name = "Amp Decay"]
+    AmpDecay,
+    #[id = "amp_release"]
+    #[name = "Amp Release"]
+    AmpRelease,
+    #[id = "filter_attack"]
+    #[name = "Filter Attack"]
+    FilterAttack,
+    #[id = "filter_decay"]
+    #[name = "Filter Decay"]
+    FilterDecay,
+    #[id = "filter_release"]
+    #[name = "Filter Release"]
+    FilterRelease,
+}
+
+/// Every modulation source's value for one voice, at one sample.
+#[derive(Clone, Copy, Default)]
+pub struct ModSources {
+    pub amp_envelope: f32,
+    pub filter_envelope: f32,
+    pub lfos: [f32; NUM_LFOS],
+    pub velocity: f32,
+    pub key: f32,
+    pub mod_wheel: f32,
+    pub aftertouch: f32,
+}
+
+impl ModSources {
+    fn get(&self, source: ModSource) -> f32 {
+        match source {
+            ModSource::Off => 0.0,
+            ModSource::AmpEnvelope => self.amp_envelope,
+            ModSource::FilterEnvelope => self.filter_envelope,
+            ModSource::Lfo1 => self.lfos[0],
+            ModSource::Lfo2 => self.lfos[1],
+            ModSource::Velocity => self.velocity,
+            ModSource::Key => self.key,
+            ModSource::ModWheel => self.mod_wheel,
+            ModSource::Aftertouch => self.aftertouch,
+        }
+    }
+}
+
+/// How far the matrix moves every destination, in each destination's own units. Everything is
+/// added on top of the destination's own setting.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct ModTargets {
+    /// In semitones.
+    pub pitch: f32,
+    /// In octaves.
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub pulse_width: f32,
+    /// Added to a voice level of 1.
+    pub level: f32,
+    /// -1 (left) to 1 (right).
+    pub pan: f32,
+    /// Envelope stage lengths, in octaves of time (1 = twice as long).
+    pub amp_attack: f32,
+    pub amp_decay: f32,
+    pub amp_release: f32,
+    pub filter_attack: f32,
+    pub filter_decay: f32,
+    pub filter_release: f32,
+}
+
+/// One routing: `source` moves `destination` by `amount`, scaled by `via` unless that's off.
+#[derive(Clone, Copy)]
+struct ModSlot {
+    source: ModSource,
+    destination: ModDestination,
+    // -1..1, of the destination's full range
+    amount: f32,
+    via: ModSource,
+}
+
+impl ModSlot {
+    fn is_active(&self) -> bool {
+        self.source != ModSource::Off && self.destination != ModDestination::Off && self.amount != 0.0
+    }
+}
+
+/// A fixed number of routings from modulation sources to destinations. The synth keeps a single
+/// one, which every voice evaluates against its own sources, so nothing is ever allocated while
+/// playing.
+#[derive(Clone)]
+pub struct ModMatrix {
+    slots: [ModSlot; NUM_MOD_SLOTS],
+    // Whether any slot routes anything, so voices can skip the matrix entirely when none do
+    active: bool,
+}
+
+impl ModMatrix {
+    pub fn new() -> Self {
+        let empty = ModSlot {
+            source: ModSource::Off,
+            destination: ModDestination::Off,
+            amount: 0.0,
+            via: ModSource::Off,
+        };
+
+        Self {
+            slots: [empty; NUM_MOD_SLOTS],
+            active: false,
+        }
+    }
+
+    pub fn set_source(&mut self, index: usize, source: ModSource) {
+        if source != self.slots[index].source {
+            self.slots[index].source = source;
+            self.update_active();
+        }
+    }
+
+    pub fn set_destination(&mut self, index: usize, destination: ModDestination) {
+        if destination != self.slots[index].destination {
+            self.slots[index].destination = destination;
+            self.update_active();
+        }
+    }
+
+    /// Sets how far slot `index` moves its destination (-1..1 of the destination's range).
+    pub fn set_amount(&mut self, index: usize, amount: f32) {
+        if amount != self.slots[index].amount {
+            self.slots[index].amount = amount;
+            self.update_active();
+        }
+    }
+
+    /// Sets the source slot `index` is scaled by, e.g. the mod wheel to bring in vibrato. Off
+    /// leaves it unscaled.
+    pub fn set_via(&mut self, index: usize, via: ModSource) {
+        self.slots[index].via = via;
+    }
+
+    /// Whether any slot could move its destination. Slots scaled by the mod wheel or aftertouch
+    /// don't count while that controller is at zero, since controllers only move between blocks
+    /// and so can't bring them in part way through one.
+    pub fn is_active(&self, mod_wheel: f32, aftertouch: f32) -> bool {
+        if !self.active {
+            return false;
+        }
+
+        let is_zero = |source: ModSource| match source {
+            ModSource::ModWheel => mod_wheel == 0.0,
+            ModSource::Aftertouch => aftertouch == 0.0,
+            _ => false,
+        };
+        self.slots
+            .iter()
+            .any(|slot| slot.is_active() && !is_zero(slot.source) && !is_zero(slot.via))
+    }
+
+    /// Adds up every slot's contribution to each destination.
+    pub fn evaluate(&self, sources: &ModSources) -> ModTargets {
+        let mut targets = ModTargets::default();
+        if !self.active {
+            return targets;
+        }
+
+        for slot in self.slots.iter() {
+            let mut value = sources.get(slot.source) * slot.amount;
+            if slot.via != ModSource::Off {
+                value *= sources.get(slot.via);
+            }
+
+            match slot.destination {
+                ModDestination::Off => {}
+                ModDestination::Pitch => targets.pitch += value * PITCH_RANGE_ST,
+                ModDestination::Cutoff => targets.cutoff += value * CUTOFF_RANGE_OCTAVES,
+                ModDestination::Resonance => targets.resonance += value,
+                ModDestination::PulseWidth => targets.pulse_width += value * PULSE_WIDTH_RANGE,
+                ModDestination::Level => targets.level += value,
+                ModDestination::Pan => targets.pan += value,
+                ModDestination::AmpAttack => targets.amp_attack += value * ENVELOPE_TIME_RANGE_OCTAVES,
+                ModDestination::AmpDecay => targets.amp_decay += value * ENVELOPE_TIME_RANGE_OCTAVES,
+                ModDestination::AmpRelease => targets.amp_release += value * ENVELOPE_TIME_RANGE_OCTAVES,
+                ModDestination::FilterAttack => targets.filter_attack += value * ENVELOPE_TIME_RANGE_OCTAVES,
+                ModDestination::FilterDecay => targets.filter_decay += value * ENVELOPE_TIME_RANGE_OCTAVES,
+                ModDestination::FilterRelease => targets.filter_release += value * ENVELOPE_TIME_RANGE_OCTAVES,
+            }
+        }
+
+        targets
+    }
+
+    fn update_active(&mut self) {
+        self.active = self.slots.iter().any(ModSlot::is_active);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every source at full, so a slot moves its destination by its amount of the full range
+    const FULL: ModSources = ModSources {
+        amp_envelope: 1.0,
+        filter_envelope: 1.0,
+        lfos: [1.0; NUM_LFOS],
+        velocity: 1.0,
+        key: 1.0,
+        mod_wheel: 1.0,
+        aftertouch: 1.0,
+    };
+
+    fn matrix(slots: &[(ModSource, ModDestination, f32, ModSource)]) -> ModMatrix {
+        let mut matrix = ModMatrix::new();
+        for (index, &(source, destination, amount, via)) in slots.iter().enumerate() {
+            matrix.set_source(index, source);
+            matrix.set_destination(index, destination);
+            matrix.set_amount(index, amount);
+            matrix.set_via(index, via);
+        }
+        matrix
+    }
+
+    #[test]
+    fn via_scales_the_slot() {
+        let vibrato = matrix(&[(ModSource::Lfo1, ModDestination::Pitch, 0.5, ModSource::ModWheel)]);
+        let sources = |mod_wheel| ModSources { mod_wheel, ..FULL };
+
+        assert_eq!(vibrato.evaluate(&sources(0.0)).pitch, 0.0);
+        assert_eq!(vibrato.evaluate(&sources(0.5)).pitch, 3.0);
+        assert_eq!(vibrato.evaluate(&sources(1.0)).pitch, 6.0);
+
+        // Without a via, the wheel makes no difference
+        let unscaled = matrix(&[(ModSource::Lfo1, ModDestination::Pitch, 0.5, ModSource::Off)]);
+        assert_eq!(unscaled.evaluate(&sources(0.0)).pitch, 6.0);
+    }
+
+    #[test]
+    fn slots_on_the_same_destination_add_up() {
+        let matrix = matrix(&[
+            (ModSource::Lfo1, ModDestination::Cutoff, 0.25, ModSource::Off),
+            (ModSource::Velocity, ModDestination::Cutoff, 0.5, ModSource::Off),
+            (ModSource::Key, ModDestination::Cutoff, -0.125, ModSource::Off),
+            (ModSource::Lfo2, ModDestination::Pan, 1.0, ModSource::Off),
+        ]);
+        let sources = ModSources { lfos: [-1.0, 0.5], velocity: 0.5, key: 1.0, ..FULL };
+
+        let targets = matrix.evaluate(&sources);
+        assert_eq!(targets.cutoff, (-0.25 + 0.25 - 0.125) * CUTOFF_RANGE_OCTAVES);
+        assert_eq!(targets.pan, 0.5);
+        assert_eq!(targets.pitch, 0.0);
+    }
+
+    #[test]
+    fn controllers_at_zero_leave_their_slots_inactive() {
+        assert!(!ModMatrix::new().is_active(1.0, 1.0));
+        let no_amount = matrix(&[(ModSource::Lfo1, ModDestination::Pitch, 0.0, ModSource::Off)]);
+        assert!(!no_amount.is_active(1.0, 1.0));
+        let no_destination = matrix(&[(ModSource::Lfo1, ModDestination::Off, 1.0, ModSource::Off)]);
+        assert!(!no_destination.is_active(1.0, 1.0));
+
+        let wheel = matrix(&[(ModSource::ModWheel, ModDestination::Cutoff, 1.0, ModSource::Off)]);
+        assert!(!wheel.is_active(0.0, 1.0));
+        assert!(wheel.is_active(0.5, 0.0));
+
+        let pressure =
+            matrix(&[(ModSource::Lfo1, ModDestination::Cutoff, 1.0, ModSource::Aftertouch)]);
+        assert!(!pressure.is_active(1.0, 0.0));
+        assert!(pressure.is_active(0.0, 0.5));
+
+        // Any other slot keeps the matrix going
+        let mut both = pressure.clone();
+        both.set_source(1, ModSource::Lfo2);
+        both.set_destination(1, ModDestination::Level);
+        both.set_amount(1, 0.1);
+        assert!(both.is_active(0.0, 0.0));
+
+        // Emptying the last slot turns it off again
+        both.set_amount(0, 0.0);
+        both.set_amount(1, 0.0);
+        assert!(!both.is_active(1.0, 1.0));
+    }
+
+    #[test]
+    fn full_amounts_reach_each_destination_range() {
+        let full = |destination, amount| {
+            matrix(&[(ModSource::Velocity, destination, amount, ModSource::Off)]).evaluate(&FULL)
+        };
+
+        assert_eq!(full(ModDestination::Pitch, 1.0).pitch, 12.0);
+        assert_eq!(full(ModDestination::Pitch, -1.0).pitch, -12.0);
+        assert_eq!(full(ModDestination::Cutoff, 1.0).cutoff, 8.0);
+        assert_eq!(full(ModDestination::Cutoff, -1.0).cutoff, -8.0);
+        assert_eq!(full(ModDestination::PulseWidth, 1.0).pulse_width, 0.5);
+
+        // Envelope times scale up to 16 times longer or shorter
+        let slower = full(ModDestination::AmpAttack, 1.0);
+        assert_eq!(slower.amp_attack.exp2(), 16.0);
+        assert_eq!(slower.amp_decay, 0.0);
+        assert_eq!(full(ModDestination::FilterRelease, -1.0).filter_release.exp2(), 1.0 / 16.0);
+        let envelope_times = [
+            (ModDestination::AmpDecay, full(ModDestination::AmpDecay, 0.5).amp_decay),
+            (ModDestination::AmpRelease, full(ModDestination::AmpRelease, 0.5).amp_release),
+            (ModDestination::FilterAttack, full(ModDestination::FilterAttack, 0.5).filter_attack),
+            (ModDestination::FilterDecay, full(ModDestination::FilterDecay, 0.5).filter_decay),
+        ];
+        for (destination, octaves) in envelope_times {
+            assert_eq!(octaves.exp2(), 4.0, "{destination:?}");
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use nih_plug::prelude::Enum;
+
+use crate::random::Random;
+use crate::{stereo_sample::StereoSample, traits::AudioSource};
+
+// Brings the filtered colours back to roughly the same level as white noise.
+const PINK_GAIN: f32 = 0.11;
+const BROWN_GAIN: f32 = 3.5;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NoiseColour {
+    /// Equal energy per frequency.
+    #[id = "white"]
+    White,
+    /// Equal energy per octave, -3 dB per octave.
+    #[id = "pink"]
+    Pink,
+    /// -6 dB per octave.
+    #[id = "brown"]
+    Brown,
+}
+
+/// One channel of coloured noise: a random source and the filter state that colours it.
+///
+/// Both filters work per sample and know nothing of the sample rate, so their responses move
+/// with it. The pink filter's coefficients are tuned for 44.1 kHz, where it holds -3 dB per
+/// octave from about 10 Hz up. At higher rates the whole curve moves up in proportion, so the
+/// lowest octaves come out flatter (below about 20 Hz at 96 kHz). Brown noise's leak flattens it
+/// out below roughly `sample_rate / 320`, which is 150 Hz at 48 kHz and 300 Hz at 96 kHz.
+#[derive(Clone)]
+struct NoiseChannel {
+    random: Random,
+    // Paul Kellet's pink noise filter, a sum of one-pole lowpasses
+    pink: [f32; 7],
+    brown: f32,
+}
+
+impl NoiseChannel {
+    fn new(seed: u32) -> Self {
+        Self { random: Random::new(seed), pink: [0.0; 7], brown: 0.0 }
+    }
+
+    fn next(&mut self, colour: NoiseColour) -> f32 {
+        let white = self.random.next_bipolar();
+
+        match colour {
+            NoiseColour::White => white,
+            NoiseColour::Pink => {
+                let p = &mut self.pink;
+                p[0] = 0.99886 * p[0] + white * 0.0555179;
+                p[1] = 0.99332 * p[1] + white * 0.0750759;
+                p[2] = 0.96900 * p[2] + white * 0.153852;
+                p[3] = 0.86650 * p[3] + white * 0.3104856;
+                p[4] = 0.55000 * p[4] + white * 0.5329522;
+                p[5] = -0.7616 * p[5] - white * 0.0168980;
+                let pink = p[0] + p[1] + p[2] + p[3] + p[4] + p[5] + p[6] + white * 0.5362;
+                p[6] = white * 0.115926;
+
+                pink * PINK_GAIN
+            }
+            NoiseColour::Brown => {
+                // Integrated white noise, leaking slowly back to zero so it can't drift away
+                self.brown = (self.brown + 0.02 * white) / 1.02;
+
+                self.brown * BROWN_GAIN
+            }
+        }
+    }
+}
+
+/// White, pink or brown noise. Cheap, never allocates, and the same seed always gives the same
+/// noise.
+#[derive(Clone)]
+pub struct Noise {
+    seed: u32,
+    colour: NoiseColour,
+    // When set, the right channel gets its own noise instead of a copy of the left one
+    stereo: bool,
+    left: NoiseChannel,
+    right: NoiseChannel,
+}
+
+impl Noise {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            seed,
+            colour: NoiseColour::White,
+            stereo: false,
+            left: NoiseChannel::new(seed),
+            right: NoiseChannel::new(!seed),
+        }
+    }
+
+    pub fn set_colour(&mut self, colour: NoiseColour) {
+        self.colour = colour;
+    }
+
+    /// Gives the left and right channels their own, uncorrelated noise.
+    pub fn set_stereo(&mut self, stereo: bool) {
+        self.stereo = stereo;
+    }
+
+    /// Sets the seed and restarts the noise from it.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+        self.reset();
+    }
+}
+
+impl AudioSource for Noise {
+    fn next_sample(&mut self) -> StereoSample {
+        let left = self.left.next(self.colour);
+
+        if !self.stereo {
+            return StereoSample::from_mono(left);
+        }
+
+        StereoSample { left, right: self.right.next(self.colour) }
+    }
+
+    fn reset(&mut self) {
+        self.left = NoiseChannel::new(self.seed);
+        self.right = NoiseChannel::new(!self.seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fft::fft;
+
+    const COLOURS: [NoiseColour; 3] = [NoiseColour::White, NoiseColour::Pink, NoiseColour::Brown];
+
+    fn noise(seed: u32, colour: NoiseColour, stereo: bool) -> Noise {
+        let mut noise = Noise::new(seed);
+        noise.set_colour(colour);
+        noise.set_stereo(stereo);
+        noise
+    }
+
+    fn render(noise: &mut Noise, samples: usize) -> Vec<StereoSample> {
+        (0..samples).map(|_| noise.next_sample()).collect()
+    }
+
+    fn left(samples: &[StereoSample]) -> Vec<f32> {
+        samples.iter().map(|sample| sample.left).collect()
+    }
+
+    #[test]
+    fn the_seed_decides_the_noise() {
+        for colour in COLOURS {
+            let first = left(&render(&mut noise(5, colour, true), 1000));
+            assert_eq!(left(&render(&mut noise(5, colour, true), 1000)), first, "{colour:?}");
+            assert_ne!(left(&render(&mut noise(6, colour, true), 1000)), first, "{colour:?}");
+
+            // A reset starts over from the seed, filters and all
+            let mut reseeded = noise(6, colour, true);
+            reseeded.set_seed(5);
+            assert_eq!(left(&render(&mut reseeded, 1000)), first, "{colour:?}");
+            render(&mut reseeded, 123);
+            reseeded.reset();
+            assert_eq!(left(&render(&mut reseeded, 1000)), first, "{colour:?}");
+        }
+    }
+
+    #[test]
+    fn stereo_noise_is_uncorrelated_between_channels() {
+        for colour in COLOURS {
+            let mono = render(&mut noise(1, colour, false), 1000);
+            assert!(mono.iter().all(|sample| sample.left == sample.right), "{colour:?}");
+
+            let stereo = render(&mut noise(1, colour, true), 1 << 16);
+            let dot = |a: fn(&StereoSample) -> f32, b: fn(&StereoSample) -> f32| {
+                stereo.iter().map(|sample| (a(sample) * b(sample)) as f64).sum::<f64>()
+            };
+            let correlation = dot(|s| s.left, |s| s.right)
+                / (dot(|s| s.left, |s| s.left) * dot(|s| s.right, |s| s.right)).sqrt();
+            assert!(correlation.abs() < 0.1, "{colour:?}: {correlation}");
+        }
+    }
+
+    #[test]
+    fn pink_and_brown_fall_off_towards_the_top() {
+        const FFT_SIZE: usize = 4096;
+        const SAMPLE_RATE: f64 = 48000.0;
+
+        // How much louder 200-400 Hz is than 6.4-12.8 kHz, per Hz, averaged over many blocks
+        let tilt_db = |colour: NoiseColour| {
+            let mut noise = noise(1, colour, false);
+            let mut power = vec![0.0; FFT_SIZE / 2];
+            for _ in 0..32 {
+                let block = render(&mut noise, FFT_SIZE);
+                let mut re: Vec<f64> = block.iter().map(|sample| sample.left as f64).collect();
+                let mut im = vec![0.0; FFT_SIZE];
+                fft(&mut re, &mut im);
+                for (bin, power) in power.iter_mut().enumerate() {
+                    *power += re[bin] * re[bin] + im[bin] * im[bin];
+                }
+            }
+
+            let band = |low_hz: f64| {
+                let bin = |hz: f64| (hz / SAMPLE_RATE * FFT_SIZE as f64) as usize;
+                let bins = &power[bin(low_hz)..bin(2.0 * low_hz)];
+                bins.iter().sum::<f64>() / bins.len() as f64
+            };
+            10.0 * (band(200.0) / band(6400.0)).log10()
+        };
+
+        // Five octaves apart: flat for white, -3 dB per octave for pink and -6 for brown
+        let white = tilt_db(NoiseColour::White);
+        let pink = tilt_db(NoiseColour::Pink);
+        let brown = tilt_db(NoiseColour::Brown);
+        assert!(white.abs() < 1.5, "white {white} dB");
+        assert!((10.0..20.0).contains(&pink), "pink {pink} dB");
+        assert!(brown > 25.0, "brown {brown} dB");
+    }
+}
@@ -0,0 +1,151 @@
+use nih_plug::prelude::Enum;
+
+use crate::voice::NoteId;
+
+/// How many held notes are remembered in mono and legato modes. When more keys are held, the
+/// oldest one is forgotten.
+pub const NOTE_STACK_SIZE: usize = 16;
+
+/// Which of the held notes sounds in mono and legato modes.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NotePriority {
+    /// The most recently pressed note.
+    #[id = "last"]
+    Last,
+    #[id = "low"]
+    #[name = "Lowest"]
+    Low,
+    #[id = "high"]
+    #[name = "Highest"]
+    High,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HeldNote {
+    pub note_id: NoteId,
+    pub velocity: f32,
+}
+
+/// The keys currently held down, oldest first. Fixed size so it never allocates.
+#[derive(Clone)]
+pub struct NoteStack {
+    notes: [HeldNote; NOTE_STACK_SIZE],
+    len: usize,
+}
+
+impl NoteStack {
+    pub fn new() -> Self {
+        let empty = HeldNote {
+            note_id: NoteId { note: 0, channel: 0, voice_id: None },
+            velocity: 0.0,
+        };
+
+        Self {
+            notes: [empty; NOTE_STACK_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, held_note: HeldNote) {
+        // A note is only ever held once, pressing it again moves it to the top
+        self.remove(&held_note.note_id);
+
+        if self.len == NOTE_STACK_SIZE {
+            // Forget the oldest note to make room
+            self.notes.copy_within(1.., 0);
+            self.len -= 1;
+        }
+
+        self.notes[self.len] = held_note;
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, note_id: &NoteId) {
+        if let Some(index) = self.held().iter().position(|n| n.note_id.matches(note_id)) {
+            self.notes.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn held(&self) -> &[HeldNote] {
+        &self.notes[..self.len]
+    }
+
+    /// The held note that should be sounding, if any key is held at all.
+    pub fn select(&self, priority: NotePriority) -> Option<HeldNote> {
+        let held = self.held().iter();
+
+        match priority {
+            NotePriority::Last => held.last(),
+            NotePriority::Low => held.min_by_key(|n| n.note_id.note),
+            NotePriority::High => held.max_by_key(|n| n.note_id.note),
+        }
+        .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(note: u8) -> HeldNote {
+        HeldNote {
+            note_id: NoteId { note, channel: 0, voice_id: None },
+            velocity: 1.0,
+        }
+    }
+
+    fn notes(stack: &NoteStack) -> Vec<u8> {
+        stack.held().iter().map(|n| n.note_id.note).collect()
+    }
+
+    fn stack(notes: &[u8]) -> NoteStack {
+        let mut stack = NoteStack::new();
+        notes.iter().for_each(|&note| stack.push(held(note)));
+        stack
+    }
+
+    #[test]
+    fn selects_by_priority() {
+        let stack = stack(&[60, 67, 55, 64]);
+
+        assert_eq!(stack.select(NotePriority::Last), Some(held(64)));
+        assert_eq!(stack.select(NotePriority::Low), Some(held(55)));
+        assert_eq!(stack.select(NotePriority::High), Some(held(67)));
+        assert_eq!(NoteStack::new().select(NotePriority::Last), None);
+    }
+
+    #[test]
+    fn removing_the_last_note_returns_to_the_previous_one() {
+        let mut stack = stack(&[60, 64, 67, 69]);
+
+        stack.remove(&held(69).note_id);
+        assert_eq!(stack.select(NotePriority::Last), Some(held(67)));
+
+        // Removing from the middle keeps the order of the rest
+        stack.remove(&held(64).note_id);
+        assert_eq!(notes(&stack), vec![60, 67]);
+    }
+
+    #[test]
+    fn pressing_a_held_note_again_moves_it_to_the_top() {
+        let mut stack = stack(&[60, 64, 67]);
+        stack.push(held(60));
+
+        assert_eq!(notes(&stack), vec![64, 67, 60]);
+    }
+
+    #[test]
+    fn forgets_the_oldest_note_when_full() {
+        let mut stack = stack(&(40..40 + NOTE_STACK_SIZE as u8).collect::<Vec<_>>());
+        stack.push(held(100));
+
+        assert_eq!(stack.held().len(), NOTE_STACK_SIZE);
+        assert_eq!(stack.held()[0], held(41));
+        assert_eq!(stack.select(NotePriority::Last), Some(held(100)));
+    }
+}
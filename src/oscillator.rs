@@ -0,0 +1,274 @@
+use std::sync::Arc;
+
+use nih_plug::prelude::Enum;
+
+use crate::noise::{Noise, NoiseColour};
+use crate::poly_blep::OscillatorQuality;
+use crate::saw_wave::SawWave;
+use crate::sine_wave::SineWave;
+use crate::square_wave::SquareWave;
+use crate::stereo_sample::StereoSample;
+use crate::traits::AudioSource;
+use crate::triangle_wave::TriangleWave;
+use crate::wavetable::{Wavetable, WavetableOscillator};
+
+// How long switching waveforms fades from the old one to the new one.
+const CROSSFADE_S: f32 = 0.005;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Waveform {
+    #[id = "sine"]
+    Sine,
+    #[id = "saw"]
+    Saw,
+    #[id = "square"]
+    Square,
+    #[id = "triangle"]
+    Triangle,
+    #[id = "noise"]
+    Noise,
+    #[id = "wavetable"]
+    Wavetable,
+}
+
+// Every waveform, in declaration order so a waveform's `as usize` indexes into it.
+const WAVEFORMS: [Waveform; 6] = [
+    Waveform::Sine,
+    Waveform::Saw,
+    Waveform::Square,
+    Waveform::Triangle,
+    Waveform::Noise,
+    Waveform::Wavetable,
+];
+
+/// An oscillator that can play any [`Waveform`]. Every waveform is kept around, so switching
+/// between them never allocates, and a switch crossfades from the old waveform to avoid clicks.
+#[derive(Clone)]
+pub struct Oscillator {
+    sine: SineWave,
+    saw: SawWave,
+    square: SquareWave,
+    triangle: TriangleWave,
+    noise: Noise,
+    wavetable: WavetableOscillator,
+
+    waveform: Waveform,
+    // How much of each waveform (indexed like `WAVEFORMS`) was in the mix when the last switch
+    // happened, faded out over the next `crossfade_remaining` samples. A switch partway through
+    // a fade carries on from the mix as it was, however many waveforms were in it.
+    fade_from: [f32; WAVEFORMS.len()],
+    crossfade_remaining: u32,
+    crossfade_len: u32,
+
+    pub frequency: f32,
+}
+
+impl Oscillator {
+    pub fn new(sample_rate: u32, freq: f32) -> Self {
+        Self {
+            sine: SineWave::new(sample_rate, freq),
+            saw: SawWave::new(sample_rate, freq),
+            square: SquareWave::new(sample_rate, freq),
+            triangle: TriangleWave::new(sample_rate, freq),
+            noise: Noise::new(1),
+            wavetable: WavetableOscillator::new(sample_rate, freq, Wavetable::basic_shapes()),
+            waveform: Waveform::Saw,
+            fade_from: [0.0; WAVEFORMS.len()],
+            crossfade_remaining: 0,
+            crossfade_len: ((sample_rate as f32 * CROSSFADE_S) as u32).max(1),
+            frequency: freq,
+        }
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        if waveform == self.waveform {
+            return;
+        }
+
+        self.fade_from = self.mix_weights();
+        self.waveform = waveform;
+        self.crossfade_remaining = self.crossfade_len;
+    }
+
+    /// How much of each waveform (indexed like `WAVEFORMS`) goes into the next sample.
+    fn mix_weights(&self) -> [f32; WAVEFORMS.len()] {
+        let mut weights = [0.0; WAVEFORMS.len()];
+        if self.crossfade_remaining == 0 {
+            weights[self.waveform as usize] = 1.0;
+            return weights;
+        }
+
+        let old_amount = self.crossfade_remaining as f32 / self.crossfade_len as f32;
+        for (weight, from) in weights.iter_mut().zip(self.fade_from.iter()) {
+            *weight = from * old_amount;
+        }
+        weights[self.waveform as usize] += 1.0 - old_amount;
+        weights
+    }
+
+    /// Only affects the square wave. See [`SquareWave::set_pulse_width()`].
+    pub fn set_pulse_width(&mut self, pulse_width: f32) {
+        self.square.set_pulse_width(pulse_width);
+    }
+
+    /// Only affects the wavetable. See [`WavetableOscillator::set_position()`].
+    pub fn set_wavetable_position(&mut self, position: f32) {
+        self.wavetable.set_position(position);
+    }
+
+    pub fn set_wavetable(&mut self, wavetable: Arc<Wavetable>) {
+        self.wavetable.set_wavetable(wavetable);
+    }
+
+    pub fn set_noise_colour(&mut self, colour: NoiseColour) {
+        self.noise.set_colour(colour);
+    }
+
+    pub fn set_noise_stereo(&mut self, stereo: bool) {
+        self.noise.set_stereo(stereo);
+    }
+
+    /// Seeds the noise waveform. See [`Noise::set_seed()`].
+    pub fn set_seed(&mut self, seed: u32) {
+        self.noise.set_seed(seed);
+    }
+
+    pub fn set_quality(&mut self, quality: OscillatorQuality) {
+        self.saw.set_quality(quality);
+        self.square.set_quality(quality);
+        self.triangle.set_quality(quality);
+    }
+
+    fn source(&mut self, waveform: Waveform) -> &mut dyn AudioSource {
+        match waveform {
+            Waveform::Sine => &mut self.sine,
+            Waveform::Saw => &mut self.saw,
+            Waveform::Square => &mut self.square,
+            Waveform::Triangle => &mut self.triangle,
+            Waveform::Noise => &mut self.noise,
+            Waveform::Wavetable => &mut self.wavetable,
+        }
+    }
+}
+
+impl AudioSource for Oscillator {
+    fn next_sample(&mut self) -> StereoSample {
+        if self.crossfade_remaining == 0 {
+            return self.source(self.waveform).next_sample();
+        }
+
+        let weights = self.mix_weights();
+        self.crossfade_remaining -= 1;
+
+        // Waveforms that have faded out completely stay paused, like any other unused one
+        let mut mix = StereoSample::from_mono(0.0);
+        for (&waveform, &weight) in WAVEFORMS.iter().zip(weights.iter()) {
+            if weight > 0.0 {
+                let sample = self.source(waveform).next_sample();
+                mix.left += sample.left * weight;
+                mix.right += sample.right * weight;
+            }
+        }
+
+        mix
+    }
+
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        if self.crossfade_remaining > 0 {
+            for sample in block.iter_mut() {
+                *sample = self.next_sample();
+            }
+            return;
+        }
+
+        self.source(self.waveform).fill_block(block);
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        self.frequency = freq;
+
+        // All of them, so a waveform picks up at the right pitch when switched to
+        self.sine.set_frequency(freq);
+        self.saw.set_frequency(freq);
+        self.square.set_frequency(freq);
+        self.triangle.set_frequency(freq);
+        self.wavetable.set_frequency(freq);
+    }
+
+    fn set_phase(&mut self, phase: f32) {
+        self.sine.set_phase(phase);
+        self.saw.set_phase(phase);
+        self.square.set_phase(phase);
+        self.triangle.set_phase(phase);
+        self.wavetable.set_phase(phase);
+    }
+
+    fn sync(&mut self, fraction: f32) {
+        if self.crossfade_remaining == 0 {
+            self.source(self.waveform).sync(fraction);
+            return;
+        }
+
+        // Everything still in the crossfade
+        let weights = self.mix_weights();
+        for (&waveform, &weight) in WAVEFORMS.iter().zip(weights.iter()) {
+            if weight > 0.0 {
+                self.source(waveform).sync(fraction);
+            }
+        }
+    }
+
+    fn next_wrap(&self) -> Option<f32> {
+        match self.waveform {
+            Waveform::Sine => self.sine.next_wrap(),
+            Waveform::Saw => self.saw.next_wrap(),
+            Waveform::Square => self.square.next_wrap(),
+            Waveform::Triangle => self.triangle.next_wrap(),
+            Waveform::Noise => self.noise.next_wrap(),
+            Waveform::Wavetable => self.wavetable.next_wrap(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sine.reset();
+        self.saw.reset();
+        self.square.reset();
+        self.triangle.reset();
+        self.noise.reset();
+        self.wavetable.reset();
+        self.crossfade_remaining = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn render(osc: &mut Oscillator, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| osc.next_sample().left).collect()
+    }
+
+    #[test]
+    fn switching_during_a_crossfade_carries_on_from_the_mix() {
+        // Slow enough that the waveforms themselves barely move from one sample to the next
+        let mut osc = Oscillator::new(SAMPLE_RATE, 1.0);
+        let fade_len = osc.crossfade_len as usize;
+        osc.set_waveform(Waveform::Sine);
+        render(&mut osc, 2 * fade_len);
+
+        // A quarter of the way through the cycle the sine is at its peak and the triangle at zero
+        osc.set_phase(0.25);
+        osc.set_waveform(Waveform::Triangle);
+        let mut output = render(&mut osc, fade_len / 2);
+        osc.set_waveform(Waveform::Sine);
+        output.extend(render(&mut osc, fade_len / 2));
+        osc.set_waveform(Waveform::Square);
+        output.extend(render(&mut osc, 2 * fade_len));
+
+        let max_step = output.windows(2).map(|pair| (pair[1] - pair[0]).abs()).fold(0.0, f32::max);
+        assert!(max_step < 2.0 / fade_len as f32, "{max_step}");
+        assert_eq!(osc.mix_weights()[Waveform::Square as usize], 1.0);
+    }
+}
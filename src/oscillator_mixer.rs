@@ -0,0 +1,379 @@
+use std::sync::Arc;
+
+use nih_plug::prelude::Enum;
+
+use crate::noise::{Noise, NoiseColour};
+use crate::oscillator::{Oscillator, Waveform};
+use crate::poly_blep::OscillatorQuality;
+use crate::stereo_sample::StereoSample;
+use crate::traits::AudioSource;
+use crate::unison::Unison;
+use crate::wavetable::Wavetable;
+
+/// How many oscillators every voice has, not counting the sub-oscillator.
+pub const NUM_OSCILLATORS: usize = 3;
+
+// Oscillators render into this scratch buffer before being mixed, longer blocks are split up.
+const SCRATCH_SIZE: usize = 64;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SubOctave {
+    #[id = "one"]
+    #[name = "-1 Octave"]
+    One,
+    #[id = "two"]
+    #[name = "-2 Octaves"]
+    Two,
+}
+
+/// One oscillator (or unison stack) and where it sits in the mix.
+#[derive(Clone)]
+struct MixerChannel<S> {
+    osc: S,
+    octave: i32,
+    semitone: i32,
+    fine_cents: f32,
+    level: f32,
+    pan: f32,
+    // Hard synced to the master oscillator
+    sync: bool,
+    // How much of the output is ring modulated by the master oscillator (0..1)
+    ring: f32,
+
+    // Worked out whenever the settings change, rather than every sample
+    ratio: f32,
+    left_gain: f32,
+    right_gain: f32,
+}
+
+impl<S: AudioSource> MixerChannel<S> {
+    fn new(osc: S, level: f32) -> Self {
+        let mut channel = Self {
+            osc,
+            octave: 0,
+            semitone: 0,
+            fine_cents: 0.0,
+            level,
+            pan: 0.0,
+            sync: false,
+            ring: 0.0,
+            ratio: 1.0,
+            left_gain: 0.0,
+            right_gain: 0.0,
+        };
+        channel.update_gains();
+        channel
+    }
+
+    fn update_ratio(&mut self, base_frequency: f32) {
+        let semitones = (self.octave * 12 + self.semitone) as f32 + self.fine_cents / 100.0;
+        self.ratio = (semitones / 12.0).exp2();
+        self.osc.set_frequency(base_frequency * self.ratio);
+    }
+
+    fn update_gains(&mut self) {
+        // Balance rather than equal-power panning, so a centred oscillator plays at its full level
+        self.left_gain = self.level * (1.0 - self.pan).min(1.0);
+        self.right_gain = self.level * (1.0 + self.pan).min(1.0);
+    }
+
+    /// Adds the channel's next sample to `mix`.
+    fn mix_sample(&mut self, mix: &mut StereoSample) {
+        if self.level <= 0.0 {
+            return;
+        }
+
+        let sample = self.osc.next_sample();
+        self.add_to(mix, sample);
+    }
+
+    /// Adds `sample`, rendered by this channel, to `mix` at the channel's level and pan.
+    fn add_to(&self, mix: &mut StereoSample, sample: StereoSample) {
+        mix.left += sample.left * self.left_gain;
+        mix.right += sample.right * self.right_gain;
+    }
+
+    /// Adds the channel's next `block.len()` samples to `block`, rendering them into `scratch`
+    /// first.
+    fn mix_block(&mut self, block: &mut [StereoSample], scratch: &mut [StereoSample]) {
+        if self.level <= 0.0 {
+            return;
+        }
+
+        self.osc.fill_block(scratch);
+
+        for (out, sample) in block.iter_mut().zip(scratch.iter()) {
+            out.left += sample.left * self.left_gain;
+            out.right += sample.right * self.right_gain;
+        }
+    }
+}
+
+/// Sums several oscillators, each with its own pitch offset, level and pan, plus a
+/// sub-oscillator one or two octaves below them and a noise source. Unison applies to every
+/// oscillator but the sub-oscillator.
+///
+/// The first oscillator is the master the others can be hard synced to or ring modulated by.
+#[derive(Clone)]
+pub struct OscillatorMixer {
+    channels: [MixerChannel<Unison>; NUM_OSCILLATORS],
+    sub: MixerChannel<Oscillator>,
+    noise: MixerChannel<Noise>,
+    scratch: [StereoSample; SCRATCH_SIZE],
+
+    pub frequency: f32,
+}
+
+impl OscillatorMixer {
+    pub fn new(sample_rate: u32, freq: f32) -> Self {
+        // Only the first oscillator is heard by default, which sounds like a single oscillator
+        let channels = std::array::from_fn(|index| {
+            MixerChannel::new(Unison::new(sample_rate, freq), if index == 0 { 1.0 } else { 0.0 })
+        });
+
+        let mut sub = MixerChannel::new(Oscillator::new(sample_rate, freq), 0.0);
+        sub.osc.set_waveform(Waveform::Sine);
+        sub.octave = -1;
+        sub.update_ratio(freq);
+
+        Self {
+            channels,
+            sub,
+            noise: MixerChannel::new(Noise::new(1), 0.0),
+            scratch: [StereoSample::from_mono(0.0); SCRATCH_SIZE],
+            frequency: freq,
+        }
+    }
+
+    pub fn set_waveform(&mut self, index: usize, waveform: Waveform) {
+        self.channels[index].osc.set_waveform(waveform);
+    }
+
+    pub fn set_octave(&mut self, index: usize, octave: i32) {
+        let channel = &mut self.channels[index];
+        if channel.octave != octave {
+            channel.octave = octave;
+            channel.update_ratio(self.frequency);
+        }
+    }
+
+    pub fn set_semitone(&mut self, index: usize, semitone: i32) {
+        let channel = &mut self.channels[index];
+        if channel.semitone != semitone {
+            channel.semitone = semitone;
+            channel.update_ratio(self.frequency);
+        }
+    }
+
+    /// Sets the fine detune in cents.
+    pub fn set_fine(&mut self, index: usize, fine_cents: f32) {
+        let channel = &mut self.channels[index];
+        if channel.fine_cents != fine_cents {
+            channel.fine_cents = fine_cents;
+            channel.update_ratio(self.frequency);
+        }
+    }
+
+    pub fn set_level(&mut self, index: usize, level: f32) {
+        self.channels[index].level = level;
+        self.channels[index].update_gains();
+    }
+
+    /// Sets the pan (-1 = left, 0 = centre, 1 = right).
+    pub fn set_pan(&mut self, index: usize, pan: f32) {
+        self.channels[index].pan = pan.clamp(-1.0, 1.0);
+        self.channels[index].update_gains();
+    }
+
+    /// Hard syncs oscillator `index` to the first oscillator. Does nothing for the first
+    /// oscillator itself.
+    pub fn set_sync(&mut self, index: usize, sync: bool) {
+        self.channels[index].sync = sync;
+    }
+
+    /// Sets how much of oscillator `index` is ring modulated by the first oscillator (0 = none,
+    /// 1 = fully). Does nothing for the first oscillator itself.
+    pub fn set_ring(&mut self, index: usize, amount: f32) {
+        self.channels[index].ring = amount.clamp(0.0, 1.0);
+    }
+
+    /// Returns `true` when an oscillator that can be heard is synced to or ring modulated by the
+    /// master, which means rendering sample by sample.
+    fn is_cross_modulating(&self) -> bool {
+        self.channels[1..].iter().any(|channel| channel.level > 0.0 && (channel.sync || channel.ring > 0.0))
+    }
+
+    /// Renders the oscillators one sample at a time, passing the master's sync and output on to
+    /// the oscillators following it.
+    fn next_cross_modulated(&mut self, mix: &mut StereoSample) {
+        let (master, slaves) = self.channels.split_first_mut().expect("At least one oscillator");
+
+        // Rendered even when it can't be heard itself, since the others follow it
+        let master_sample = master.osc.next_sample();
+        if master.level > 0.0 {
+            master.add_to(mix, master_sample);
+        }
+
+        for slave in slaves.iter_mut().filter(|slave| slave.level > 0.0) {
+            if slave.sync {
+                slave.osc.sync_to(&master.osc);
+            }
+
+            let mut sample = slave.osc.next_sample();
+            sample.left *= 1.0 - slave.ring + slave.ring * master_sample.left;
+            sample.right *= 1.0 - slave.ring + slave.ring * master_sample.right;
+            slave.add_to(mix, sample);
+        }
+    }
+
+    pub fn set_sub_waveform(&mut self, waveform: Waveform) {
+        self.sub.osc.set_waveform(waveform);
+    }
+
+    pub fn set_sub_octave(&mut self, octave: SubOctave) {
+        let octave = match octave {
+            SubOctave::One => -1,
+            SubOctave::Two => -2,
+        };
+
+        if self.sub.octave != octave {
+            self.sub.octave = octave;
+            self.sub.update_ratio(self.frequency);
+        }
+    }
+
+    pub fn set_sub_level(&mut self, level: f32) {
+        self.sub.level = level;
+        self.sub.update_gains();
+    }
+
+    pub fn set_unison(&mut self, count: usize) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_count(count));
+    }
+
+    /// See [`Unison::set_detune()`].
+    pub fn set_unison_detune(&mut self, detune_cents: f32) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_detune(detune_cents));
+    }
+
+    /// See [`Unison::set_detune_curve()`].
+    pub fn set_unison_detune_curve(&mut self, amount: f32) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_detune_curve(amount));
+    }
+
+    /// See [`Unison::set_spread()`].
+    pub fn set_unison_spread(&mut self, spread: f32) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_spread(spread));
+    }
+
+    /// See [`Unison::set_blend()`].
+    pub fn set_unison_blend(&mut self, blend: f32) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_blend(blend));
+    }
+
+    pub fn set_noise_level(&mut self, level: f32) {
+        self.noise.level = level;
+        self.noise.update_gains();
+    }
+
+    /// Sets the colour of both the noise source and the noise waveform.
+    pub fn set_noise_colour(&mut self, colour: NoiseColour) {
+        self.noise.osc.set_colour(colour);
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_noise_colour(colour));
+        self.sub.osc.set_noise_colour(colour);
+    }
+
+    pub fn set_noise_stereo(&mut self, stereo: bool) {
+        self.noise.osc.set_stereo(stereo);
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_noise_stereo(stereo));
+        self.sub.osc.set_noise_stereo(stereo);
+    }
+
+    /// Gives the unison copies new start phases for a new note. See [`Unison::trigger()`].
+    pub fn trigger(&mut self) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.trigger());
+    }
+
+    /// Seeds the unison start phases and all noise. Every source gets its own seed derived from
+    /// `seed`.
+    pub fn set_seed(&mut self, seed: u32) {
+        let seed = seed.wrapping_mul(NUM_OSCILLATORS as u32 + 2);
+        for (index, channel) in self.channels.iter_mut().enumerate() {
+            channel.osc.set_seed(seed.wrapping_add(index as u32));
+        }
+        self.sub.osc.set_seed(seed.wrapping_add(NUM_OSCILLATORS as u32));
+        self.noise.osc.set_seed(seed.wrapping_add(NUM_OSCILLATORS as u32 + 1));
+    }
+
+    /// Only affects square waves. See [`Oscillator::set_pulse_width()`].
+    pub fn set_pulse_width(&mut self, pulse_width: f32) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_pulse_width(pulse_width));
+        self.sub.osc.set_pulse_width(pulse_width);
+    }
+
+    /// Only affects wavetables. See [`Oscillator::set_wavetable_position()`].
+    pub fn set_wavetable_position(&mut self, position: f32) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_wavetable_position(position));
+        self.sub.osc.set_wavetable_position(position);
+    }
+
+    pub fn set_wavetable(&mut self, wavetable: Arc<Wavetable>) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_wavetable(wavetable.clone()));
+        self.sub.osc.set_wavetable(wavetable);
+    }
+
+    pub fn set_quality(&mut self, quality: OscillatorQuality) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.set_quality(quality));
+        self.sub.osc.set_quality(quality);
+    }
+}
+
+impl AudioSource for OscillatorMixer {
+    fn next_sample(&mut self) -> StereoSample {
+        let mut mix = StereoSample::from_mono(0.0);
+
+        if self.is_cross_modulating() {
+            self.next_cross_modulated(&mut mix);
+        } else {
+            self.channels.iter_mut().for_each(|channel| channel.mix_sample(&mut mix));
+        }
+        self.sub.mix_sample(&mut mix);
+        self.noise.mix_sample(&mut mix);
+
+        mix
+    }
+
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        if self.is_cross_modulating() {
+            for sample in block.iter_mut() {
+                *sample = self.next_sample();
+            }
+            return;
+        }
+
+        block.fill(StereoSample::from_mono(0.0));
+
+        for chunk in block.chunks_mut(SCRATCH_SIZE) {
+            let scratch = &mut self.scratch[..chunk.len()];
+
+            self.channels.iter_mut().for_each(|channel| channel.mix_block(chunk, scratch));
+            self.sub.mix_block(chunk, scratch);
+            self.noise.mix_block(chunk, scratch);
+        }
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        self.frequency = freq;
+
+        for channel in self.channels.iter_mut() {
+            channel.osc.set_frequency(freq * channel.ratio);
+        }
+        self.sub.osc.set_frequency(freq * self.sub.ratio);
+    }
+
+    fn reset(&mut self) {
+        self.channels.iter_mut().for_each(|channel| channel.osc.reset());
+        self.sub.osc.reset();
+        self.noise.osc.reset();
+    }
+}
@@ -0,0 +1,522 @@
+//! Polynomial band-limited steps (PolyBLEP) and ramps (PolyBLAMP).
+//!
+//! A naive oscillator jumps (or changes slope) instantly, which puts energy above Nyquist that
+//! folds back down as aliasing. Adding a short polynomial residual around every discontinuity
+//! smooths it into a band-limited transition. The residuals here are the integrated B-spline
+//! kernels: linear over two samples, or cubic over four samples for a steeper cutoff.
+
+use nih_plug::prelude::Enum;
+
+/// How much effort the oscillators put into suppressing aliasing.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OscillatorQuality {
+    /// Naive waveforms, which alias badly at high pitches. Cheapest.
+    #[id = "draft"]
+    Draft,
+    /// Two-sample PolyBLEP.
+    #[id = "standard"]
+    Standard,
+    /// Four-sample PolyBLEP based on a cubic B-spline, for noticeably less aliasing.
+    #[id = "high"]
+    High,
+}
+
+/// Residual for a unit step, `x` samples after it (negative before it).
+fn step_residual(x: f32, quality: OscillatorQuality) -> f32 {
+    match quality {
+        OscillatorQuality::Draft => 0.0,
+        OscillatorQuality::Standard => {
+            if x <= -1.0 || x >= 1.0 {
+                0.0
+            } else if x < 0.0 {
+                0.5 * (1.0 + x) * (1.0 + x)
+            } else {
+                -0.5 * (1.0 - x) * (1.0 - x)
+            }
+        }
+        OscillatorQuality::High => {
+            if x <= -2.0 || x >= 2.0 {
+                return 0.0;
+            }
+
+            // The residual is odd, so only the integral of the kernel up to -|x| is needed
+            let y = -x.abs();
+            let integral = if y < -1.0 {
+                (2.0 + y).powi(4) / 24.0
+            } else {
+                0.5 + (4.0 * y - 2.0 * y.powi(3) - 0.75 * y.powi(4)) / 6.0
+            };
+
+            if x < 0.0 {
+                integral
+            } else {
+                -integral
+            }
+        }
+    }
+}
+
+/// Residual for a unit change in slope (per sample), `x` samples after it (negative before it).
+fn ramp_residual(x: f32, quality: OscillatorQuality) -> f32 {
+    match quality {
+        OscillatorQuality::Draft => 0.0,
+        OscillatorQuality::Standard => {
+            if x <= -1.0 || x >= 1.0 {
+                0.0
+            } else {
+                (1.0 - x.abs()).powi(3) / 6.0
+            }
+        }
+        OscillatorQuality::High => {
+            if x <= -2.0 || x >= 2.0 {
+                return 0.0;
+            }
+
+            // Even, so work with -|x| like the step residual does
+            let y = -x.abs();
+            if y < -1.0 {
+                (2.0 + y).powi(5) / 120.0
+            } else {
+                0.5 * (y + 1.0)
+                    + (2.0 * y * y - 0.5 * y.powi(4) - 0.15 * y.powi(5) - 1.65) / 6.0
+                    + 1.0 / 120.0
+            }
+        }
+    }
+}
+
+/// Correction to add to a naive waveform for a step of height 1 at phase 0. Multiply by the
+/// actual step height.
+///
+/// `phase` is the oscillator's phase relative to the discontinuity, in 0..1, and `increment` is
+/// how far the phase advances per sample (at most 0.5, i.e. Nyquist).
+pub fn blep(phase: f32, increment: f32, quality: OscillatorQuality) -> f32 {
+    if increment <= 0.0 {
+        return 0.0;
+    }
+
+    // Up to Nyquist only the previous and the next discontinuity can be close enough to matter
+    step_residual(phase / increment, quality) + step_residual((phase - 1.0) / increment, quality)
+}
+
+/// Correction to add to a naive waveform for a change in slope of 1 per sample at phase 0.
+/// Multiply by the actual change in slope per sample. Arguments as for [`blep()`].
+pub fn blamp(phase: f32, increment: f32, quality: OscillatorQuality) -> f32 {
+    if increment <= 0.0 {
+        return 0.0;
+    }
+
+    ramp_residual(phase / increment, quality) + ramp_residual((phase - 1.0) / increment, quality)
+}
+
+/// Wraps a phase offset back into 0..1.
+pub fn wrap_phase(phase: f32) -> f32 {
+    phase - phase.floor()
+}
+
+/// Moves `phase` on by `increment`, wrapping back into 0..1.
+pub fn advance_phase(phase: &mut f32, increment: f32) {
+    *phase += increment;
+    if *phase >= 1.0 {
+        *phase -= 1.0;
+    }
+}
+
+/// If the phase wraps between the next sample (at `phase`) and the one after it, how long after
+/// the next sample it does, in samples (0..1]. This is what a master passes on to the
+/// oscillators synced to it, a sample ahead so their residuals can start early enough.
+pub fn next_wrap(phase: f32, increment: f32) -> Option<f32> {
+    if increment > 0.0 && phase + increment >= 1.0 {
+        Some((1.0 - phase) / increment)
+    } else {
+        None
+    }
+}
+
+/// Somewhere in its cycle a waveform jumps, or changes slope, and that an oscillator smooths
+/// over with [`blep()`] or [`blamp()`].
+#[derive(Clone, Copy)]
+pub struct Discontinuity {
+    /// Where in the cycle it is (0..1).
+    pub phase: f32,
+    /// How far the level jumps.
+    pub step: f32,
+    /// How far the slope changes, per cycle.
+    pub bend: f32,
+}
+
+impl Discontinuity {
+    /// The oscillator's correction for this discontinuity, `x` samples after it.
+    fn residual(&self, x: f32, increment: f32, quality: OscillatorQuality) -> f32 {
+        if increment <= 0.0 {
+            return 0.0;
+        }
+
+        self.step * step_residual(x, quality) + self.bend * increment * ramp_residual(x, quality)
+    }
+}
+
+/// A hard sync restart: how far it jumps, and when.
+#[derive(Clone, Copy)]
+struct Restart {
+    // How long after the sample being rendered it happens, in samples. Negative once it's passed.
+    offset: f32,
+    step: f32,
+    // How far the slope changes, per sample
+    bend: f32,
+    // How far the phase has moved on since the restart, not wrapped, at the sample being rendered
+    travelled: f32,
+}
+
+impl Restart {
+    fn residual(&self, quality: OscillatorQuality) -> f32 {
+        let x = -self.offset;
+        self.step * step_residual(x, quality) + self.bend * ramp_residual(x, quality)
+    }
+}
+
+/// Band-limited hard sync for a phase accumulator. The master's restart is passed in with
+/// [`HardSync::trigger()`] two samples ahead, and the slave runs every sample through
+/// [`HardSync::process()`], which restarts its phase there. The jump in level and slope that
+/// causes is smoothed over with the same residuals as [`blep()`] and [`blamp()`], and the
+/// slave's own corrections for the part of the cycle that gets cut off are taken back out.
+#[derive(Clone, Copy, Default)]
+pub struct HardSync {
+    // A restart just passed in, how long after the sample after the next rendered one
+    pending: Option<f32>,
+    // The restart coming up, whose residual reaches back to the samples before it
+    upcoming: Option<Restart>,
+    // The last restart, whose residual still reaches the samples after it
+    last: Option<Restart>,
+}
+
+impl HardSync {
+    /// Restarts the cycle `fraction` (0..1] of a sample after the sample after the next rendered
+    /// sample, as passed on from the master's [`next_wrap()`].
+    pub fn trigger(&mut self, fraction: f32) {
+        self.pending = Some(fraction.clamp(0.0, 1.0));
+    }
+
+    /// Adds the sync corrections to `sample`, the slave's output at `phase`. `discontinuities`
+    /// are the ones the slave corrects for itself, and `naive` gives its naive level and slope
+    /// (per cycle) anywhere in the cycle, to work out how far a restart jumps.
+    ///
+    /// Returns the corrected sample, and the phase to carry on from at the next sample if the
+    /// cycle restarted.
+    pub fn process(
+        &mut self,
+        sample: f32,
+        phase: f32,
+        increment: f32,
+        quality: OscillatorQuality,
+        discontinuities: &[Discontinuity],
+        naive: impl Fn(f32) -> (f32, f32),
+    ) -> (f32, Option<f32>) {
+        let mut sample = sample;
+
+        if let Some(fraction) = self.pending.take() {
+            self.upcoming = Some(Restart {
+                offset: 1.0 + fraction,
+                step: 0.0,
+                bend: 0.0,
+                travelled: 0.0,
+            });
+        }
+
+        if let Some(last) = self.last.as_mut() {
+            sample += last.residual(quality);
+
+            // The slave's corrections reach back past the restart into a cycle that never
+            // happened, for anything it passed before the restart rather than after
+            for discontinuity in discontinuities {
+                let since = wrap_phase(phase - discontinuity.phase);
+                if since >= last.travelled {
+                    sample -= discontinuity.residual(since / increment, increment, quality);
+                }
+            }
+
+            // The widest residual only reaches two samples past the restart
+            last.offset -= 1.0;
+            last.travelled += increment;
+            if last.offset <= -2.0 {
+                self.last = None;
+            }
+        }
+
+        let Some(mut upcoming) = self.upcoming else {
+            return (sample, None);
+        };
+
+        // Where the cycle gets cut off, worked out again every sample from the current phase in
+        // case the frequency changes. Not wrapped, so a wrap before the restart still counts.
+        let end = phase + upcoming.offset * increment;
+        let (level_before, slope_before) = naive(if end >= 1.0 { end - 1.0 } else { end });
+        let (level_after, slope_after) = naive(0.0);
+        upcoming.step = level_after - level_before;
+        upcoming.bend = (slope_after - slope_before) * increment;
+        sample += upcoming.residual(quality);
+
+        // The slave's corrections also reach forwards to anything it would have passed after
+        // the restart, had the cycle carried on
+        for discontinuity in discontinuities {
+            let until = 1.0 - wrap_phase(phase - discontinuity.phase);
+            if phase + until > end {
+                sample -= discontinuity.residual(-until / increment, increment, quality);
+            }
+        }
+
+        if upcoming.offset > 1.0 {
+            upcoming.offset -= 1.0;
+            self.upcoming = Some(upcoming);
+            return (sample, None);
+        }
+
+        // The restart lands before the next sample
+        let restart_phase = (1.0 - upcoming.offset) * increment;
+        self.upcoming = None;
+        self.last = Some(Restart {
+            offset: upcoming.offset - 1.0,
+            travelled: restart_phase,
+            ..upcoming
+        });
+
+        (sample, Some(restart_phase))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fft::fft;
+    use crate::saw_wave::SawWave;
+    use crate::sine_wave::SineWave;
+    use crate::square_wave::SquareWave;
+    use crate::stereo_sample::StereoSample;
+    use crate::traits::AudioSource;
+    use crate::triangle_wave::TriangleWave;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const FFT_SIZE: usize = 4096;
+    // ~5kHz, chosen to land exactly on an FFT bin. The phase increment is then exactly
+    // representable, so the waveform repeats perfectly and no window is needed.
+    const FUNDAMENTAL_BIN: usize = 427;
+    const FUNDAMENTAL: f32 = (FUNDAMENTAL_BIN * SAMPLE_RATE as usize) as f32 / FFT_SIZE as f32;
+
+    // A master around 700Hz, also on a bin, for hard syncing a slave at a higher pitch. The
+    // synced waveform repeats with the master, so its harmonics are the master's.
+    const MASTER_BIN: usize = 61;
+    const MASTER: f32 = (MASTER_BIN * SAMPLE_RATE as usize) as f32 / FFT_SIZE as f32;
+    const SLAVE_RATIO: f32 = 2.71;
+
+    // Around 1.2 kHz for pulse width modulation, on a bin that puts the aliases halfway between
+    // the harmonics, clear of the sidebands a sweep spreads them into
+    const PWM_BIN: usize = 101;
+    const PWM_SIDEBANDS: usize = 20;
+
+    /// Energy of everything that isn't a harmonic of `fundamental_bin` in `block`, relative to
+    /// the energy of the harmonics, in dB.
+    fn aliasing_in_db(block: &[StereoSample], fundamental_bin: usize) -> f64 {
+        modulated_aliasing_in_db(block, fundamental_bin, 0)
+    }
+
+    /// As [`aliasing_in_db()`], but counting anything within `sidebands` bins of a harmonic as
+    /// part of it, for waveforms modulated over the block.
+    fn modulated_aliasing_in_db(
+        block: &[StereoSample],
+        fundamental_bin: usize,
+        sidebands: usize,
+    ) -> f64 {
+        let mut re: Vec<f64> = block.iter().map(|s| s.left as f64).collect();
+        let mut im = vec![0.0; FFT_SIZE];
+        fft(&mut re, &mut im);
+
+        let (mut harmonic_energy, mut alias_energy) = (0.0, 0.0);
+        for bin in 1..FFT_SIZE / 2 {
+            let energy = re[bin] * re[bin] + im[bin] * im[bin];
+            let offset = bin % fundamental_bin;
+            if offset.min(fundamental_bin - offset) <= sidebands {
+                harmonic_energy += energy;
+            } else {
+                alias_energy += energy;
+            }
+        }
+
+        10.0 * (alias_energy / harmonic_energy).log10()
+    }
+
+    /// Aliasing of `osc` playing on its own, in dB. See [`aliasing_in_db()`].
+    fn aliasing_db(osc: &mut impl AudioSource) -> f64 {
+        osc.set_frequency(FUNDAMENTAL);
+
+        // Let the first cycle pass so the start doesn't count as a discontinuity
+        let mut block = vec![StereoSample::from_mono(0.0); FFT_SIZE];
+        osc.fill_block(&mut block);
+        osc.fill_block(&mut block);
+
+        aliasing_in_db(&block, FUNDAMENTAL_BIN)
+    }
+
+    /// Aliasing of `slave` hard synced to a saw, in dB. See [`aliasing_in_db()`].
+    fn synced_aliasing_db(slave: &mut impl AudioSource) -> f64 {
+        let mut master = saw(OscillatorQuality::High);
+        master.set_frequency(MASTER);
+        slave.set_frequency(MASTER * SLAVE_RATIO);
+
+        let mut block = vec![StereoSample::from_mono(0.0); FFT_SIZE];
+        for _ in 0..2 {
+            for out in block.iter_mut() {
+                master.next_sample();
+                if let Some(fraction) = master.next_wrap() {
+                    slave.sync(fraction);
+                }
+                *out = slave.next_sample();
+            }
+        }
+
+        aliasing_in_db(&block, MASTER_BIN)
+    }
+
+    /// Aliasing of a square wave at `fundamental_bin` with its pulse width set to `width(index)`
+    /// before every sample of the block, in dB. See [`modulated_aliasing_in_db()`].
+    fn pwm_aliasing_db(
+        quality: OscillatorQuality,
+        fundamental_bin: usize,
+        sidebands: usize,
+        width: impl Fn(usize) -> f32,
+    ) -> f64 {
+        let mut osc = square(quality);
+        osc.set_frequency((fundamental_bin * SAMPLE_RATE as usize) as f32 / FFT_SIZE as f32);
+
+        let mut block = vec![StereoSample::from_mono(0.0); FFT_SIZE];
+        for _ in 0..2 {
+            for (index, out) in block.iter_mut().enumerate() {
+                osc.set_pulse_width(width(index));
+                *out = osc.next_sample();
+            }
+        }
+
+        modulated_aliasing_in_db(&block, fundamental_bin, sidebands)
+    }
+
+    fn saw(quality: OscillatorQuality) -> SawWave {
+        let mut osc = SawWave::new(SAMPLE_RATE, 0.0);
+        osc.set_quality(quality);
+        osc
+    }
+
+    fn square(quality: OscillatorQuality) -> SquareWave {
+        let mut osc = SquareWave::new(SAMPLE_RATE, 0.0);
+        osc.set_quality(quality);
+        osc
+    }
+
+    fn triangle(quality: OscillatorQuality) -> TriangleWave {
+        let mut osc = TriangleWave::new(SAMPLE_RATE, 0.0);
+        osc.set_quality(quality);
+        osc
+    }
+
+    #[test]
+    fn saw_aliasing_is_suppressed() {
+        let naive = aliasing_db(&mut saw(OscillatorQuality::Draft));
+        let standard = aliasing_db(&mut saw(OscillatorQuality::Standard));
+        let high = aliasing_db(&mut saw(OscillatorQuality::High));
+
+        assert!(standard < naive - 10.0, "standard {standard} dB, naive {naive} dB");
+        assert!(high < standard - 5.0, "high {high} dB, standard {standard} dB");
+        assert!(high < -28.0, "high {high} dB");
+    }
+
+    #[test]
+    fn square_aliasing_is_suppressed() {
+        let naive = aliasing_db(&mut square(OscillatorQuality::Draft));
+        let standard = aliasing_db(&mut square(OscillatorQuality::Standard));
+        let high = aliasing_db(&mut square(OscillatorQuality::High));
+
+        assert!(standard < naive - 10.0, "standard {standard} dB, naive {naive} dB");
+        assert!(high < standard - 5.0, "high {high} dB, standard {standard} dB");
+        assert!(high < -28.0, "high {high} dB");
+    }
+
+    #[test]
+    fn triangle_aliasing_is_suppressed() {
+        let naive = aliasing_db(&mut triangle(OscillatorQuality::Draft));
+        let standard = aliasing_db(&mut triangle(OscillatorQuality::Standard));
+        let high = aliasing_db(&mut triangle(OscillatorQuality::High));
+
+        assert!(standard < naive - 8.0, "standard {standard} dB, naive {naive} dB");
+        assert!(high < standard - 5.0, "high {high} dB, standard {standard} dB");
+        assert!(high < -42.0, "high {high} dB");
+    }
+
+    #[test]
+    fn hard_synced_saw_aliasing_is_suppressed() {
+        let naive = synced_aliasing_db(&mut saw(OscillatorQuality::Draft));
+        let standard = synced_aliasing_db(&mut saw(OscillatorQuality::Standard));
+        let high = synced_aliasing_db(&mut saw(OscillatorQuality::High));
+
+        assert!(standard < naive - 10.0, "standard {standard} dB, naive {naive} dB");
+        assert!(high < standard - 8.0, "high {high} dB, standard {standard} dB");
+        assert!(high < -36.0, "high {high} dB");
+    }
+
+    #[test]
+    fn hard_synced_square_aliasing_is_suppressed() {
+        let naive = synced_aliasing_db(&mut square(OscillatorQuality::Draft));
+        let standard = synced_aliasing_db(&mut square(OscillatorQuality::Standard));
+        let high = synced_aliasing_db(&mut square(OscillatorQuality::High));
+
+        assert!(standard < naive - 10.0, "standard {standard} dB, naive {naive} dB");
+        assert!(high < standard - 8.0, "high {high} dB, standard {standard} dB");
+        assert!(high < -36.0, "high {high} dB");
+    }
+
+    #[test]
+    fn hard_synced_triangle_aliasing_is_suppressed() {
+        // The slave is cut off on its way down, so every restart changes the slope as well
+        let naive = synced_aliasing_db(&mut triangle(OscillatorQuality::Draft));
+        let standard = synced_aliasing_db(&mut triangle(OscillatorQuality::Standard));
+        let high = synced_aliasing_db(&mut triangle(OscillatorQuality::High));
+
+        assert!(standard < naive - 10.0, "standard {standard} dB, naive {naive} dB");
+        assert!(high < standard - 8.0, "high {high} dB, standard {standard} dB");
+        assert!(high < -46.0, "high {high} dB");
+    }
+
+    #[test]
+    fn hard_synced_sine_aliasing_is_suppressed() {
+        let synced = synced_aliasing_db(&mut SineWave::new(SAMPLE_RATE, 0.0));
+
+        assert!(synced < -48.0, "{synced} dB");
+    }
+
+    #[test]
+    fn narrow_pulse_aliasing_is_suppressed() {
+        // At `PWM_BIN`, as 5 kHz pulses this narrow would be under a sample wide
+        for width in [0.05, 0.95] {
+            let naive = pwm_aliasing_db(OscillatorQuality::Draft, PWM_BIN, 0, |_| width);
+            let standard = pwm_aliasing_db(OscillatorQuality::Standard, PWM_BIN, 0, |_| width);
+            let high = pwm_aliasing_db(OscillatorQuality::High, PWM_BIN, 0, |_| width);
+
+            assert!(standard < naive - 10.0, "width {width}: {standard} dB, naive {naive} dB");
+            assert!(high < standard - 8.0, "width {width}: high {high} dB, {standard} dB");
+            assert!(high < -36.0, "width {width}: high {high} dB");
+        }
+    }
+
+    #[test]
+    fn pulse_width_sweep_aliasing_is_suppressed() {
+        // One sweep from 30% to 70% and back per block, moving the width every sample. The
+        // sweep spreads each harmonic into sidebands, so those are counted as part of it.
+        let sweep = |index: usize| {
+            0.5 + 0.2 * (std::f32::consts::TAU * index as f32 / FFT_SIZE as f32).sin()
+        };
+        let naive = pwm_aliasing_db(OscillatorQuality::Draft, PWM_BIN, PWM_SIDEBANDS, sweep);
+        let standard = pwm_aliasing_db(OscillatorQuality::Standard, PWM_BIN, PWM_SIDEBANDS, sweep);
+        let high = pwm_aliasing_db(OscillatorQuality::High, PWM_BIN, PWM_SIDEBANDS, sweep);
+
+        assert!(standard < naive - 10.0, "standard {standard} dB, naive {naive} dB");
+        assert!(high < standard - 5.0, "high {high} dB, standard {standard} dB");
+        assert!(high < -36.0, "high {high} dB");
+    }
+}
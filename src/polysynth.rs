@@ -1,49 +1,486 @@
+use crate::oscillator::Waveform;
+use crate::fm::FmAlgorithm;
+use crate::noise::NoiseColour;
+use crate::oscillator_mixer::SubOctave;
+use crate::poly_blep::OscillatorQuality;
+use crate::state_variable_filter::FilterMode;
+use crate::ladder_filter::LadderSlope;
+use crate::lfo::{Lfo, LfoMode, LfoShape, NUM_LFOS};
+use crate::mod_matrix::{ModDestination, ModMatrix, ModSource};
+use crate::note_stack::{HeldNote, NotePriority, NoteStack};
 use crate::stereo_sample::StereoSample;
 use crate::traits::AudioSource;
-use crate::voice::Voice;
+use crate::velocity::VelocityCurve;
+use crate::voice::{FilterType, NoteId, Voice, VoiceEngine};
+use crate::wavetable::Wavetable;
+
+use nih_plug::prelude::Enum;
+use std::sync::Arc;
+
+/// The largest block rendered in one go. Longer blocks are split up.
+pub const MAX_BLOCK_SIZE: usize = 64;
+
+/// Voices are preallocated up to this limit, so changing the voice count never allocates.
+pub const MAX_VOICES: usize = 64;
+
+/// The most unison copies of each oscillator playing across all voices at once. Unison is
+/// scaled down when the voice count times the unison count would go over it, so the worst case
+/// costs no more than 64 voices with 4 copies each.
+pub const MAX_UNISON_VOICES: usize = 256;
+
+// Upper bound on voice terminations queued between calls to `drain_terminated`.
+// Preallocated so reporting finished voices never allocates on the audio thread.
+const MAX_TERMINATED: usize = 256;
+
+pub fn midi_note_to_frequency(note_number: u8) -> f32 {
+    // A4 = note 69 = 440 Hz
+    440.0 * 2.0_f32.powf((note_number as f32 - 69.0) / 12.0)
+}
+
+/// Which voice gets taken over when a note is played and every voice is busy.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VoiceStealing {
+    /// The voice that was triggered first.
+    #[id = "oldest"]
+    Oldest,
+    /// The voice with the lowest current envelope amplitude.
+    #[id = "quietest"]
+    Quietest,
+    /// Voices that have already been released, oldest first, then voices only the pedals are
+    /// holding, before any voice whose key is down.
+    #[id = "released-first"]
+    #[name = "Released First"]
+    ReleasedFirst,
+    #[id = "lowest"]
+    #[name = "Lowest Note"]
+    Lowest,
+    #[id = "highest"]
+    #[name = "Highest Note"]
+    Highest,
+    /// Replaying a note that is still sounding retriggers its voice instead of taking another.
+    /// Otherwise behaves like `Oldest`.
+    #[id = "same-note"]
+    #[name = "Same Note"]
+    SameNote,
+}
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PlayMode {
+    #[id = "poly"]
+    Poly,
+    /// A single voice that retriggers its envelope on every note.
+    #[id = "mono"]
+    Mono,
+    /// A single voice that glides to new notes without retriggering while a key is held.
+    #[id = "legato"]
+    Legato,
+}
+
+/// Something the host tells the synth while it plays, already translated from MIDI.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SynthEvent {
+    NoteOn { note_id: NoteId, velocity: f32 },
+    NoteOff { note_id: NoteId },
+    PolyPressure { note_id: NoteId, pressure: f32 },
+    /// Where the pitch bend wheel is, -1..1 with 0 at rest.
+    PitchBend(f32),
+    /// Where the mod wheel is, 0..1.
+    ModWheel(f32),
+    /// The pressure on the whole channel, 0..1.
+    ChannelPressure(f32),
+    /// Whether the sustain pedal is down.
+    SustainPedal(bool),
+    /// Whether the sostenuto pedal is down.
+    SostenutoPedal(bool),
+}
+
+/// What [`PolySynth::process()`] needs from the host while it renders a buffer. Timings are
+/// sample offsets into that buffer.
+pub trait SynthHost {
+    /// Takes the next event, in timing order.
+    fn next_event(&mut self) -> Option<(usize, SynthEvent)>;
+
+    /// Lets the host know a note's voice has finished, or been stolen.
+    fn voice_terminated(&mut self, timing: usize, note_id: NoteId);
+
+    /// Pushes the settings that decide which voice a new note goes to into the synth.
+    fn update_voice_allocation(&mut self, synth: &mut PolySynth);
+
+    /// Pushes the sound parameters into the synth for the `MAX_BLOCK_SIZE` samples from `timing`.
+    /// Called every `MAX_BLOCK_SIZE` samples counted from the last reset rather than from the
+    /// start of each buffer, so smoothing comes out the same however the host sizes its buffers.
+    /// Called before the events at `timing`, so they see the new parameters too.
+    fn update_params(&mut self, synth: &mut PolySynth, timing: usize);
+}
 
 #[derive(Clone)]
 pub struct PolySynth {
     pub voices: Vec<Voice>,
+    // Only the first `voice_count` voices are given new notes.
+    voice_count: usize,
+    // The unison count asked for, which voices play as much of as `MAX_UNISON_VOICES` allows
+    unison: usize,
+    voice_stealing: VoiceStealing,
+    play_mode: PlayMode,
+    // Keys held down in mono and legato modes, so releasing a key can return to an older one.
+    held_notes: NoteStack,
+    note_priority: NotePriority,
+    // Incremented on every note-on, so voices can be ordered by when they were triggered.
+    trigger_counter: u64,
+    // Notes whose voices have finished or been stolen, waiting to be reported to the host.
+    terminated: Vec<NoteId>,
+    // Scratch space each voice renders into before being mixed, `MAX_BLOCK_SIZE` long
+    voice_block: Vec<StereoSample>,
+    // The LFOs shared by every voice, used in place of the voices' own for LFOs in global mode
+    lfos: [Lfo; NUM_LFOS],
+    // The global LFOs' values for every sample of the block being rendered, `MAX_BLOCK_SIZE` long
+    lfo_block: Vec<[f32; NUM_LFOS]>,
+    // The routings every voice evaluates against its own modulation sources
+    mod_matrix: ModMatrix,
+    // Where the pitch bend wheel is (-1..1), and how far it bends at either end, in semitones
+    pitch_bend: f32,
+    pitch_bend_range: f32,
+    sustain_pedal: bool,
+    sostenuto_pedal: bool,
+    // Samples rendered since the last reset, which parameter updates are lined up to
+    position: u64,
 }
 
 impl PolySynth {
     pub fn new(sample_rate: u32, n_voices: usize) -> Self {
-        let mut voices = Vec::new();
-        for _ in 0..n_voices {
-            voices.push(Voice::new(sample_rate, 220.0));
+        let mut voices = Vec::with_capacity(MAX_VOICES);
+        for index in 0..MAX_VOICES {
+            let mut voice = Voice::new(sample_rate, 220.0);
+            voice.set_seed(index as u32 + 1);
+            voices.push(voice);
+        }
+        Self {
+            voices,
+            voice_count: n_voices.clamp(1, MAX_VOICES),
+            unison: 1,
+            voice_stealing: VoiceStealing::Oldest,
+            play_mode: PlayMode::Poly,
+            held_notes: NoteStack::new(),
+            note_priority: NotePriority::Last,
+            trigger_counter: 0,
+            terminated: Vec::with_capacity(MAX_TERMINATED),
+            voice_block: vec![StereoSample::from_mono(0.0); MAX_BLOCK_SIZE],
+            // Voices are seeded from 1 up, so seeding these from 0 keeps them apart from every
+            // voice's own LFOs
+            lfos: std::array::from_fn(|index| Lfo::new(sample_rate as f32, index as u32)),
+            lfo_block: vec![[0.0; NUM_LFOS]; MAX_BLOCK_SIZE],
+            mod_matrix: ModMatrix::new(),
+            pitch_bend: 0.0,
+            pitch_bend_range: 2.0,
+            sustain_pedal: false,
+            sostenuto_pedal: false,
+            position: 0,
         }
-        Self { voices }
     }
 
-    pub fn play(&mut self, freq: f32) {
-        // First, try to find an inactive voice
-        if let Some(voice) = self.voices.iter_mut().find(|v| !v.active) {
-            voice.play(freq); // Use the inactive voice
-        } else {
-            // No inactive voice: Find the closest frequency to `freq`
-            let closest_voice = self.voices
+    pub fn play(&mut self, note_id: NoteId, velocity: f32) {
+        // A new phrase restarts the global LFOs, notes played over held ones leave them running
+        if !self.voices.iter().any(|v| v.active && !v.env.is_released()) {
+            self.lfos.iter_mut().for_each(|lfo| lfo.trigger());
+        }
+
+        if self.play_mode != PlayMode::Poly {
+            self.held_notes.push(HeldNote { note_id, velocity });
+            self.update_mono_voice();
+            return;
+        }
+
+        let freq = midi_note_to_frequency(note_id.note);
+        let index = self.allocate_voice(&note_id);
+        let voice = &mut self.voices[index];
+
+        let same_note = voice.note_id.note == note_id.note && voice.note_id.channel == note_id.channel;
+        // Striking a note the sostenuto pedal is holding keeps it held, like the piano damper it
+        // copies staying up. A stolen voice's latch is dropped along with its note.
+        let latched = voice.active && voice.sostenuto && same_note;
+
+        if voice.active && self.terminated.len() < MAX_TERMINATED {
+            // The stolen note is gone as far as the host is concerned.
+            self.terminated.push(voice.note_id);
+        }
+
+        self.trigger_counter += 1;
+        voice.trigger_order = self.trigger_counter;
+        voice.play(note_id, freq, velocity);
+        voice.sostenuto = latched;
+    }
+
+    /// Picks the voice for a new note: a retriggered same note, an inactive voice, or else one
+    /// stolen according to the voice stealing mode.
+    fn allocate_voice(&self, note_id: &NoteId) -> usize {
+        let voices = &self.voices[..self.voice_count];
+
+        // A note the pedals are holding after its key was let go is struck again on its own
+        // voice, rather than piling up more voices playing the same note
+        if let Some(index) = voices.iter().position(|v| {
+            v.active
+                && !v.key_held
+                && !v.env.is_released()
+                && v.note_id.note == note_id.note
+                && v.note_id.channel == note_id.channel
+        }) {
+            return index;
+        }
+
+        if self.voice_stealing == VoiceStealing::SameNote {
+            if let Some(index) = voices.iter().position(|v| {
+                v.active && v.note_id.note == note_id.note && v.note_id.channel == note_id.channel
+            }) {
+                return index;
+            }
+        }
+
+        if let Some(index) = voices.iter().position(|v| !v.active) {
+            return index;
+        }
+
+        let candidates = voices.iter().enumerate();
+        let stolen = match self.voice_stealing {
+            VoiceStealing::Oldest | VoiceStealing::SameNote => {
+                candidates.min_by_key(|(_, v)| v.trigger_order)
+            }
+            VoiceStealing::Quietest => candidates.min_by(|(_, v1), (_, v2)| {
+                // `total_cmp` keeps this well defined even if an envelope ever produces NaN
+                v1.env.get_amplitude().total_cmp(&v2.env.get_amplitude())
+            }),
+            VoiceStealing::ReleasedFirst => {
+                candidates.min_by_key(|(_, v)| (!v.env.is_released(), v.key_held, v.trigger_order))
+            }
+            VoiceStealing::Lowest => {
+                candidates.min_by_key(|(_, v)| (v.note_id.note, v.trigger_order))
+            }
+            VoiceStealing::Highest => {
+                candidates.min_by_key(|(_, v)| (u8::MAX - v.note_id.note, v.trigger_order))
+            }
+        };
+
+        stolen.map(|(index, _)| index).unwrap_or(0)
+    }
+
+    pub fn set_voice_count(&mut self, voice_count: usize) {
+        let voice_count = voice_count.clamp(1, MAX_VOICES);
+
+        // Voices above the new limit are released and left to fade out rather than cut off
+        if voice_count < self.voice_count {
+            self.voices[voice_count..self.voice_count]
                 .iter_mut()
-                .min_by(|v1, v2| {
-                    (v1.get_frequency() - freq) 
-                        .abs()
-                        .partial_cmp(&(v2.get_frequency() - freq).abs())
-                        .unwrap()
-                });
-
-            if let Some(voice) = closest_voice {
-                voice.play(freq);
+                .filter(|v| v.active)
+                .for_each(|v| v.stop());
+        }
+
+        self.voice_count = voice_count;
+        self.update_unison();
+    }
+
+    pub fn set_voice_stealing(&mut self, voice_stealing: VoiceStealing) {
+        self.voice_stealing = voice_stealing;
+    }
+
+    /// Makes the single mono voice follow the held note with the highest priority, or releases
+    /// it once no keys are held.
+    fn update_mono_voice(&mut self) {
+        let voice = &mut self.voices[0];
+        let sounding = voice.active && !voice.env.is_released();
+
+        let Some(target) = self.held_notes.select(self.note_priority) else {
+            // The pedals can hold on to the last note after every key is let go
+            voice.key_held = false;
+            if sounding && !self.sustain_pedal && !voice.sostenuto {
+                voice.stop();
+            }
+            return;
+        };
+        voice.key_held = true;
+
+        if sounding && voice.note_id == target.note_id {
+            return;
+        }
+
+        let freq = midi_note_to_frequency(target.note_id.note);
+        self.trigger_counter += 1;
+        voice.trigger_order = self.trigger_counter;
+
+        if sounding && self.play_mode == PlayMode::Legato {
+            // The voice carries on sounding, so the host only hears about it once it stops
+            voice.glide_to(target.note_id, freq);
+        } else {
+            if voice.active && self.terminated.len() < MAX_TERMINATED {
+                // The previous note is cut off by the retrigger, so it's gone as far as the host
+                // is concerned.
+                self.terminated.push(voice.note_id);
             }
+            voice.play(target.note_id, freq, target.velocity);
+        }
+    }
+
+    pub fn set_play_mode(&mut self, play_mode: PlayMode) {
+        if (play_mode == PlayMode::Poly) != (self.play_mode == PlayMode::Poly) {
+            // Held notes don't carry over between the poly and mono voice layouts
+            self.voices
+                .iter_mut()
+                .filter(|v| v.active)
+                .for_each(|v| v.stop());
+            self.held_notes.clear();
+        }
+
+        self.play_mode = play_mode;
+    }
+
+    pub fn set_note_priority(&mut self, note_priority: NotePriority) {
+        self.note_priority = note_priority;
+    }
+
+    pub fn stop(&mut self, note_id: NoteId) {
+        if self.play_mode != PlayMode::Poly {
+            self.held_notes.remove(&note_id);
+            self.update_mono_voice();
+            return;
+        }
+
+        let sustain_pedal = self.sustain_pedal;
+        self.voices
+            .iter_mut()
+            .filter(|v| v.active && v.note_id.matches(&note_id))
+            .for_each(|v| {
+                v.key_held = false;
+                if !sustain_pedal && !v.sostenuto {
+                    v.stop();
+                }
+            });
+    }
+
+    /// Presses or lifts the sustain pedal. While it's down, letting go of a key leaves its note
+    /// sounding until the pedal is lifted.
+    pub fn set_sustain_pedal(&mut self, down: bool) {
+        if down == self.sustain_pedal {
+            return;
+        }
+
+        self.sustain_pedal = down;
+        if !down {
+            self.release_unheld_voices();
+        }
+    }
+
+    /// Presses or lifts the sostenuto pedal. Pressing it holds on to the notes whose keys are
+    /// down right then, and only those, until it's lifted.
+    pub fn set_sostenuto_pedal(&mut self, down: bool) {
+        // Hosts may send the same pedal position again, which mustn't latch notes played since
+        if down == self.sostenuto_pedal {
+            return;
+        }
+
+        self.sostenuto_pedal = down;
+        if down {
+            self.voices
+                .iter_mut()
+                .for_each(|v| v.sostenuto = v.active && v.key_held);
+        } else {
+            self.voices.iter_mut().for_each(|v| v.sostenuto = false);
+            self.release_unheld_voices();
         }
     }
 
-    pub fn stop(&mut self, freq: f32) {
+    /// Releases every voice whose key has been let go, unless a pedal is still holding it.
+    fn release_unheld_voices(&mut self) {
+        if self.sustain_pedal {
+            return;
+        }
+
         self.voices
             .iter_mut()
-            .filter(|v| v.active && v.get_frequency() == freq)
+            .filter(|v| v.active && !v.key_held && !v.sostenuto && !v.env.is_released())
             .for_each(|v| v.stop());
     }
 
+    /// Takes the notes whose voices have finished since the last call.
+    pub fn drain_terminated(&mut self) -> impl Iterator<Item = NoteId> + '_ {
+        self.terminated.drain(..)
+    }
+
+    /// Renders `output`, handling the host's events on the exact samples they land on however
+    /// the host sizes its buffers.
+    pub fn process(&mut self, output: &mut [StereoSample], host: &mut impl SynthHost) {
+        let num_samples = output.len();
+        let mut next_event = host.next_event();
+        let mut block_start = 0;
+
+        // Render in sub-blocks that end on the parameter update grid, and wherever the next event
+        // lands
+        while block_start < num_samples {
+            let grid_offset = (self.position % MAX_BLOCK_SIZE as u64) as usize;
+            let mut block_end = (block_start + MAX_BLOCK_SIZE - grid_offset).min(num_samples);
+
+            // Needed before the note events so new notes are allocated with the current settings,
+            // and start with this block's parameters rather than the last one's
+            host.update_voice_allocation(self);
+            if grid_offset == 0 {
+                host.update_params(self, block_start);
+            }
+
+            // Handle every event due at the start of this block. Events timed past the end of the
+            // buffer land on its last sample rather than being lost.
+            while let Some((timing, event)) = next_event {
+                let timing = timing.min(num_samples - 1);
+                if timing > block_start {
+                    block_end = timing.min(block_end);
+                    break;
+                }
+
+                self.handle_event(event);
+                next_event = host.next_event();
+            }
+
+            // Voices stolen by the events above end here
+            self.report_terminated(host, block_start);
+
+            self.fill_block(&mut output[block_start..block_end]);
+            self.position += (block_end - block_start) as u64;
+
+            // Voices whose release finished during this block
+            self.report_terminated(host, block_end - 1);
+
+            block_start = block_end;
+        }
+
+        // Anything left over came with an empty buffer. It still has to be handled, or a lost
+        // note-off would leave its note stuck.
+        if next_event.is_some() {
+            host.update_voice_allocation(self);
+        }
+        while let Some((_, event)) = next_event {
+            self.handle_event(event);
+            next_event = host.next_event();
+        }
+        self.report_terminated(host, num_samples.saturating_sub(1));
+    }
+
+    /// Acts on an event from the host.
+    pub fn handle_event(&mut self, event: SynthEvent) {
+        match event {
+            SynthEvent::NoteOn { note_id, velocity } => self.play(note_id, velocity),
+            SynthEvent::NoteOff { note_id } => self.stop(note_id),
+            SynthEvent::PolyPressure { note_id, pressure } => self.set_poly_aftertouch(note_id, pressure),
+            SynthEvent::PitchBend(bend) => self.set_pitch_bend(bend),
+            SynthEvent::ModWheel(position) => self.set_mod_wheel(position),
+            SynthEvent::ChannelPressure(pressure) => self.set_channel_pressure(pressure),
+            SynthEvent::SustainPedal(down) => self.set_sustain_pedal(down),
+            SynthEvent::SostenutoPedal(down) => self.set_sostenuto_pedal(down),
+        }
+    }
+
+    /// Passes the notes whose voices have finished since the last call on to the host.
+    fn report_terminated(&mut self, host: &mut impl SynthHost, timing: usize) {
+        for note_id in self.terminated.drain(..) {
+            host.voice_terminated(timing, note_id);
+        }
+    }
+
     pub fn set_attack(&mut self, attack_s: f32) {
         self.voices
             .iter_mut()
@@ -68,24 +505,1242 @@ impl PolySynth {
             .for_each(|v| v.env.set_release(release_s));
     }
 
-    pub fn set_glide(&mut self, glide_s: f32) {
+    pub fn set_attack_curve(&mut self, amount: f32) {
         self.voices
             .iter_mut()
-            .for_each(|v| v.frequency_env.set_ramp(glide_s));
+            .for_each(|v| v.env.set_attack_curve(amount));
     }
-}
 
-impl AudioSource for PolySynth {
-    fn next_sample(&mut self) -> StereoSample {
+    pub fn set_decay_curve(&mut self, amount: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.env.set_decay_curve(amount));
+    }
 
-        let mut stereo_sample = StereoSample { left: 0.0, right: 0.0 };
+    pub fn set_release_curve(&mut self, amount: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.env.set_release_curve(amount));
+    }
 
-        for v in self.voices.iter_mut() {
-            let next_sample = v.next_sample();
-            stereo_sample.left += next_sample.left;
-            stereo_sample.right += next_sample.right;
-        }
+    pub fn set_rc_release(&mut self, rc_release: bool) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.env.set_rc_release(rc_release));
+    }
 
-        stereo_sample
+    pub fn set_release_threshold(&mut self, threshold: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.env.set_release_threshold(threshold));
+    }
+
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_velocity_curve(curve));
+    }
+
+    pub fn set_velocity_depth(&mut self, depth: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_velocity_depth(depth));
+    }
+
+    pub fn set_filter_type(&mut self, filter_type: FilterType) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_filter_type(filter_type));
+    }
+
+    pub fn set_filter_mode(&mut self, mode: FilterMode) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.svf.set_mode(mode));
+    }
+
+    pub fn set_filter_slope(&mut self, slope: LadderSlope) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.ladder.set_slope(slope));
+    }
+
+    pub fn set_filter_cutoff(&mut self, cutoff_hz: f32) {
+        self.voices.iter_mut().for_each(|v| {
+            v.svf.set_cutoff(cutoff_hz);
+            v.ladder.set_cutoff(cutoff_hz);
+        });
+    }
+
+    pub fn set_filter_resonance(&mut self, resonance: f32) {
+        self.voices.iter_mut().for_each(|v| {
+            v.svf.set_resonance(resonance);
+            v.ladder.set_resonance(resonance);
+        });
+    }
+
+    pub fn set_filter_drive(&mut self, drive: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.ladder.set_drive(drive));
+    }
+
+    pub fn set_filter_attack(&mut self, attack_s: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.filter_env.set_attack(attack_s));
+    }
+
+    pub fn set_filter_decay(&mut self, decay_s: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.filter_env.set_decay(decay_s));
+    }
+
+    pub fn set_filter_sustain(&mut self, sustain_level: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.filter_env.set_sustain(sustain_level));
+    }
+
+    pub fn set_filter_release(&mut self, release_s: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.filter_env.set_release(release_s));
+    }
+
+    pub fn set_filter_env_amount(&mut self, octaves: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_filter_env_amount(octaves));
+    }
+
+    pub fn set_key_tracking(&mut self, amount: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_key_tracking(amount));
+    }
+
+    pub fn set_filter_velocity_depth(&mut self, octaves: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_filter_velocity_depth(octaves));
+    }
+
+    pub fn set_lfo_shape(&mut self, index: usize, shape: LfoShape) {
+        self.lfos[index].set_shape(shape);
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.lfos[index].set_shape(shape));
     }
-}
\ No newline at end of file
+
+    pub fn set_lfo_mode(&mut self, index: usize, mode: LfoMode) {
+        self.lfos[index].set_mode(mode);
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.lfos[index].set_mode(mode));
+    }
+
+    pub fn set_lfo_rate(&mut self, index: usize, rate_hz: f32) {
+        self.lfos[index].set_rate(rate_hz);
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.lfos[index].set_rate(rate_hz));
+    }
+
+    pub fn set_lfo_phase_offset(&mut self, index: usize, offset: f32) {
+        self.lfos[index].set_phase_offset(offset);
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.lfos[index].set_phase_offset(offset));
+    }
+
+    pub fn set_lfo_fade_in(&mut self, index: usize, fade_in_s: f32) {
+        self.lfos[index].set_fade_in(fade_in_s);
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.lfos[index].set_fade_in(fade_in_s));
+    }
+
+    /// Lines global LFO `index` up with the host's transport, see
+    /// [`Lfo::set_transport_position()`]. Per-voice LFOs start with their notes instead.
+    pub fn set_lfo_transport_position(&mut self, index: usize, cycles: Option<f64>) {
+        self.lfos[index].set_transport_position(cycles);
+    }
+
+    pub fn set_lfo_retrigger(&mut self, index: usize, retrigger: bool) {
+        self.lfos[index].set_retrigger(retrigger);
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.lfos[index].set_retrigger(retrigger));
+    }
+
+    pub fn set_mod_source(&mut self, index: usize, source: ModSource) {
+        self.mod_matrix.set_source(index, source);
+    }
+
+    pub fn set_mod_destination(&mut self, index: usize, destination: ModDestination) {
+        self.mod_matrix.set_destination(index, destination);
+    }
+
+    pub fn set_mod_amount(&mut self, index: usize, amount: f32) {
+        self.mod_matrix.set_amount(index, amount);
+    }
+
+    pub fn set_mod_via(&mut self, index: usize, via: ModSource) {
+        self.mod_matrix.set_via(index, via);
+    }
+
+    /// Sets the pressure on the key playing `note_id` (0..1), as polyphonic aftertouch.
+    pub fn set_poly_aftertouch(&mut self, note_id: NoteId, pressure: f32) {
+        self.voices
+            .iter_mut()
+            .filter(|v| v.active && v.note_id.matches(&note_id))
+            .for_each(|v| v.set_aftertouch(pressure));
+    }
+
+    /// Sets the pressure on the whole channel (0..1), as channel aftertouch. Like the mod wheel,
+    /// every voice follows it, including silent ones.
+    pub fn set_channel_pressure(&mut self, pressure: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_channel_pressure(pressure));
+    }
+
+    pub fn set_voice_engine(&mut self, engine: VoiceEngine) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_engine(engine));
+    }
+
+    pub fn set_fm_algorithm(&mut self, algorithm: FmAlgorithm) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_algorithm(algorithm));
+    }
+
+    pub fn set_fm_ratio(&mut self, index: usize, ratio: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_ratio(index, ratio));
+    }
+
+    pub fn set_fm_fixed(&mut self, index: usize, fixed: bool) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_fixed(index, fixed));
+    }
+
+    pub fn set_fm_fixed_frequency(&mut self, index: usize, freq: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_fixed_frequency(index, freq));
+    }
+
+    pub fn set_fm_level(&mut self, index: usize, level: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_level(index, level));
+    }
+
+    pub fn set_fm_feedback(&mut self, index: usize, feedback: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_feedback(index, feedback));
+    }
+
+    pub fn set_fm_velocity_sensitivity(&mut self, index: usize, sensitivity: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_velocity_sensitivity(index, sensitivity));
+    }
+
+    pub fn set_fm_attack(&mut self, index: usize, attack_s: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_attack(index, attack_s));
+    }
+
+    pub fn set_fm_decay(&mut self, index: usize, decay_s: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_decay(index, decay_s));
+    }
+
+    pub fn set_fm_sustain(&mut self, index: usize, sustain_level: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_sustain(index, sustain_level));
+    }
+
+    pub fn set_fm_release(&mut self, index: usize, release_s: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.fm.set_release(index, release_s));
+    }
+
+    pub fn set_osc_waveform(&mut self, index: usize, waveform: Waveform) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_waveform(index, waveform));
+    }
+
+    pub fn set_osc_octave(&mut self, index: usize, octave: i32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_octave(index, octave));
+    }
+
+    pub fn set_osc_semitone(&mut self, index: usize, semitone: i32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_semitone(index, semitone));
+    }
+
+    pub fn set_osc_fine(&mut self, index: usize, fine_cents: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_fine(index, fine_cents));
+    }
+
+    pub fn set_osc_level(&mut self, index: usize, level: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_level(index, level));
+    }
+
+    pub fn set_osc_pan(&mut self, index: usize, pan: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_pan(index, pan));
+    }
+
+    pub fn set_osc_sync(&mut self, index: usize, sync: bool) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_sync(index, sync));
+    }
+
+    pub fn set_osc_ring(&mut self, index: usize, amount: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_ring(index, amount));
+    }
+
+    pub fn set_sub_waveform(&mut self, waveform: Waveform) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_sub_waveform(waveform));
+    }
+
+    pub fn set_sub_octave(&mut self, octave: SubOctave) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_sub_octave(octave));
+    }
+
+    pub fn set_sub_level(&mut self, level: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_sub_level(level));
+    }
+
+    pub fn set_noise_level(&mut self, level: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_noise_level(level));
+    }
+
+    pub fn set_noise_colour(&mut self, colour: NoiseColour) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_noise_colour(colour));
+    }
+
+    pub fn set_noise_stereo(&mut self, stereo: bool) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_noise_stereo(stereo));
+    }
+
+    /// Sets the unison count, lowered as far as needed to keep within [`MAX_UNISON_VOICES`].
+    pub fn set_unison(&mut self, count: usize) {
+        self.unison = count;
+        self.update_unison();
+    }
+
+    /// The unison count voices actually play.
+    pub fn unison(&self) -> usize {
+        self.unison.min(MAX_UNISON_VOICES / self.voice_count).max(1)
+    }
+
+    fn update_unison(&mut self) {
+        let count = self.unison();
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_unison(count));
+    }
+
+    pub fn set_unison_detune(&mut self, detune_cents: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_unison_detune(detune_cents));
+    }
+
+    pub fn set_unison_detune_curve(&mut self, amount: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_unison_detune_curve(amount));
+    }
+
+    pub fn set_unison_spread(&mut self, spread: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_unison_spread(spread));
+    }
+
+    pub fn set_unison_blend(&mut self, blend: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_unison_blend(blend));
+    }
+
+    pub fn set_pulse_width(&mut self, pulse_width: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_pulse_width(pulse_width));
+    }
+
+    pub fn set_wavetable_position(&mut self, position: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_wavetable_position(position));
+    }
+
+    /// Swaps the table every voice plays. Only ever shares the table, so this doesn't allocate.
+    pub fn set_wavetable(&mut self, wavetable: Arc<Wavetable>) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_wavetable(wavetable.clone()));
+    }
+
+    pub fn set_oscillator_quality(&mut self, quality: OscillatorQuality) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.mixer.set_quality(quality));
+    }
+
+    pub fn set_glide(&mut self, glide_s: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.frequency_env.set_ramp(glide_s));
+    }
+
+    /// Sets where the pitch bend wheel is, from -1 (fully down) to 1 (fully up).
+    pub fn set_pitch_bend(&mut self, bend: f32) {
+        self.pitch_bend = bend;
+        self.update_pitch_bend();
+    }
+
+    /// Sets how far the pitch bend wheel bends at either end, in semitones.
+    pub fn set_pitch_bend_range(&mut self, semitones: f32) {
+        self.pitch_bend_range = semitones;
+        self.update_pitch_bend();
+    }
+
+    /// Sets the mod wheel position (0..1). Every voice follows it, including silent ones, so new
+    /// notes start from wherever the wheel is.
+    pub fn set_mod_wheel(&mut self, mod_wheel: f32) {
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_mod_wheel(mod_wheel));
+    }
+
+    fn update_pitch_bend(&mut self) {
+        let semitones = self.pitch_bend * self.pitch_bend_range;
+        self.voices
+            .iter_mut()
+            .for_each(|v| v.set_pitch_bend(semitones));
+    }
+
+    /// Advances the global LFOs by a sample. LFOs in per-voice mode are left alone, the voices
+    /// run their own.
+    fn next_global_lfos(&mut self) -> [f32; NUM_LFOS] {
+        let mut values = [0.0; NUM_LFOS];
+        for (value, lfo) in values.iter_mut().zip(self.lfos.iter_mut()) {
+            if lfo.mode() == LfoMode::Global {
+                *value = lfo.next();
+            }
+        }
+        values
+    }
+}
+
+impl AudioSource for PolySynth {
+    fn reset(&mut self) {
+        self.voices.iter_mut().for_each(|v| v.reset());
+        self.lfos.iter_mut().for_each(|lfo| lfo.reset());
+        self.held_notes.clear();
+        self.sustain_pedal = false;
+        self.sostenuto_pedal = false;
+        // Nothing is playing any more, so there is nothing left to report either.
+        self.terminated.clear();
+        self.position = 0;
+    }
+
+    fn next_sample(&mut self) -> StereoSample {
+
+        let mut stereo_sample = StereoSample { left: 0.0, right: 0.0 };
+        let global_lfos = self.next_global_lfos();
+
+        for v in self.voices.iter_mut() {
+            let was_active = v.active;
+            let next_sample = v.next_sample(&global_lfos, &self.mod_matrix);
+            stereo_sample.left += next_sample.left;
+            stereo_sample.right += next_sample.right;
+
+            if was_active && !v.active && self.terminated.len() < MAX_TERMINATED {
+                self.terminated.push(v.note_id);
+            }
+        }
+
+        stereo_sample
+    }
+
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        for chunk in block.chunks_mut(MAX_BLOCK_SIZE) {
+            chunk.fill(StereoSample::from_mono(0.0));
+            for index in 0..chunk.len() {
+                self.lfo_block[index] = self.next_global_lfos();
+            }
+
+            let voice_block = &mut self.voice_block[..chunk.len()];
+            let lfo_block = &self.lfo_block[..chunk.len()];
+
+            // Inactive voices are skipped entirely rather than rendering silence
+            for v in self.voices.iter_mut().filter(|v| v.active) {
+                v.fill_block(voice_block, lfo_block, &self.mod_matrix);
+
+                for (out, voice_sample) in chunk.iter_mut().zip(voice_block.iter()) {
+                    out.left += voice_sample.left;
+                    out.right += voice_sample.right;
+                }
+
+                if !v.active && self.terminated.len() < MAX_TERMINATED {
+                    self.terminated.push(v.note_id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adsr_envelope::AdsrStage;
+    use crate::unison::MAX_UNISON;
+
+    fn note(note: u8) -> NoteId {
+        NoteId { note, channel: 0, voice_id: None }
+    }
+
+    fn synth(voice_count: usize, voice_stealing: VoiceStealing) -> PolySynth {
+        let mut synth = PolySynth::new(48000, voice_count);
+        synth.set_voice_stealing(voice_stealing);
+        synth
+    }
+
+    fn render(synth: &mut PolySynth, samples: usize) {
+        for _ in 0..samples {
+            synth.next_sample();
+        }
+    }
+
+    fn active_notes(synth: &PolySynth) -> Vec<u8> {
+        let mut notes: Vec<u8> = synth.voices.iter().filter(|v| v.active).map(|v| v.note_id.note).collect();
+        notes.sort();
+        notes
+    }
+
+    /// Notes still sounding at full strength, i.e. not yet released.
+    fn sounding_notes(synth: &PolySynth) -> Vec<u8> {
+        let mut notes: Vec<u8> = synth
+            .voices
+            .iter()
+            .filter(|v| v.active && !v.env.is_released())
+            .map(|v| v.note_id.note)
+            .collect();
+        notes.sort();
+        notes
+    }
+
+    #[derive(Clone, Copy)]
+    enum Event {
+        On(u8),
+        Off(u8),
+        Sustain(bool),
+        Sostenuto(bool),
+    }
+
+    /// Plays `events` into the synth as the host would, with a little audio rendered after each.
+    fn play_events(synth: &mut PolySynth, events: &[Event]) {
+        for event in events {
+            match *event {
+                Event::On(n) => synth.play(note(n), 1.0),
+                Event::Off(n) => synth.stop(note(n)),
+                Event::Sustain(down) => synth.set_sustain_pedal(down),
+                Event::Sostenuto(down) => synth.set_sostenuto_pedal(down),
+            }
+            render(synth, 64);
+        }
+    }
+
+    /// Hands `process()` its events in buffers of `buffer_len` samples, the way a host would.
+    struct TestHost {
+        // Timed from the very start rather than the current buffer
+        events: Vec<(usize, SynthEvent)>,
+        buffer_start: usize,
+        buffer_len: usize,
+        terminated: Vec<(usize, NoteId)>,
+        // Sets the parameters for the given sample, timed from the very start
+        automation: fn(&mut PolySynth, usize),
+    }
+
+    impl SynthHost for TestHost {
+        fn next_event(&mut self) -> Option<(usize, SynthEvent)> {
+            let &(timing, _) = self.events.first()?;
+            if timing >= self.buffer_start + self.buffer_len {
+                return None;
+            }
+
+            let (timing, event) = self.events.remove(0);
+            Some((timing - self.buffer_start, event))
+        }
+
+        fn voice_terminated(&mut self, timing: usize, note_id: NoteId) {
+            self.terminated.push((self.buffer_start + timing, note_id));
+        }
+
+        fn update_voice_allocation(&mut self, _synth: &mut PolySynth) {}
+
+        fn update_params(&mut self, synth: &mut PolySynth, timing: usize) {
+            (self.automation)(synth, self.buffer_start + timing);
+        }
+    }
+
+    /// Renders `len` samples with `process()` in buffers of `buffer_len`, returning the output
+    /// and the voice terminations reported along the way.
+    fn process_in_buffers(
+        synth: &mut PolySynth,
+        events: &[(usize, SynthEvent)],
+        automation: fn(&mut PolySynth, usize),
+        len: usize,
+        buffer_len: usize,
+    ) -> (Vec<StereoSample>, Vec<(usize, NoteId)>) {
+        let mut host = TestHost {
+            events: events.to_vec(),
+            buffer_start: 0,
+            buffer_len: 0,
+            terminated: Vec::new(),
+            automation,
+        };
+        let mut output = vec![StereoSample::from_mono(0.0); len];
+
+        for buffer in output.chunks_mut(buffer_len) {
+            host.buffer_len = buffer.len();
+            synth.process(buffer, &mut host);
+            host.buffer_start += buffer.len();
+        }
+
+        (output, host.terminated)
+    }
+
+    fn assert_identical(actual: &[StereoSample], expected: &[StereoSample], context: &str) {
+        for (index, (a, b)) in actual.iter().zip(expected.iter()).enumerate() {
+            assert!(
+                a.left.to_bits() == b.left.to_bits() && a.right.to_bits() == b.right.to_bits(),
+                "{context}, sample {index}: {a:?} != {b:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn output_does_not_depend_on_buffer_size() {
+        use SynthEvent::*;
+        // Cloned for every buffer size, which mustn't lose the termination queue
+        let mut synth = synth(2, VoiceStealing::Oldest);
+        synth.set_glide(0.02);
+        synth.set_release(0.05);
+        // Vibrato on the mod wheel, so the wheel moving mid-note changes how voices render
+        synth.set_mod_source(0, ModSource::Lfo1);
+        synth.set_mod_destination(0, ModDestination::Pitch);
+        synth.set_mod_amount(0, 0.1);
+        synth.set_mod_via(0, ModSource::ModWheel);
+
+        let events = [
+            (10, NoteOn { note_id: note(60), velocity: 0.8 }),
+            (10, NoteOn { note_id: note(64), velocity: 0.5 }),
+            (700, PitchBend(0.5)),
+            (1111, ModWheel(0.7)),
+            // Steals the first voice
+            (1500, NoteOn { note_id: note(67), velocity: 1.0 }),
+            (2000, SustainPedal(true)),
+            (2345, NoteOff { note_id: note(64) }),
+            (2346, NoteOff { note_id: note(67) }),
+            (3003, SustainPedal(false)),
+            (3500, ModWheel(0.0)),
+            (4100, PitchBend(0.0)),
+        ];
+
+        let (expected, expected_terminated) = process_in_buffers(&mut synth.clone(), &events, |_, _| {}, 8000, 1);
+        assert_eq!(expected_terminated.len(), 3);
+
+        for buffer_len in [7, 32, 64, 100, 512] {
+            let (output, terminated) = process_in_buffers(&mut synth.clone(), &events, |_, _| {}, 8000, buffer_len);
+            assert_identical(&output, &expected, &format!("buffer size {buffer_len}"));
+            // Finished voices are only noticed at the end of a block, so only the notes have to match
+            let notes = |terminated: &[(usize, NoteId)]| -> Vec<NoteId> {
+                terminated.iter().map(|&(_, note_id)| note_id).collect()
+            };
+            assert_eq!(notes(&terminated), notes(&expected_terminated), "buffer size {buffer_len}");
+        }
+    }
+
+    #[test]
+    fn automation_does_not_depend_on_buffer_size() {
+        use SynthEvent::*;
+        let events = [
+            (0, NoteOn { note_id: note(48), velocity: 1.0 }),
+            (300, NoteOn { note_id: note(55), velocity: 0.7 }),
+            (5000, NoteOff { note_id: note(48) }),
+            (5000, NoteOff { note_id: note(55) }),
+        ];
+        // Sweeps as a smoothed parameter would, moving on with every update
+        let automation: fn(&mut PolySynth, usize) = |synth, time| {
+            let sweep = (time as f32 / 6000.0).min(1.0);
+            synth.set_filter_cutoff(200.0 + 4000.0 * sweep);
+            synth.set_filter_resonance(0.7 * sweep);
+            synth.set_pulse_width(0.5 - 0.4 * sweep);
+            synth.set_osc_waveform(0, Waveform::Square);
+            synth.set_osc_fine(0, 20.0 * sweep);
+            synth.set_release(0.01 + 0.05 * sweep);
+        };
+
+        let render = |buffer_len| {
+            let mut synth = synth(4, VoiceStealing::Oldest);
+            process_in_buffers(&mut synth, &events, automation, 8000, buffer_len).0
+        };
+
+        let expected = render(1);
+        for buffer_len in [32, 100, 512] {
+            assert_identical(&render(buffer_len), &expected, &format!("buffer size {buffer_len}"));
+        }
+    }
+
+    #[test]
+    fn notes_on_the_update_grid_start_with_that_blocks_parameters() {
+        // Key retrigger comes on with the update at the start of the second block, and decides
+        // whether a new phrase restarts the global LFO bending the pitch
+        let automation: fn(&mut PolySynth, usize) = |synth, time| {
+            synth.set_lfo_retrigger(0, time >= MAX_BLOCK_SIZE);
+        };
+        let render = |timing| {
+            let mut synth = synth(4, VoiceStealing::Oldest);
+            synth.set_lfo_mode(0, LfoMode::Global);
+            synth.set_lfo_rate(0, 5.0);
+            synth.set_mod_source(0, ModSource::Lfo1);
+            synth.set_mod_destination(0, ModDestination::Pitch);
+            synth.set_mod_amount(0, 0.1);
+            let events = [(timing, SynthEvent::NoteOn { note_id: note(60), velocity: 1.0 })];
+            process_in_buffers(&mut synth, &events, automation, 4000, 512).0
+        };
+
+        // A note right on the update plays just like one a sample later, bar the sample
+        let on_grid = render(MAX_BLOCK_SIZE);
+        let after_grid = render(MAX_BLOCK_SIZE + 1);
+        let on_grid = &on_grid[MAX_BLOCK_SIZE..];
+        assert_identical(&after_grid[MAX_BLOCK_SIZE + 1..], on_grid, "on the grid");
+    }
+
+    #[test]
+    fn events_outside_the_buffer_are_not_lost() {
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        synth.play(note(60), 1.0);
+        synth.play(note(64), 1.0);
+        let note_off = |note_id| SynthEvent::NoteOff { note_id };
+
+        // A note-off sent with an empty buffer
+        let mut host = TestHost {
+            events: vec![(0, note_off(note(60)))],
+            buffer_start: 0,
+            buffer_len: 1,
+            terminated: Vec::new(),
+            automation: |_, _| {},
+        };
+        synth.process(&mut [], &mut host);
+        assert_eq!(sounding_notes(&synth), vec![64]);
+
+        // One timed past the end of its buffer lands on the last sample
+        host.events.push((100, note_off(note(64))));
+        host.buffer_len = 1000;
+        synth.process(&mut [StereoSample::from_mono(0.0); 16], &mut host);
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+        assert!(host.events.is_empty());
+    }
+
+    #[test]
+    fn voice_count_limits_polyphony() {
+        let mut synth = synth(2, VoiceStealing::Oldest);
+        synth.play(note(60), 1.0);
+        synth.play(note(62), 1.0);
+        synth.play(note(64), 1.0);
+
+        assert_eq!(active_notes(&synth), vec![62, 64]);
+    }
+
+    #[test]
+    fn unison_is_scaled_down_to_the_voice_budget() {
+        let mut synth = synth(MAX_VOICES, VoiceStealing::Oldest);
+        synth.set_unison(MAX_UNISON);
+        assert_eq!(synth.unison() * MAX_VOICES, MAX_UNISON_VOICES);
+
+        // Fewer voices leave room for more copies each, up to the full count asked for
+        synth.set_voice_count(MAX_UNISON_VOICES / MAX_UNISON);
+        assert_eq!(synth.unison(), MAX_UNISON);
+        synth.set_voice_count(1);
+        assert_eq!(synth.unison(), MAX_UNISON);
+    }
+
+    #[test]
+    fn oldest_steals_first_triggered_voice() {
+        let mut synth = synth(3, VoiceStealing::Oldest);
+        synth.play(note(64), 1.0);
+        synth.play(note(60), 1.0);
+        synth.play(note(67), 1.0);
+        synth.drain_terminated().for_each(drop);
+
+        synth.play(note(72), 1.0);
+
+        assert_eq!(active_notes(&synth), vec![60, 67, 72]);
+        assert_eq!(synth.drain_terminated().collect::<Vec<_>>(), vec![note(64)]);
+    }
+
+    #[test]
+    fn quietest_steals_lowest_envelope_amplitude() {
+        let mut synth = synth(2, VoiceStealing::Quietest);
+        synth.set_attack(0.1);
+        synth.play(note(60), 1.0);
+        // Partway into the attack
+        render(&mut synth, 2400);
+        synth.play(note(62), 1.0);
+        render(&mut synth, 10);
+
+        synth.play(note(64), 1.0);
+
+        assert_eq!(active_notes(&synth), vec![60, 64]);
+    }
+
+    #[test]
+    fn released_first_prefers_released_voices() {
+        let mut synth = synth(2, VoiceStealing::ReleasedFirst);
+        synth.play(note(60), 1.0);
+        synth.play(note(62), 1.0);
+        synth.stop(note(62));
+
+        synth.play(note(64), 1.0);
+
+        assert_eq!(active_notes(&synth), vec![60, 64]);
+    }
+
+    #[test]
+    fn released_first_falls_back_to_oldest() {
+        let mut synth = synth(2, VoiceStealing::ReleasedFirst);
+        synth.play(note(62), 1.0);
+        synth.play(note(60), 1.0);
+
+        synth.play(note(64), 1.0);
+
+        assert_eq!(active_notes(&synth), vec![60, 64]);
+    }
+
+    #[test]
+    fn lowest_steals_lowest_note() {
+        let mut synth = synth(3, VoiceStealing::Lowest);
+        synth.play(note(64), 1.0);
+        synth.play(note(60), 1.0);
+        synth.play(note(67), 1.0);
+
+        synth.play(note(62), 1.0);
+
+        assert_eq!(active_notes(&synth), vec![62, 64, 67]);
+    }
+
+    #[test]
+    fn highest_steals_highest_note() {
+        let mut synth = synth(3, VoiceStealing::Highest);
+        synth.play(note(64), 1.0);
+        synth.play(note(72), 1.0);
+        synth.play(note(67), 1.0);
+
+        synth.play(note(62), 1.0);
+
+        assert_eq!(active_notes(&synth), vec![62, 64, 67]);
+    }
+
+    #[test]
+    fn same_note_retriggers_its_voice() {
+        let mut synth = synth(4, VoiceStealing::SameNote);
+        synth.play(note(60), 1.0);
+        synth.play(note(62), 1.0);
+        let index = synth.voices.iter().position(|v| v.note_id.note == 60).unwrap();
+
+        synth.play(note(60), 1.0);
+
+        assert_eq!(active_notes(&synth), vec![60, 62]);
+        assert_eq!(synth.voices[index].trigger_order, synth.trigger_counter);
+    }
+
+    #[test]
+    fn same_note_falls_back_to_oldest() {
+        let mut synth = synth(2, VoiceStealing::SameNote);
+        synth.play(note(60), 1.0);
+        synth.play(note(62), 1.0);
+
+        synth.play(note(64), 1.0);
+
+        assert_eq!(active_notes(&synth), vec![62, 64]);
+    }
+
+    #[test]
+    fn lowering_voice_count_releases_extra_voices() {
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        for n in [60, 62, 64, 65] {
+            synth.play(note(n), 1.0);
+        }
+
+        synth.set_voice_count(2);
+
+        assert!(synth.voices[..2].iter().all(|v| !v.env.is_released()));
+        assert!(synth.voices[2..4].iter().all(|v| v.env.is_released()));
+    }
+
+    #[test]
+    fn mono_plays_the_held_note_with_the_highest_priority() {
+        use Event::*;
+        for (priority, expected) in [(NotePriority::Last, 64), (NotePriority::Low, 60), (NotePriority::High, 67)] {
+            let mut synth = synth(4, VoiceStealing::Oldest);
+            synth.set_play_mode(PlayMode::Mono);
+            synth.set_note_priority(priority);
+            play_events(&mut synth, &[On(60), On(67), On(64)]);
+
+            assert_eq!(sounding_notes(&synth), vec![expected], "{priority:?}");
+        }
+    }
+
+    #[test]
+    fn mono_returns_to_the_previous_note_on_release() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        synth.set_play_mode(PlayMode::Mono);
+        play_events(&mut synth, &[On(60), On(64), On(67), Off(67)]);
+        assert_eq!(sounding_notes(&synth), vec![64]);
+
+        // Letting go of a note that isn't sounding changes nothing
+        play_events(&mut synth, &[Off(60)]);
+        assert_eq!(sounding_notes(&synth), vec![64]);
+
+        play_events(&mut synth, &[Off(64)]);
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn mono_retriggers_and_reports_the_cut_off_note() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        synth.set_play_mode(PlayMode::Mono);
+        play_events(&mut synth, &[On(60)]);
+        synth.play(note(64), 1.0);
+
+        assert_eq!(synth.voices[0].env.stage(), AdsrStage::Retrigger);
+        assert_eq!(synth.drain_terminated().collect::<Vec<_>>(), vec![note(60)]);
+    }
+
+    #[test]
+    fn legato_glides_on_the_same_voice_without_terminating_it() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        synth.set_play_mode(PlayMode::Legato);
+        play_events(&mut synth, &[On(60), On(64)]);
+
+        let voice = &synth.voices[0];
+        assert_ne!(voice.env.stage(), AdsrStage::Retrigger);
+        assert_eq!(voice.get_frequency(), midi_note_to_frequency(64));
+        assert_eq!(synth.drain_terminated().count(), 0);
+
+        // Back to the note still held, still without retriggering
+        play_events(&mut synth, &[Off(64)]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+        assert_ne!(synth.voices[0].env.stage(), AdsrStage::Retrigger);
+        assert_eq!(synth.drain_terminated().count(), 0);
+
+        // Only reported once the voice has actually finished
+        play_events(&mut synth, &[Off(60)]);
+        render(&mut synth, 48000);
+        assert_eq!(active_notes(&synth), Vec::<u8>::new());
+        assert_eq!(synth.drain_terminated().collect::<Vec<_>>(), vec![note(60)]);
+    }
+
+    #[test]
+    fn sustain_defers_note_offs_until_lifted() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        play_events(&mut synth, &[Sustain(true), On(60), On(64), Off(60), Off(64)]);
+        assert_eq!(sounding_notes(&synth), vec![60, 64]);
+
+        play_events(&mut synth, &[Sustain(false)]);
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+        assert_eq!(active_notes(&synth), vec![60, 64]);
+    }
+
+    #[test]
+    fn lifting_sustain_leaves_held_keys_sounding() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        play_events(&mut synth, &[Sustain(true), On(60), On(64), Off(60), Sustain(false)]);
+        assert_eq!(sounding_notes(&synth), vec![64]);
+
+        play_events(&mut synth, &[Off(64)]);
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn sostenuto_only_holds_notes_down_when_pressed() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        play_events(&mut synth, &[On(60), Sostenuto(true), On(64), Off(60), Off(64)]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+
+        play_events(&mut synth, &[Sostenuto(false)]);
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn repeated_sostenuto_press_latches_nothing_new() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        play_events(&mut synth, &[On(60), Sostenuto(true), On(64), Sostenuto(true), Off(60), Off(64)]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+    }
+
+    #[test]
+    fn sustain_and_sostenuto_together() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        play_events(&mut synth, &[On(60), Sostenuto(true), Sustain(true), On(64), Off(60), Off(64)]);
+        assert_eq!(sounding_notes(&synth), vec![60, 64]);
+
+        // The sostenuto pedal still has the first note
+        play_events(&mut synth, &[Sustain(false)]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+
+        play_events(&mut synth, &[Sostenuto(false)]);
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn repeated_notes_under_sustain_share_a_voice() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        play_events(&mut synth, &[Sustain(true), On(60), Off(60), On(60), Off(60), On(60), Off(60)]);
+        assert_eq!(active_notes(&synth), vec![60]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+
+        play_events(&mut synth, &[Sustain(false)]);
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn repeated_note_held_through_sustain_lift_keeps_sounding() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        play_events(&mut synth, &[Sustain(true), On(60), Off(60), On(60), Sustain(false)]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+
+        play_events(&mut synth, &[Off(60)]);
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn restruck_note_stays_latched_by_sostenuto() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        play_events(&mut synth, &[On(60), Sostenuto(true), Off(60), On(60), Off(60)]);
+        assert_eq!(active_notes(&synth), vec![60]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+    }
+
+    #[test]
+    fn stolen_voice_drops_its_sostenuto_latch() {
+        use Event::*;
+        let mut synth = synth(1, VoiceStealing::Oldest);
+        play_events(&mut synth, &[On(60), Sostenuto(true), On(62), Off(60), Off(62)]);
+
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+        assert_eq!(synth.drain_terminated().collect::<Vec<_>>(), vec![note(60)]);
+    }
+
+    #[test]
+    fn sustained_note_off_for_a_stolen_note_leaves_its_new_note_alone() {
+        use Event::*;
+        let mut synth = synth(1, VoiceStealing::Oldest);
+        play_events(&mut synth, &[Sustain(true), On(60), On(62), Off(60), Sustain(false)]);
+        assert_eq!(sounding_notes(&synth), vec![62]);
+    }
+
+    #[test]
+    fn released_first_steals_pedal_held_before_key_held() {
+        use Event::*;
+        let mut synth = synth(2, VoiceStealing::ReleasedFirst);
+        play_events(&mut synth, &[Sustain(true), On(62), On(60), Off(60), On(64)]);
+        assert_eq!(active_notes(&synth), vec![62, 64]);
+    }
+
+    #[test]
+    fn sustain_holds_the_last_mono_note() {
+        use Event::*;
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        synth.set_play_mode(PlayMode::Mono);
+        play_events(&mut synth, &[Sustain(true), On(60), Off(60)]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+
+        play_events(&mut synth, &[On(60), Sustain(false)]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+
+        play_events(&mut synth, &[Off(60)]);
+        assert_eq!(sounding_notes(&synth), Vec::<u8>::new());
+    }
+
+    /// How far the sounding note's pitch is from `reference`, in semitones.
+    fn semitones_from(synth: &PolySynth, reference: u8) -> f32 {
+        let voice = synth.voices.iter().find(|v| v.active).expect("a sounding voice");
+        12.0 * (voice.mixer.frequency / midi_note_to_frequency(reference)).log2()
+    }
+
+    #[test]
+    fn pitch_bend_reaches_the_bend_range() {
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        synth.set_glide(0.0);
+        synth.set_pitch_bend_range(7.0);
+        synth.play(note(60), 1.0);
+
+        synth.set_pitch_bend(1.0);
+        render(&mut synth, 4800);
+        assert!((semitones_from(&synth, 60) - 7.0).abs() < 1e-3, "{}", semitones_from(&synth, 60));
+
+        synth.set_pitch_bend(-0.5);
+        render(&mut synth, 4800);
+        assert!((semitones_from(&synth, 60) + 3.5).abs() < 1e-3, "{}", semitones_from(&synth, 60));
+
+        // Changing the range moves a wheel that's already bent
+        synth.set_pitch_bend_range(24.0);
+        render(&mut synth, 4800);
+        assert!((semitones_from(&synth, 60) + 12.0).abs() < 1e-3, "{}", semitones_from(&synth, 60));
+
+        // A new note starts out at the wheel's bend rather than gliding there
+        synth.play(note(64), 1.0);
+        synth.next_sample();
+        let voice = synth.voices.iter().find(|v| v.note_id.note == 64).unwrap();
+        let bent = 12.0 * (voice.mixer.frequency / midi_note_to_frequency(64)).log2();
+        assert!((bent + 12.0).abs() < 1e-3, "{bent}");
+    }
+
+    #[test]
+    fn pitch_bend_is_smoothed_onto_the_wheel() {
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        synth.set_glide(0.0);
+        synth.play(note(60), 1.0);
+        render(&mut synth, 64);
+
+        // Rising steadily towards the full two semitones without overshooting, about two thirds
+        // of the way there after the smoothing time
+        synth.set_pitch_bend(1.0);
+        let mut bends = Vec::new();
+        for _ in 0..4800 {
+            synth.next_sample();
+            bends.push(semitones_from(&synth, 60));
+        }
+        assert!(bends[0] > 0.0 && bends[0] < 0.01, "{}", bends[0]);
+        assert!(bends.windows(2).all(|pair| pair[1] >= pair[0] && pair[1] <= 2.0));
+        // The voices' 10 ms smoothing time
+        let after_smoothing = bends[479] / 2.0;
+        assert!((after_smoothing - 0.632).abs() < 0.01, "{after_smoothing}");
+
+        // Landing exactly on the bent pitch, rather than creeping ever closer
+        let voice = synth.voices.iter().find(|v| v.active).unwrap();
+        assert_eq!(voice.mixer.frequency, midi_note_to_frequency(60) * (2.0f32 / 12.0).exp2());
+    }
+
+    #[test]
+    fn pitch_bend_stacks_on_a_glide() {
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        synth.set_play_mode(PlayMode::Legato);
+        synth.set_glide(0.5);
+        synth.play(note(60), 1.0);
+        render(&mut synth, 64);
+
+        // Bending partway through the glide up an octave, against the same glide left unbent
+        synth.play(note(72), 1.0);
+        render(&mut synth, 4800);
+        let mut unbent = PolySynth::new(48000, 4);
+        unbent.set_play_mode(PlayMode::Legato);
+        unbent.set_glide(0.5);
+        unbent.play(note(60), 1.0);
+        render(&mut unbent, 64);
+        unbent.play(note(72), 1.0);
+        render(&mut unbent, 4800);
+
+        synth.set_pitch_bend(-1.0);
+        for _ in 0..4800 {
+            synth.next_sample();
+            unbent.next_sample();
+        }
+        // Still gliding, two semitones below wherever the glide has got to
+        let gliding = semitones_from(&unbent, 60);
+        assert!(gliding > 1.0 && gliding < 11.0, "{gliding}");
+        let bend = semitones_from(&synth, 60) - gliding;
+        assert!((bend + 2.0).abs() < 1e-3, "{bend}");
+
+        // And two below the new note once the glide has finished
+        render(&mut synth, 24000);
+        assert!((semitones_from(&synth, 72) + 2.0).abs() < 1e-3, "{}", semitones_from(&synth, 72));
+    }
+
+    #[test]
+    fn channel_pressure_drives_the_aftertouch_source_for_every_note() {
+        // Full pressure turns the level right down
+        let mut synth = synth(4, VoiceStealing::Oldest);
+        synth.set_mod_source(0, ModSource::Aftertouch);
+        synth.set_mod_destination(0, ModDestination::Level);
+        synth.set_mod_amount(0, -1.0);
+        let peak = |synth: &mut PolySynth| {
+            (0..480).map(|_| synth.next_sample().left.abs()).fold(0.0, f32::max)
+        };
+
+        synth.play(note(60), 1.0);
+        assert!(peak(&mut synth) > 0.1);
+        synth.handle_event(SynthEvent::ChannelPressure(1.0));
+        assert_eq!(peak(&mut synth), 0.0);
+
+        // Notes played while the channel is pressed follow it too
+        synth.play(note(64), 1.0);
+        assert_eq!(peak(&mut synth), 0.0);
+
+        // Either pressure is enough, whichever is pressed harder counts
+        synth.handle_event(SynthEvent::ChannelPressure(0.0));
+        synth.handle_event(SynthEvent::PolyPressure { note_id: note(60), pressure: 1.0 });
+        synth.handle_event(SynthEvent::PolyPressure { note_id: note(64), pressure: 1.0 });
+        assert_eq!(peak(&mut synth), 0.0);
+        synth.handle_event(SynthEvent::PolyPressure { note_id: note(64), pressure: 0.0 });
+        assert!(peak(&mut synth) > 0.1);
+    }
+}
@@ -27,6 +27,11 @@ impl RampEnvelope {
         self.ramp_time_s = ramp_time_s;
     }
 
+    /// Returns `true` once the ramp has reached its end and stays there.
+    pub fn is_finished(&self) -> bool {
+        self.current_time_s >= self.ramp_time_s
+    }
+
     fn get_amount(&self) -> f32 {
         (self.current_time_s / self.ramp_time_s).min(1.0)
     }
@@ -42,4 +47,9 @@ impl AudioProcessor for RampEnvelope {
 
         StereoSample { left: input.left * amount, right: input.right * amount }
     }
+
+    fn reset(&mut self) {
+        // Same as a freshly created envelope, the ramp is already complete.
+        self.current_time_s = 1.0;
+    }
 }
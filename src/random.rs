@@ -0,0 +1,74 @@
+/// A small, fast xorshift PRNG for the audio thread. Never allocates, and the same seed always
+/// gives the same numbers.
+#[derive(Clone)]
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    pub fn new(seed: u32) -> Self {
+        // Xorshift is linear, so seeds close together (1, 2, 3...) would give related sequences.
+        // Scrambling the seed first makes every seed start somewhere unrelated.
+        let mut state = seed.wrapping_add(0x9E37_79B9);
+        state = (state ^ (state >> 16)).wrapping_mul(0x85EB_CA6B);
+        state = (state ^ (state >> 13)).wrapping_mul(0xC2B2_AE35);
+        state ^= state >> 16;
+
+        Self {
+            // Xorshift gets stuck on zero
+            state: state.max(1),
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    /// A random number in [0..1).
+    pub fn next_f32(&mut self) -> f32 {
+        // Top 24 bits, as many as an f32 can hold exactly
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+
+    /// A random number in [-1..1).
+    pub fn next_bipolar(&mut self) -> f32 {
+        2.0 * self.next_f32() - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(seed: u32) -> Vec<u32> {
+        let mut random = Random::new(seed);
+        (0..1000).map(|_| random.next_u32()).collect()
+    }
+
+    #[test]
+    fn the_seed_decides_the_numbers() {
+        assert_eq!(numbers(1), numbers(1));
+
+        // Neighbouring seeds give unrelated numbers, sharing hardly any bits
+        for seed in 0..16 {
+            let (a, b) = (numbers(seed), numbers(seed + 1));
+            assert_ne!(a, b, "seed {seed}");
+            let matching_bits: u32 =
+                a.iter().zip(b.iter()).map(|(a, b)| (!(a ^ b)).count_ones()).sum();
+            let fraction = matching_bits as f32 / (32.0 * a.len() as f32);
+            assert!((fraction - 0.5).abs() < 0.02, "seed {seed}: {fraction}");
+        }
+    }
+
+    #[test]
+    fn floats_stay_in_range() {
+        let mut random = Random::new(7);
+        for _ in 0..100_000 {
+            assert!((0.0..1.0).contains(&random.next_f32()));
+            assert!((-1.0..1.0).contains(&random.next_bipolar()));
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::poly_blep::{self, Discontinuity, HardSync, OscillatorQuality};
 use crate::{stereo_sample::StereoSample, traits::AudioSource};
 
 #[derive(Clone)]
@@ -5,6 +6,10 @@ pub struct SawWave {
     phase: f32,
     pub frequency: f32,
     sample_rate: u32,
+    quality: OscillatorQuality,
+    sync: HardSync,
+    // Where the phase wraps after the next sample, for syncing other oscillators to this one
+    next_wrap: Option<f32>,
 }
 
 impl SawWave {
@@ -14,29 +19,76 @@ impl SawWave {
             phase: 0.0,
             frequency: freq.min(sample_rate as f32 / 2.0),
             sample_rate,
+            quality: OscillatorQuality::High,
+            sync: HardSync::default(),
+            next_wrap: None,
         }
     }
-}
 
-impl AudioSource for SawWave {
-    fn next_sample(&mut self) -> StereoSample {
+    pub fn set_quality(&mut self, quality: OscillatorQuality) {
+        self.quality = quality;
+    }
+
+    fn render(&mut self, increment: f32) -> f32 {
         // The saw wave can be defined as going linearly from -1.0 to +1.0 over one period [0..1].
         // We'll map self.phase in [0..1] to the saw wave range of [-1..1].
-        let sample = 2.0 * self.phase - 1.0;
+        let mut sample = 2.0 * self.phase - 1.0;
+
+        // Smooth out the drop from +1.0 back to -1.0, a step of -2.0
+        sample -= 2.0 * poly_blep::blep(self.phase, increment, self.quality);
 
-        // Increment the phase by frequency / sample_rate.
-        self.phase += self.frequency / self.sample_rate as f32;
+        let drop = Discontinuity { phase: 0.0, step: -2.0, bend: 0.0 };
+        let (sample, restart) =
+            self.sync.process(sample, self.phase, increment, self.quality, &[drop], |phase| (2.0 * phase - 1.0, 2.0));
 
-        // Wrap phase if it goes beyond 1.0
-        if self.phase >= 1.0 {
-            self.phase -= 1.0;
+        // Increment the phase by frequency / sample_rate, unless a hard sync restarted it.
+        match restart {
+            Some(phase) => self.phase = phase,
+            None => poly_blep::advance_phase(&mut self.phase, increment),
         }
+        self.next_wrap = poly_blep::next_wrap(self.phase, increment);
+
+        sample
+    }
+}
+
+impl AudioSource for SawWave {
+    fn next_sample(&mut self) -> StereoSample {
+        let sample = self.render(self.frequency / self.sample_rate as f32);
 
         StereoSample { left: sample, right: sample }
     }
 
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        // The frequency can't change mid-block, so the increment only needs working out once
+        let increment = self.frequency / self.sample_rate as f32;
+
+        for out in block.iter_mut() {
+            let sample = self.render(increment);
+            *out = StereoSample { left: sample, right: sample };
+        }
+    }
+
     fn set_frequency(&mut self, freq: f32) {
         // Limit to Nyquist frequency (half the sample rate).
         self.frequency = freq.min(self.sample_rate as f32 / 2.0);
     }
+
+    fn set_phase(&mut self, phase: f32) {
+        self.phase = phase.rem_euclid(1.0);
+    }
+
+    fn sync(&mut self, fraction: f32) {
+        self.sync.trigger(fraction);
+    }
+
+    fn next_wrap(&self) -> Option<f32> {
+        self.next_wrap
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.sync = HardSync::default();
+        self.next_wrap = None;
+    }
 }
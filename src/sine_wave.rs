@@ -1,3 +1,4 @@
+use crate::poly_blep::{self, HardSync, OscillatorQuality};
 use crate::{stereo_sample::StereoSample, traits::AudioSource};
 
 #[derive(Clone)]
@@ -5,6 +6,9 @@ pub struct SineWave {
     phase: f32,
     pub frequency: f32,
     sample_rate: u32,
+    sync: HardSync,
+    // Where the phase wraps after the next sample, for syncing other oscillators to this one
+    next_wrap: Option<f32>,
 }
 
 impl SineWave {
@@ -13,31 +17,85 @@ impl SineWave {
             phase: 0.0,
             frequency: freq,
             sample_rate,
+            sync: HardSync::default(),
+            next_wrap: None,
         }
     }
-}
 
-impl AudioSource for SineWave {
-    fn next_sample(&mut self) -> StereoSample {
+    fn render(&mut self, increment: f32) -> f32 {
         use std::f32::consts::PI;
-        
+
         // Calculate the sample based on current phase in [0..1]
         let sample = (2.0 * PI * self.phase).sin();
 
-        // Increment phase by freq / sample_rate
-        self.phase += self.frequency / self.sample_rate as f32;
+        // The sine itself never jumps, only a sync restart can make it
+        let naive = |phase: f32| ((2.0 * PI * phase).sin(), 2.0 * PI * (2.0 * PI * phase).cos());
+        let (sample, restart) = self.sync.process(sample, self.phase, increment, OscillatorQuality::High, &[], naive);
+
+        // Increment the phase by frequency / sample_rate, unless a hard sync restarted it.
+        match restart {
+            Some(phase) => self.phase = phase,
+            None => poly_blep::advance_phase(&mut self.phase, increment),
+        }
+        self.next_wrap = poly_blep::next_wrap(self.phase, increment);
+
+        sample
+    }
+
+    /// Like `next_sample()`, but with the phase pushed on by `phase_offset` cycles for this
+    /// sample only. This is what phase modulation (FM) drives.
+    pub fn next_modulated(&mut self, phase_offset: f32) -> f32 {
+        use std::f32::consts::PI;
+
+        let sample = (2.0 * PI * (self.phase + phase_offset)).sin();
 
-        // Wrap phase if it goes beyond 1.0
+        self.phase += self.frequency / self.sample_rate as f32;
         if self.phase >= 1.0 {
             self.phase -= 1.0;
         }
 
+        sample
+    }
+}
+
+impl AudioSource for SineWave {
+    fn next_sample(&mut self) -> StereoSample {
+        let sample = self.render(self.frequency / self.sample_rate as f32);
+
         StereoSample { left: sample, right: sample }
     }
 
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        // The frequency can't change mid-block, so the increment only needs working out once
+        let increment = self.frequency / self.sample_rate as f32;
+
+        for out in block.iter_mut() {
+            let sample = self.render(increment);
+            *out = StereoSample { left: sample, right: sample };
+        }
+    }
+
     fn set_frequency(&mut self, freq: f32) {
         // To account for Nyquist theorem.
         // (The highest frequency component of a signal that can be accurately digitized is half the sampling rate)
         self.frequency = freq.min(self.sample_rate as f32 / 2.0);
     }
+
+    fn set_phase(&mut self, phase: f32) {
+        self.phase = phase.rem_euclid(1.0);
+    }
+
+    fn sync(&mut self, fraction: f32) {
+        self.sync.trigger(fraction);
+    }
+
+    fn next_wrap(&self) -> Option<f32> {
+        self.next_wrap
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.sync = HardSync::default();
+        self.next_wrap = None;
+    }
 }
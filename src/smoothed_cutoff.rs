@@ -0,0 +1,85 @@
+// The cutoff is kept this far below Nyquist, where filter coefficients head to infinity.
+const MAX_CUTOFF_RATIO: f32 = 0.49;
+const MIN_CUTOFF_HZ: f32 = 10.0;
+
+// How quickly the cutoff catches up with a new value.
+const CUTOFF_SMOOTHING_S: f32 = 0.002;
+
+/// A filter cutoff that glides to new values over a couple of milliseconds rather than jumping.
+/// Smoothing happens in log2(Hz), so sweeps move at the same speed in every octave.
+///
+/// Modulation (envelopes, key tracking...) is added on top in octaves and isn't smoothed, so it
+/// can move as fast as its source does.
+#[derive(Clone)]
+pub struct SmoothedCutoff {
+    // Where the cutoff is heading, and where it is right now, as log2(Hz)
+    target: f32,
+    current: f32,
+    // In octaves
+    modulation: f32,
+    modulation_changed: bool,
+    // The range the modulated cutoff is kept in, as log2(Hz)
+    min: f32,
+    max: f32,
+    // Per sample smoothing coefficient
+    smoothing: f32,
+}
+
+impl SmoothedCutoff {
+    pub fn new(sample_rate: f32, cutoff_hz: f32) -> Self {
+        let mut cutoff = Self {
+            target: 0.0,
+            current: 0.0,
+            modulation: 0.0,
+            modulation_changed: false,
+            min: MIN_CUTOFF_HZ.log2(),
+            max: (sample_rate * MAX_CUTOFF_RATIO).log2(),
+            smoothing: 1.0 - (-1.0 / (CUTOFF_SMOOTHING_S * sample_rate)).exp(),
+        };
+        cutoff.set(cutoff_hz);
+        cutoff.current = cutoff.target;
+        cutoff
+    }
+
+    /// Sets where the cutoff is heading, in Hz, kept between 10 Hz and just below Nyquist.
+    pub fn set(&mut self, cutoff_hz: f32) {
+        self.target = cutoff_hz.log2().clamp(self.min, self.max);
+    }
+
+    /// Moves the cutoff `octaves` away from where it's been set to, straight away.
+    pub fn set_modulation(&mut self, octaves: f32) {
+        if octaves != self.modulation {
+            self.modulation = octaves;
+            self.modulation_changed = true;
+        }
+    }
+
+    /// The cutoff right now, modulation included, in Hz.
+    pub fn hz(&self) -> f32 {
+        (self.current + self.modulation).clamp(self.min, self.max).exp2()
+    }
+
+    /// Moves the cutoff one sample closer to its target. Returns `true` if it or the modulation
+    /// moved, meaning any coefficients worked out from it need updating.
+    pub fn step(&mut self) -> bool {
+        let modulation_changed = std::mem::take(&mut self.modulation_changed);
+
+        let distance = self.target - self.current;
+        if distance == 0.0 {
+            return modulation_changed;
+        }
+
+        // Close enough to be inaudible, so land on it and stop moving
+        if distance.abs() < 1e-4 {
+            self.current = self.target;
+        } else {
+            self.current += distance * self.smoothing;
+        }
+        true
+    }
+
+    /// Jumps straight to the target.
+    pub fn snap(&mut self) {
+        self.current = self.target;
+    }
+}
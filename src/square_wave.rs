@@ -1,10 +1,21 @@
+use crate::poly_blep::{self, Discontinuity, HardSync, OscillatorQuality};
 use crate::{stereo_sample::StereoSample, traits::AudioSource};
 
+// Any narrower and the pulse all but disappears.
+const MIN_PULSE_WIDTH: f32 = 0.01;
+const MAX_PULSE_WIDTH: f32 = 0.99;
+
 #[derive(Clone)]
 pub struct SquareWave {
     phase: f32,
     pub frequency: f32,
     sample_rate: u32,
+    quality: OscillatorQuality,
+    // The part of the cycle spent high (0.5 = square)
+    pulse_width: f32,
+    sync: HardSync,
+    // Where the phase wraps after the next sample, for syncing other oscillators to this one
+    next_wrap: Option<f32>,
 }
 
 impl SquareWave {
@@ -14,33 +25,121 @@ impl SquareWave {
             phase: 0.0,
             frequency: freq.min(sample_rate as f32 / 2.0),
             sample_rate,
+            quality: OscillatorQuality::High,
+            pulse_width: 0.5,
+            sync: HardSync::default(),
+            next_wrap: None,
         }
     }
-}
 
-impl AudioSource for SquareWave {
-    fn next_sample(&mut self) -> StereoSample {
-        // If phase is in the first half of [0..1), output +1.0.
+    pub fn set_quality(&mut self, quality: OscillatorQuality) {
+        self.quality = quality;
+    }
+
+    /// Sets the part of each cycle spent high, clamped to 1%..99%. Cheap enough to call every
+    /// sample for audio rate PWM.
+    pub fn set_pulse_width(&mut self, pulse_width: f32) {
+        self.pulse_width = pulse_width.clamp(MIN_PULSE_WIDTH, MAX_PULSE_WIDTH);
+    }
+
+    fn render(&mut self, increment: f32) -> f32 {
+        // If phase is in the first `pulse_width` of [0..1), output +1.0.
         // Otherwise, output -1.0.
-        let sample = if self.phase < 0.5 { 
+        let mut sample = if self.phase < self.pulse_width { 
             1.0 
         } else { 
             -1.0 
         };
 
-        // Increment the phase.
-        self.phase += self.frequency / self.sample_rate as f32;
+        // Smooth out the rise at the start of the cycle (+2.0) and the fall at the pulse width (-2.0).
+        // The falling edge is corrected wherever it currently is, so PWM sweeps stay band-limited.
+        sample += 2.0 * poly_blep::blep(self.phase, increment, self.quality);
+        sample -= 2.0 * poly_blep::blep(poly_blep::wrap_phase(self.phase - self.pulse_width), increment, self.quality);
+
+        // Narrow pulses sit mostly low, remove that DC offset so sweeping the width doesn't
+        // shift the whole waveform up and down
+        sample -= 2.0 * self.pulse_width - 1.0;
 
-        // Wrap phase back to [0..1).
-        if self.phase >= 1.0 {
-            self.phase -= 1.0;
+        let edges = [
+            Discontinuity { phase: 0.0, step: 2.0, bend: 0.0 },
+            Discontinuity { phase: self.pulse_width, step: -2.0, bend: 0.0 },
+        ];
+        let (sample, restart) =
+            self.sync.process(sample, self.phase, increment, self.quality, &edges, |phase| (if phase < self.pulse_width { 1.0 } else { -1.0 }, 0.0));
+
+        // Increment the phase by frequency / sample_rate, unless a hard sync restarted it.
+        match restart {
+            Some(phase) => self.phase = phase,
+            None => poly_blep::advance_phase(&mut self.phase, increment),
         }
+        self.next_wrap = poly_blep::next_wrap(self.phase, increment);
+
+        sample
+    }
+}
+
+impl AudioSource for SquareWave {
+    fn next_sample(&mut self) -> StereoSample {
+        let sample = self.render(self.frequency / self.sample_rate as f32);
 
         StereoSample { left: sample, right: sample }
     }
 
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        // The frequency can't change mid-block, so the increment only needs working out once
+        let increment = self.frequency / self.sample_rate as f32;
+
+        for out in block.iter_mut() {
+            let sample = self.render(increment);
+            *out = StereoSample { left: sample, right: sample };
+        }
+    }
+
     fn set_frequency(&mut self, freq: f32) {
         // Limit to the Nyquist frequency.
         self.frequency = freq.min(self.sample_rate as f32 / 2.0);
     }
+
+    fn set_phase(&mut self, phase: f32) {
+        self.phase = phase.rem_euclid(1.0);
+    }
+
+    fn sync(&mut self, fraction: f32) {
+        self.sync.trigger(fraction);
+    }
+
+    fn next_wrap(&self) -> Option<f32> {
+        self.next_wrap
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.sync = HardSync::default();
+        self.next_wrap = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_pulse_width_is_centred_on_zero() {
+        // A whole number of cycles in the block, so any offset is down to the waveform itself
+        const SAMPLES: usize = 4096;
+        let frequency = 101.0 * 48000.0 / SAMPLES as f32;
+
+        for quality in [OscillatorQuality::Standard, OscillatorQuality::High] {
+            for width in [0.01, 0.05, 0.2, 0.5, 0.8, 0.95, 0.99] {
+                let mut osc = SquareWave::new(48000, frequency);
+                osc.set_quality(quality);
+                osc.set_pulse_width(width);
+                osc.next_sample();
+
+                let sum: f32 = (0..SAMPLES).map(|_| osc.next_sample().left).sum();
+                let mean = sum / SAMPLES as f32;
+                assert!(mean.abs() < 1e-3, "{quality:?}, width {width}: {mean}");
+            }
+        }
+    }
 }
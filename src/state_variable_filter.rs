@@ -0,0 +1,244 @@
+use nih_plug::prelude::Enum;
+
+use crate::smoothed_cutoff::SmoothedCutoff;
+use crate::{stereo_sample::StereoSample, traits::AudioProcessor};
+
+// Resonance 0 gives a Butterworth response, no peak at the cutoff (k = 1/Q = sqrt(2)).
+const BUTTERWORTH_DAMPING: f32 = std::f32::consts::SQRT_2;
+
+// Damping never gets quite to zero, so full resonance rings for a long time without blowing up.
+const MIN_DAMPING: f32 = 0.01;
+
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FilterMode {
+    #[id = "lowpass"]
+    #[name = "Low Pass"]
+    LowPass,
+    #[id = "highpass"]
+    #[name = "High Pass"]
+    HighPass,
+    #[id = "bandpass"]
+    #[name = "Band Pass"]
+    BandPass,
+    #[id = "notch"]
+    Notch,
+}
+
+/// The integrator states for one channel.
+#[derive(Clone, Copy, Default)]
+struct SvfState {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+/// A 12 dB/octave zero-delay-feedback state-variable filter, after Andrew Simper's trapezoidal
+/// SVF. Solving the feedback loop exactly rather than with a sample of delay keeps it stable at
+/// any cutoff, resonance and sample rate.
+#[derive(Clone)]
+pub struct StateVariableFilter {
+    mode: FilterMode,
+    cutoff: SmoothedCutoff,
+    resonance: f32,
+    // Added to `resonance`, without smoothing
+    resonance_modulation: f32,
+    sample_rate: f32,
+
+    // Coefficients, only worked out again when the cutoff or resonance moves
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+
+    left: SvfState,
+    right: SvfState,
+}
+
+impl StateVariableFilter {
+    pub fn new(sample_rate: f32, cutoff_hz: f32, resonance: f32) -> Self {
+        let mut filter = Self {
+            mode: FilterMode::LowPass,
+            cutoff: SmoothedCutoff::new(sample_rate, cutoff_hz),
+            resonance: 0.0,
+            resonance_modulation: 0.0,
+            sample_rate,
+            k: BUTTERWORTH_DAMPING,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            left: SvfState::default(),
+            right: SvfState::default(),
+        };
+        filter.set_resonance(resonance);
+        filter
+    }
+
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    /// Sets the cutoff frequency in Hz. The filter glides there over a couple of milliseconds
+    /// rather than jumping.
+    pub fn set_cutoff(&mut self, cutoff_hz: f32) {
+        self.cutoff.set(cutoff_hz);
+    }
+
+    /// The cutoff right now, modulation included, in Hz.
+    pub fn cutoff_hz(&self) -> f32 {
+        self.cutoff.hz()
+    }
+
+    /// Moves the cutoff `octaves` away from the one set by `set_cutoff()`, without smoothing.
+    pub fn set_cutoff_modulation(&mut self, octaves: f32) {
+        self.cutoff.set_modulation(octaves);
+    }
+
+    /// Sets the resonance (0 = none, 1 = ringing on the edge of self-oscillation).
+    pub fn set_resonance(&mut self, resonance: f32) {
+        self.resonance = resonance;
+        self.update_coefficients();
+    }
+
+    /// Moves the resonance `amount` away from the one set by `set_resonance()`.
+    pub fn set_resonance_modulation(&mut self, amount: f32) {
+        if amount != self.resonance_modulation {
+            self.resonance_modulation = amount;
+            self.update_coefficients();
+        }
+    }
+
+    fn update_coefficients(&mut self) {
+        let resonance = (self.resonance + self.resonance_modulation).clamp(0.0, 1.0);
+        self.k = (BUTTERWORTH_DAMPING * (1.0 - resonance)).max(MIN_DAMPING);
+
+        let g = (std::f32::consts::PI * self.cutoff.hz() / self.sample_rate).tan();
+        self.a1 = 1.0 / (1.0 + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+
+    fn tick(&self, state: &mut SvfState, input: f32) -> f32 {
+        let v3 = input - state.ic2eq;
+        let v1 = self.a1 * state.ic1eq + self.a2 * v3;
+        let v2 = state.ic2eq + self.a2 * state.ic1eq + self.a3 * v3;
+        state.ic1eq = 2.0 * v1 - state.ic1eq;
+        state.ic2eq = 2.0 * v2 - state.ic2eq;
+
+        match self.mode {
+            FilterMode::LowPass => v2,
+            FilterMode::HighPass => input - self.k * v1 - v2,
+            FilterMode::BandPass => v1,
+            FilterMode::Notch => input - self.k * v1,
+        }
+    }
+}
+
+impl AudioProcessor for StateVariableFilter {
+    fn process_sample(&mut self, input: StereoSample) -> StereoSample {
+        if self.cutoff.step() {
+            self.update_coefficients();
+        }
+
+        let mut left = self.left;
+        let mut right = self.right;
+        let output = StereoSample {
+            left: self.tick(&mut left, input.left),
+            right: self.tick(&mut right, input.right),
+        };
+        self.left = left;
+        self.right = right;
+
+        output
+    }
+
+    fn reset(&mut self) {
+        self.left = SvfState::default();
+        self.right = SvfState::default();
+        self.cutoff.snap();
+        self.update_coefficients();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Random;
+
+    const SAMPLE_RATES: [f32; 4] = [44100.0, 48000.0, 96000.0, 192000.0];
+    const MODES: [FilterMode; 4] =
+        [FilterMode::LowPass, FilterMode::HighPass, FilterMode::BandPass, FilterMode::Notch];
+
+    /// The filter's gain for a sine at `freq`, once it has settled, measured from the peaks.
+    fn gain(mode: FilterMode, cutoff_hz: f32, freq: f32) -> f32 {
+        let sample_rate = 48000.0;
+        let mut filter = StateVariableFilter::new(sample_rate, cutoff_hz, 0.0);
+        filter.set_mode(mode);
+
+        let mut peak: f32 = 0.0;
+        for index in 0..sample_rate as usize {
+            let phase = std::f32::consts::TAU * freq * index as f32 / sample_rate;
+            let output = filter.process_sample(StereoSample::from_mono(phase.sin())).left;
+            // Skip the first half second, while it settles
+            if index as f32 > sample_rate / 2.0 {
+                peak = peak.max(output.abs());
+            }
+        }
+        peak
+    }
+
+    fn db(gain: f32) -> f32 {
+        20.0 * gain.log10()
+    }
+
+    #[test]
+    fn stays_stable_under_extreme_settings() {
+        for sample_rate in SAMPLE_RATES {
+            for mode in MODES {
+                let mut filter = StateVariableFilter::new(sample_rate, 20000.0, 1.0);
+                filter.set_mode(mode);
+                let mut random = Random::new(1);
+
+                // Loud noise while the cutoff jumps between the bottom and the top of its range.
+                // Full resonance has a Q of 100, so the output can ring well above the input, it
+                // just mustn't keep growing.
+                for index in 0..sample_rate as usize {
+                    if index % 512 == 0 {
+                        filter.set_cutoff(if index % 1024 == 0 { 20.0 } else { 1e6 });
+                    }
+                    let input = StereoSample::from_mono(4.0 * random.next_bipolar());
+                    let output = filter.process_sample(input);
+                    assert!(output.left.is_finite() && output.left.abs() < 400.0, "{mode:?}");
+                    assert!(output.right.is_finite() && output.right.abs() < 400.0, "{mode:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn low_pass_response() {
+        assert!(db(gain(FilterMode::LowPass, 1000.0, 100.0)).abs() < 0.1);
+        assert!((db(gain(FilterMode::LowPass, 1000.0, 1000.0)) + 3.0).abs() < 0.2);
+        // 12 dB per octave, a little more as Nyquist squashes the top octaves
+        assert!(db(gain(FilterMode::LowPass, 1000.0, 8000.0)) < -35.0);
+    }
+
+    #[test]
+    fn high_pass_response() {
+        assert!(db(gain(FilterMode::HighPass, 1000.0, 10000.0)).abs() < 0.1);
+        assert!((db(gain(FilterMode::HighPass, 1000.0, 1000.0)) + 3.0).abs() < 0.2);
+        assert!(db(gain(FilterMode::HighPass, 1000.0, 125.0)) < -35.0);
+    }
+
+    #[test]
+    fn band_pass_response() {
+        let peak = gain(FilterMode::BandPass, 1000.0, 1000.0);
+        assert!(db(gain(FilterMode::BandPass, 1000.0, 100.0)) < db(peak) - 15.0);
+        assert!(db(gain(FilterMode::BandPass, 1000.0, 10000.0)) < db(peak) - 15.0);
+    }
+
+    #[test]
+    fn notch_response() {
+        assert!(db(gain(FilterMode::Notch, 1000.0, 1000.0)) < -40.0);
+        assert!(db(gain(FilterMode::Notch, 1000.0, 100.0)).abs() < 0.2);
+        assert!(db(gain(FilterMode::Notch, 1000.0, 10000.0)).abs() < 0.2);
+    }
+}
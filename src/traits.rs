@@ -2,9 +2,39 @@ use crate::stereo_sample::StereoSample;
 
 pub trait AudioSource {
     fn next_sample(&mut self) -> StereoSample;
+    /// Fills the whole block with the next samples. Override this when a source can do better
+    /// than one `next_sample` call per sample.
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        for sample in block.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
     fn set_frequency(&mut self, _freq: f32) {}
+    /// Jumps to `phase` (0..1) in the waveform's cycle. Sources without a phase ignore this.
+    fn set_phase(&mut self, _phase: f32) {}
+    /// Hard sync input: restarts the cycle `fraction` (0..1] of a sample after the sample after
+    /// the next one, as passed on from a master's [`AudioSource::next_wrap()`]. Sources without a
+    /// phase ignore this.
+    fn sync(&mut self, _fraction: f32) {}
+    /// Hard sync output: if the cycle restarts between the next sample and the one after it, how
+    /// long after the next sample it does, in samples (0..1]. Known a sample early so synced
+    /// oscillators can start smoothing over the restart before it happens.
+    fn next_wrap(&self) -> Option<f32> {
+        None
+    }
+    /// Returns the source to its initial state (e.g. phase back to zero).
+    fn reset(&mut self) {}
 }
 
 pub trait AudioProcessor {
     fn process_sample(&mut self, input: StereoSample) -> StereoSample;
-}
\ No newline at end of file
+    /// Processes the whole block in place. Override this when a processor can do better than
+    /// one `process_sample` call per sample.
+    fn process_block(&mut self, block: &mut [StereoSample]) {
+        for sample in block.iter_mut() {
+            *sample = self.process_sample(*sample);
+        }
+    }
+    /// Clears any internal state, as if no samples had been processed yet.
+    fn reset(&mut self) {}
+}
@@ -0,0 +1,100 @@
+use crate::poly_blep::{self, Discontinuity, HardSync, OscillatorQuality};
+use crate::{stereo_sample::StereoSample, traits::AudioSource};
+
+#[derive(Clone)]
+pub struct TriangleWave {
+    phase: f32,
+    pub frequency: f32,
+    sample_rate: u32,
+    quality: OscillatorQuality,
+    sync: HardSync,
+    // Where the phase wraps after the next sample, for syncing other oscillators to this one
+    next_wrap: Option<f32>,
+}
+
+impl TriangleWave {
+    /// Create a new TriangleWave.
+    pub fn new(sample_rate: u32, freq: f32) -> Self {
+        Self {
+            phase: 0.0,
+            frequency: freq.min(sample_rate as f32 / 2.0),
+            sample_rate,
+            quality: OscillatorQuality::High,
+            sync: HardSync::default(),
+            next_wrap: None,
+        }
+    }
+
+    pub fn set_quality(&mut self, quality: OscillatorQuality) {
+        self.quality = quality;
+    }
+
+    fn render(&mut self, increment: f32) -> f32 {
+        // Rises from -1.0 at the start of the cycle to +1.0 halfway through, then falls back.
+        let mut sample = 1.0 - 4.0 * (self.phase - 0.5).abs();
+
+        // The slope flips between -4 and +4 per cycle at both corners. The correction is scaled
+        // to the change in slope per sample.
+        let slope_change = 8.0 * increment;
+        sample += slope_change * poly_blep::blamp(self.phase, increment, self.quality);
+        sample -= slope_change * poly_blep::blamp(poly_blep::wrap_phase(self.phase - 0.5), increment, self.quality);
+
+        // A sync restart can also change the slope, which is smoothed over just like the corners
+        let corners = [
+            Discontinuity { phase: 0.0, step: 0.0, bend: 8.0 },
+            Discontinuity { phase: 0.5, step: 0.0, bend: -8.0 },
+        ];
+        let naive = |phase: f32| (1.0 - 4.0 * (phase - 0.5).abs(), if phase < 0.5 { 4.0 } else { -4.0 });
+        let (sample, restart) = self.sync.process(sample, self.phase, increment, self.quality, &corners, naive);
+
+        // Increment the phase by frequency / sample_rate, unless a hard sync restarted it.
+        match restart {
+            Some(phase) => self.phase = phase,
+            None => poly_blep::advance_phase(&mut self.phase, increment),
+        }
+        self.next_wrap = poly_blep::next_wrap(self.phase, increment);
+
+        sample
+    }
+}
+
+impl AudioSource for TriangleWave {
+    fn next_sample(&mut self) -> StereoSample {
+        let sample = self.render(self.frequency / self.sample_rate as f32);
+
+        StereoSample { left: sample, right: sample }
+    }
+
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        // The frequency can't change mid-block, so the increment only needs working out once
+        let increment = self.frequency / self.sample_rate as f32;
+
+        for out in block.iter_mut() {
+            let sample = self.render(increment);
+            *out = StereoSample { left: sample, right: sample };
+        }
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        // Limit to Nyquist frequency (half the sample rate).
+        self.frequency = freq.min(self.sample_rate as f32 / 2.0);
+    }
+
+    fn set_phase(&mut self, phase: f32) {
+        self.phase = phase.rem_euclid(1.0);
+    }
+
+    fn sync(&mut self, fraction: f32) {
+        self.sync.trigger(fraction);
+    }
+
+    fn next_wrap(&self) -> Option<f32> {
+        self.next_wrap
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.sync = HardSync::default();
+        self.next_wrap = None;
+    }
+}
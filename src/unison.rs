@@ -0,0 +1,389 @@
+use std::sync::Arc;
+
+use crate::noise::NoiseColour;
+use crate::oscillator::{Oscillator, Waveform};
+use crate::poly_blep::OscillatorQuality;
+use crate::random::Random;
+use crate::stereo_sample::StereoSample;
+use crate::traits::AudioSource;
+use crate::wavetable::Wavetable;
+
+/// The most copies unison can stack. All of them are preallocated, so changing the count never
+/// allocates.
+pub const MAX_UNISON: usize = 16;
+
+// Copies render into this scratch buffer before being mixed, longer blocks are split up.
+const SCRATCH_SIZE: usize = 64;
+
+// How far a detune curve of +-1 bends the copies away from evenly spaced pitches, as the
+// exponent (2^x) their spacing is raised to.
+const DETUNE_CURVE_STEEPNESS: f32 = 2.0;
+
+/// Stacks up to [`MAX_UNISON`] copies of an oscillator, detuned against each other and spread
+/// across the stereo field. All copies live inside a single voice, so a note still only takes one
+/// voice from the synth however many copies it plays.
+#[derive(Clone)]
+pub struct Unison {
+    copies: [Oscillator; MAX_UNISON],
+    // Only the first `count` copies are played
+    count: usize,
+    // How far the outermost copies are detuned, in cents either way
+    detune_cents: f32,
+    // -1..1, see `set_detune_curve()`
+    detune_curve: f32,
+    // 0 = every copy centred, 1 = the outermost copies panned hard left and right
+    spread: f32,
+    // 0 = only the centre copies, 0.5 = every copy equally loud, 1 = only the side copies
+    blend: f32,
+    seed: u32,
+    // Draws the copies' start phases, restarted from `seed` on a reset so the same notes always
+    // get the same phases
+    random: Random,
+
+    // Worked out whenever the settings change, rather than every sample
+    ratios: [f32; MAX_UNISON],
+    left_gains: [f32; MAX_UNISON],
+    right_gains: [f32; MAX_UNISON],
+
+    scratch: [StereoSample; SCRATCH_SIZE],
+
+    pub frequency: f32,
+}
+
+impl Unison {
+    pub fn new(sample_rate: u32, freq: f32) -> Self {
+        let mut unison = Self {
+            copies: std::array::from_fn(|_| Oscillator::new(sample_rate, freq)),
+            count: 1,
+            detune_cents: 0.0,
+            detune_curve: 0.0,
+            spread: 0.0,
+            blend: 0.5,
+            seed: 1,
+            random: Random::new(1),
+            ratios: [1.0; MAX_UNISON],
+            left_gains: [0.0; MAX_UNISON],
+            right_gains: [0.0; MAX_UNISON],
+            scratch: [StereoSample::from_mono(0.0); SCRATCH_SIZE],
+            frequency: freq,
+        };
+        unison.update();
+        unison
+    }
+
+    pub fn set_count(&mut self, count: usize) {
+        let count = count.clamp(1, MAX_UNISON);
+        if count != self.count {
+            self.count = count;
+            self.update();
+        }
+    }
+
+    /// Sets how far the outermost copies are detuned, in cents either way.
+    pub fn set_detune(&mut self, detune_cents: f32) {
+        if detune_cents != self.detune_cents {
+            self.detune_cents = detune_cents;
+            self.update();
+        }
+    }
+
+    /// Bends how the detune is shared out between the copies: 0 spaces them evenly, positive
+    /// amounts bunch them up around the centre pitch, negative amounts push them out towards the
+    /// outermost ones.
+    pub fn set_detune_curve(&mut self, amount: f32) {
+        if amount != self.detune_curve {
+            self.detune_curve = amount;
+            self.update();
+        }
+    }
+
+    /// Sets the stereo spread (0 = mono, 1 = the outermost copies panned hard left and right).
+    pub fn set_spread(&mut self, spread: f32) {
+        let spread = spread.clamp(0.0, 1.0);
+        if spread != self.spread {
+            self.spread = spread;
+            self.update();
+        }
+    }
+
+    /// Sets the balance between the centre copies and the side copies around them (0 = centre
+    /// only, 0.5 = all equally loud, 1 = sides only).
+    pub fn set_blend(&mut self, blend: f32) {
+        let blend = blend.clamp(0.0, 1.0);
+        if blend != self.blend {
+            self.blend = blend;
+            self.update();
+        }
+    }
+
+    /// Sets the seed the copies' start phases and noise are drawn from, and restarts them from it.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+
+        // Every copy gets noise of its own
+        for (index, osc) in self.copies.iter_mut().enumerate() {
+            osc.set_seed(seed.wrapping_mul(MAX_UNISON as u32).wrapping_add(index as u32));
+        }
+        self.random = Random::new(seed);
+        self.randomise_phases();
+    }
+
+    /// Gives the copies new start phases for a new note, so notes don't all sound alike.
+    pub fn trigger(&mut self) {
+        self.randomise_phases();
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.copies.iter_mut().for_each(|osc| osc.set_waveform(waveform));
+    }
+
+    pub fn set_pulse_width(&mut self, pulse_width: f32) {
+        self.copies.iter_mut().for_each(|osc| osc.set_pulse_width(pulse_width));
+    }
+
+    pub fn set_wavetable_position(&mut self, position: f32) {
+        self.copies.iter_mut().for_each(|osc| osc.set_wavetable_position(position));
+    }
+
+    pub fn set_wavetable(&mut self, wavetable: Arc<Wavetable>) {
+        self.copies.iter_mut().for_each(|osc| osc.set_wavetable(wavetable.clone()));
+    }
+
+    pub fn set_noise_colour(&mut self, colour: NoiseColour) {
+        self.copies.iter_mut().for_each(|osc| osc.set_noise_colour(colour));
+    }
+
+    pub fn set_noise_stereo(&mut self, stereo: bool) {
+        self.copies.iter_mut().for_each(|osc| osc.set_noise_stereo(stereo));
+    }
+
+    pub fn set_quality(&mut self, quality: OscillatorQuality) {
+        self.copies.iter_mut().for_each(|osc| osc.set_quality(quality));
+    }
+
+    /// Hard syncs every copy to the matching copy in `master`, passing on any restart coming up
+    /// after the next sample. Call this after `master` has rendered the same sample.
+    pub fn sync_to(&mut self, master: &Unison) {
+        for (osc, master_osc) in self.copies[..self.count].iter_mut().zip(master.copies.iter()) {
+            if let Some(fraction) = master_osc.next_wrap() {
+                osc.sync(fraction);
+            }
+        }
+    }
+
+    /// Gives every copy the next random start phase, so stacked copies don't start out in phase
+    /// and sum into one loud spike. The phases follow from the seed, so they come out the same
+    /// for the same notes after a reset.
+    fn randomise_phases(&mut self) {
+        // The first copy keeps its own phase, so a single copy plays just like a plain oscillator
+        for osc in self.copies[1..].iter_mut() {
+            osc.set_phase(self.random.next_f32());
+        }
+    }
+
+    /// Works out every copy's pitch ratio and pan gains from the current settings.
+    fn update(&mut self) {
+        let count = self.count;
+        let curve_exponent = (self.detune_curve * DETUNE_CURVE_STEEPNESS).exp2();
+
+        // With one or two copies, there are no side copies to blend against
+        let centre_gain = if count <= 2 { 1.0 } else { (2.0 * (1.0 - self.blend)).min(1.0) };
+        let side_gain = if count <= 2 { 1.0 } else { (2.0 * self.blend).min(1.0) };
+
+        let mut total_power = 0.0;
+        for index in 0..count {
+            // Where the copy sits between the lowest (-1) and highest (+1) pitch
+            let position = if count == 1 { 0.0 } else { 2.0 * index as f32 / (count - 1) as f32 - 1.0 };
+
+            let detune = position.signum() * position.abs().powf(curve_exponent) * self.detune_cents;
+            self.ratios[index] = (detune / 1200.0).exp2();
+
+            // Every other pair of copies swaps sides, so the flat and sharp copies don't all end
+            // up on the same side
+            let pair = index.min(count - 1 - index);
+            let pan = (if pair % 2 == 0 { position } else { -position }) * self.spread;
+
+            let is_centre = 2 * pair + 2 >= count;
+            let gain = if is_centre { centre_gain } else { side_gain };
+
+            // Balance rather than equal-power panning, so a centred copy plays at its full level
+            let (left, right) = (gain * (1.0 - pan).min(1.0), gain * (1.0 + pan).min(1.0));
+            self.left_gains[index] = left;
+            self.right_gains[index] = right;
+            total_power += (left * left + right * right) / 2.0;
+        }
+
+        // The copies are out of phase, so they add up in power rather than amplitude. Counting
+        // the power after panning keeps the level the same however wide the copies are spread.
+        let normalise = if total_power > 0.0 { 1.0 / total_power.sqrt() } else { 0.0 };
+        for index in 0..count {
+            self.left_gains[index] *= normalise;
+            self.right_gains[index] *= normalise;
+        }
+
+        self.set_frequency(self.frequency);
+    }
+}
+
+impl AudioSource for Unison {
+    fn next_sample(&mut self) -> StereoSample {
+        if self.count == 1 {
+            // Exactly as `fill_block()` plays it
+            return self.copies[0].next_sample();
+        }
+
+        let mut mix = StereoSample::from_mono(0.0);
+
+        for (index, osc) in self.copies[..self.count].iter_mut().enumerate() {
+            let sample = osc.next_sample();
+            mix.left += sample.left * self.left_gains[index];
+            mix.right += sample.right * self.right_gains[index];
+        }
+
+        mix
+    }
+
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        if self.count == 1 {
+            // A single centred copy at full level is just the oscillator itself
+            self.copies[0].fill_block(block);
+            return;
+        }
+
+        block.fill(StereoSample::from_mono(0.0));
+
+        for chunk in block.chunks_mut(SCRATCH_SIZE) {
+            let scratch = &mut self.scratch[..chunk.len()];
+
+            for (index, osc) in self.copies[..self.count].iter_mut().enumerate() {
+                osc.fill_block(scratch);
+
+                let (left_gain, right_gain) = (self.left_gains[index], self.right_gains[index]);
+                for (out, sample) in chunk.iter_mut().zip(scratch.iter()) {
+                    out.left += sample.left * left_gain;
+                    out.right += sample.right * right_gain;
+                }
+            }
+        }
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        self.frequency = freq;
+
+        for (osc, ratio) in self.copies[..self.count].iter_mut().zip(self.ratios.iter()) {
+            osc.set_frequency(freq * ratio);
+        }
+    }
+
+    fn set_phase(&mut self, phase: f32) {
+        self.copies.iter_mut().for_each(|osc| osc.set_phase(phase));
+    }
+
+    fn reset(&mut self) {
+        self.copies.iter_mut().for_each(|osc| osc.reset());
+        self.random = Random::new(self.seed);
+        self.randomise_phases();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(unison: &mut Unison, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| unison.next_sample().left).collect()
+    }
+
+    #[test]
+    fn every_note_starts_from_new_phases_that_repeat_after_a_reset() {
+        let mut unison = Unison::new(48000, 110.0);
+        unison.set_count(4);
+        unison.set_seed(7);
+
+        unison.trigger();
+        let first_note = render(&mut unison, 256);
+        unison.reset();
+        unison.trigger();
+        assert_eq!(render(&mut unison, 256), first_note);
+
+        unison.reset();
+        unison.trigger();
+        unison.trigger();
+        assert_ne!(render(&mut unison, 256), first_note);
+    }
+
+    fn rms(samples: &[StereoSample]) -> f32 {
+        let power: f32 = samples.iter().map(|s| s.left * s.left + s.right * s.right).sum();
+        (power / (2 * samples.len()) as f32).sqrt()
+    }
+
+    #[test]
+    fn spread_copies_are_wide_but_no_louder() {
+        let play = |count: usize| {
+            let mut unison = Unison::new(48000, 110.0);
+            unison.set_count(count);
+            unison.set_detune(30.0);
+            unison.set_spread(1.0);
+            unison.trigger();
+            (0..48000).map(|_| unison.next_sample()).collect::<Vec<_>>()
+        };
+        let single = rms(&play(1));
+
+        for count in [2, 3, 5, 8, MAX_UNISON] {
+            let output = play(count);
+            let difference: f32 = output.iter().map(|s| (s.left - s.right).abs()).sum();
+            assert!(difference / output.len() as f32 > 0.05, "{count} copies");
+
+            let gain_db = 20.0 * (rms(&output) / single).log10();
+            assert!(gain_db.abs() < 1.5, "{count} copies: {gain_db} dB");
+        }
+    }
+
+    #[test]
+    fn in_tune_copies_in_phase_play_one_oscillator() {
+        let mut single = Unison::new(48000, 110.0);
+        let single = render(&mut single, 1000);
+
+        for count in [2, 5, MAX_UNISON] {
+            let mut unison = Unison::new(48000, 110.0);
+            unison.set_count(count);
+            unison.trigger();
+            // Lined up again, as if the start phases weren't randomised
+            unison.set_phase(0.0);
+
+            // Every copy plays exactly the same, summed at the power normalised level
+            let gain = (count as f32).sqrt();
+            let output: Vec<_> = (0..1000).map(|_| unison.next_sample()).collect();
+            for (sample, single) in output.iter().zip(single.iter()) {
+                assert!((sample.left - gain * single).abs() < 1e-4, "{count} copies: {sample:?}");
+                assert!((sample.left - sample.right).abs() < 1e-5, "{count} copies: {sample:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn detune_curve_and_blend_shape_the_copies() {
+        let cents = |unison: &Unison| -> Vec<f32> {
+            let ratios = &unison.ratios[..unison.count];
+            ratios.iter().map(|ratio| (1200.0 * ratio.log2()).round()).collect()
+        };
+        let mut unison = Unison::new(48000, 110.0);
+        unison.set_count(5);
+        unison.set_detune(100.0);
+        assert_eq!(cents(&unison), vec![-100.0, -50.0, 0.0, 50.0, 100.0]);
+
+        // Bunched up around the centre pitch, or pushed out towards the outermost ones
+        unison.set_detune_curve(1.0);
+        assert_eq!(cents(&unison), vec![-100.0, -6.0, 0.0, 6.0, 100.0]);
+        unison.set_detune_curve(-1.0);
+        assert_eq!(cents(&unison), vec![-100.0, -84.0, 0.0, 84.0, 100.0]);
+
+        // Only the centre copy, then only the side copies
+        unison.set_blend(0.0);
+        assert!(unison.left_gains[2] > 0.0);
+        assert!([0, 1, 3, 4].iter().all(|&index| unison.left_gains[index] == 0.0));
+        unison.set_blend(1.0);
+        assert_eq!(unison.left_gains[2], 0.0);
+        assert!([0, 1, 3, 4].iter().all(|&index| unison.left_gains[index] > 0.0));
+    }
+}
@@ -0,0 +1,68 @@
+use nih_plug::prelude::Enum;
+
+// How strongly the exponential and logarithmic curves bend away from linear.
+const CURVE_STEEPNESS: f32 = 4.0;
+
+/// Maps note-on velocity onto how hard the note is played.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VelocityCurve {
+    #[id = "linear"]
+    Linear,
+    /// Soft playing stays quiet for longer, only the hardest hits reach full level.
+    #[id = "exponential"]
+    Exponential,
+    /// Reaches loud levels quickly, good for light keyboards.
+    #[id = "logarithmic"]
+    Logarithmic,
+    /// Ignores velocity, every note is played at full velocity.
+    #[id = "fixed"]
+    Fixed,
+}
+
+impl VelocityCurve {
+    /// Shapes a normalised velocity (0..1) into a normalised value (0..1).
+    pub fn apply(&self, velocity: f32) -> f32 {
+        let velocity = velocity.clamp(0.0, 1.0);
+
+        match self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::Exponential => {
+                ((CURVE_STEEPNESS * velocity).exp() - 1.0) / (CURVE_STEEPNESS.exp() - 1.0)
+            }
+            VelocityCurve::Logarithmic => {
+                (1.0 + (CURVE_STEEPNESS.exp() - 1.0) * velocity).ln() / CURVE_STEEPNESS
+            }
+            VelocityCurve::Fixed => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves_map_soft_medium_and_hard_notes() {
+        let cases = [
+            (VelocityCurve::Linear, [0.0, 0.5, 1.0]),
+            (VelocityCurve::Exponential, [0.0, 0.1192, 1.0]),
+            (VelocityCurve::Logarithmic, [0.0, 0.8313, 1.0]),
+            (VelocityCurve::Fixed, [1.0, 1.0, 1.0]),
+        ];
+
+        for (curve, expected) in cases {
+            for (velocity, expected) in [0.0, 0.5, 1.0].into_iter().zip(expected) {
+                let actual = curve.apply(velocity);
+                assert!((actual - expected).abs() < 1e-4, "{curve:?} at {velocity}: {actual}");
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_velocities_are_clamped() {
+        for curve in [VelocityCurve::Linear, VelocityCurve::Exponential, VelocityCurve::Logarithmic] {
+            assert_eq!(curve.apply(-0.5), curve.apply(0.0), "{curve:?}");
+            assert_eq!(curve.apply(1.5), curve.apply(1.0), "{curve:?}");
+        }
+    }
+}
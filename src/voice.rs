@@ -1,71 +1,659 @@
 use crate::ramp_envelope::RampEnvelope;
-use crate::stereo_sample::{self, StereoSample};
+use crate::stereo_sample::StereoSample;
 use crate::traits::{AudioSource, AudioProcessor};
-use crate::saw_wave::SawWave;
+use crate::oscillator_mixer::OscillatorMixer;
+use crate::fm::FmEngine;
+use crate::state_variable_filter::StateVariableFilter;
+use crate::ladder_filter::LadderFilter;
+use crate::lfo::{Lfo, LfoMode, NUM_LFOS};
+use crate::mod_matrix::{ModMatrix, ModSources, ModTargets};
 use crate::adsr_envelope::AdsrEnvelope;
 use crate::gain::Gain;
+use crate::velocity::VelocityCurve;
+
+use nih_plug::prelude::Enum;
+
+// Key tracking moves the cutoff relative to this note (middle C), which plays at the cutoff as
+// set whatever the amount.
+const KEY_TRACKING_CENTRE_HZ: f32 = 261.63;
+
+// The key modulation source reaches +-1 this many octaves either side of middle C.
+const KEY_SOURCE_RANGE_OCTAVES: f32 = 5.0;
+
+// How quickly the pitch catches up with the bend wheel, which only sends a message every few
+// milliseconds.
+const PITCH_BEND_SMOOTHING_S: f32 = 0.01;
+
+/// Identifies the note a voice is playing, as sent by the host.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NoteId {
+    pub note: u8,
+    pub channel: u8,
+    // Only set by hosts that support polyphonic voice IDs (e.g. CLAP).
+    pub voice_id: Option<i32>,
+}
+
+impl NoteId {
+    /// Returns `true` if a note event for `other` is meant for this note.
+    /// Voice IDs take priority when both sides have one, otherwise fall back to note and channel.
+    pub fn matches(&self, other: &NoteId) -> bool {
+        match (self.voice_id, other.voice_id) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.note == other.note && self.channel == other.channel,
+        }
+    }
+}
+
+/// What generates a voice's sound, before the amplitude envelope.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VoiceEngine {
+    /// The oscillator mixer: oscillators, sub-oscillator and noise.
+    #[id = "oscillators"]
+    Oscillators,
+    /// Phase modulation between sine operators.
+    #[id = "fm"]
+    #[name = "FM"]
+    Fm,
+}
+
+/// Which filter a voice's sound goes through.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FilterType {
+    /// The clean state-variable filter, with lowpass, highpass, bandpass and notch modes.
+    #[id = "svf"]
+    #[name = "SVF"]
+    Svf,
+    /// The saturating 4-pole ladder lowpass.
+    #[id = "ladder"]
+    Ladder,
+}
 
 #[derive(Clone)]
 pub struct Voice {
-    pub osc: SawWave,
+    pub mixer: OscillatorMixer,
+    pub fm: FmEngine,
+    engine: VoiceEngine,
+    pub svf: StateVariableFilter,
+    pub ladder: LadderFilter,
+    filter_type: FilterType,
+    pub filter_env: AdsrEnvelope,
+    pub lfos: [Lfo; NUM_LFOS],
+    // Each LFO's value for the sample being rendered, from this voice's own LFOs or the synth's
+    // global ones depending on their mode
+    lfo_values: [f32; NUM_LFOS],
+    // How far the filter envelope moves the cutoff at its peak, in octaves (negative sweeps down)
+    filter_env_amount: f32,
+    // How closely the cutoff follows the note (0 = not at all, 1 = an octave per octave)
+    key_tracking: f32,
+    // How far a full velocity note raises the cutoff, in octaves
+    filter_velocity_depth: f32,
+    // What the mod matrix is doing to each destination right now
+    modulation: ModTargets,
+    // The pitch bend, in semitones: where it's heading, where it is right now, and how far it
+    // moves each sample
+    pitch_bend_target: f32,
+    pitch_bend: f32,
+    pitch_bend_smoothing: f32,
+    // The pitch modulation and bend together, as a frequency ratio
+    pitch_ratio: f32,
+    // The pulse width as set, before modulation
+    pulse_width: f32,
+    // Mod wheel, this voice's key pressure and the whole channel's pressure (0..1), for the mod
+    // matrix
+    mod_wheel: f32,
+    aftertouch: f32,
+    channel_pressure: f32,
     pub env: AdsrEnvelope,
     pub gain: Gain,
+    velocity_gain: Gain,
     pub frequency_env: RampEnvelope,
     start_frequency: f32,
     end_frequency: f32,
+    // Where the glide is at right now
+    frequency: f32,
+    pub note_id: NoteId,
+    // When this voice was last triggered, relative to the other voices (higher is more recent)
+    pub trigger_order: u64,
+    // Note-on velocity (0..1) as sent by the host
+    note_velocity: f32,
+    velocity_curve: VelocityCurve,
+    // `note_velocity` through `velocity_curve`, worked out on note-on rather than every sample
+    velocity: f32,
+    // How much velocity affects the amplitude (0 = not at all, 1 = fully)
+    velocity_depth: f32,
     pub active: bool,
+    // Whether the key playing this voice is still down. Once it's let go, the pedals may keep the
+    // voice sounding until they're lifted.
+    pub key_held: bool,
+    // Latched by the sostenuto pedal, which holds the notes whose keys were down when it was
+    // pressed
+    pub sostenuto: bool,
 }
 
 impl Voice {
     pub fn new(sample_rate: u32, frequency: f32) -> Self {
         Self {
-            osc: SawWave::new(sample_rate, frequency),
+            mixer: OscillatorMixer::new(sample_rate, frequency),
+            fm: FmEngine::new(sample_rate, frequency),
+            engine: VoiceEngine::Oscillators,
+            svf: StateVariableFilter::new(sample_rate as f32, 20000.0, 0.0),
+            ladder: LadderFilter::new(sample_rate as f32, 20000.0, 0.0),
+            filter_type: FilterType::Svf,
+            filter_env: AdsrEnvelope::new(sample_rate as f32, 0.01, 0.3, 0.0, 0.3),
+            lfos: std::array::from_fn(|index| Lfo::new(sample_rate as f32, index as u32)),
+            lfo_values: [0.0; NUM_LFOS],
+            filter_env_amount: 0.0,
+            key_tracking: 0.0,
+            filter_velocity_depth: 0.0,
+            modulation: ModTargets::default(),
+            pitch_bend_target: 0.0,
+            pitch_bend: 0.0,
+            pitch_bend_smoothing: 1.0 - (-1.0 / (PITCH_BEND_SMOOTHING_S * sample_rate as f32)).exp(),
+            pitch_ratio: 1.0,
+            pulse_width: 0.5,
+            mod_wheel: 0.0,
+            aftertouch: 0.0,
+            channel_pressure: 0.0,
             env: AdsrEnvelope::new(sample_rate as f32, 0.02, 0.2, 1.0, 0.2),
             frequency_env: RampEnvelope::new(sample_rate as f32, 0.1),
             gain: Gain::new(0.9),
+            velocity_gain: Gain::new(1.0),
             active: false,
+            key_held: false,
+            sostenuto: false,
             start_frequency: frequency,
             end_frequency: frequency,
+            frequency,
+            note_id: NoteId { note: 0, channel: 0, voice_id: None },
+            trigger_order: 0,
+            note_velocity: 1.0,
+            velocity_curve: VelocityCurve::Linear,
+            velocity: 1.0,
+            velocity_depth: 1.0,
         }
     }
 
-    pub fn play(&mut self, frequency: f32) {
-        self.start_frequency = self.osc.frequency;
-        self.end_frequency = frequency;
-        self.frequency_env.trigger();
+    pub fn play(&mut self, note_id: NoteId, frequency: f32, velocity: f32) {
+        // A silent voice hasn't been following the bend, so it starts wherever the wheel is now
+        if !self.active {
+            self.pitch_bend = self.pitch_bend_target;
+            self.update_pitch_ratio();
+        }
+        self.glide_to(note_id, frequency);
+        self.note_velocity = velocity;
+        self.velocity = self.velocity_curve.apply(velocity);
+        // Pressure belongs to the note that was playing before
+        self.aftertouch = 0.0;
+        self.mixer.trigger();
         self.env.trigger();
+        self.filter_env.trigger();
+        self.lfos.iter_mut().for_each(|lfo| lfo.trigger());
+        self.fm.trigger(self.velocity());
         self.active = true;
     }
 
+    /// Moves to a new note from the current pitch without retriggering the amplitude envelope.
+    pub fn glide_to(&mut self, note_id: NoteId, frequency: f32) {
+        self.start_frequency = self.frequency;
+        self.end_frequency = frequency;
+        self.note_id = note_id;
+        self.frequency_env.trigger();
+        // A new note, so it's only latched if the sostenuto pedal is pressed again
+        self.key_held = true;
+        self.sostenuto = false;
+    }
+
     pub fn stop(&mut self) {
         self.env.release();
+        self.filter_env.release();
+        self.fm.release();
+    }
+
+    /// Silences the voice immediately and clears all oscillator and envelope state.
+    pub fn reset(&mut self) {
+        self.mixer.reset();
+        self.fm.reset();
+        self.svf.reset();
+        self.ladder.reset();
+        self.filter_env.reset();
+        self.lfos.iter_mut().for_each(|lfo| lfo.reset());
+        self.env.reset();
+        self.frequency_env.reset();
+        self.modulation = ModTargets::default();
+        self.pitch_bend = self.pitch_bend_target;
+        self.apply_modulation();
+        self.set_frequency(self.end_frequency);
+        self.start_frequency = self.end_frequency;
+        self.active = false;
+        self.key_held = false;
+        self.sostenuto = false;
+    }
+
+    /// The note-on velocity shaped by the velocity curve (0..1), for anything that should
+    /// respond to how hard the note was played.
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        if curve != self.velocity_curve {
+            self.velocity_curve = curve;
+            self.velocity = curve.apply(self.note_velocity);
+        }
+    }
+
+    pub fn set_velocity_depth(&mut self, depth: f32) {
+        self.velocity_depth = depth;
+    }
+
+    /// Switches what generates the voice's sound. Both engines are always kept ready, so this
+    /// never allocates.
+    pub fn set_engine(&mut self, engine: VoiceEngine) {
+        self.engine = engine;
+    }
+
+    pub fn set_filter_type(&mut self, filter_type: FilterType) {
+        self.filter_type = filter_type;
+    }
+
+    /// Sets how far the filter envelope moves the cutoff at its peak, in octaves either way.
+    pub fn set_filter_env_amount(&mut self, octaves: f32) {
+        self.filter_env_amount = octaves;
+    }
+
+    /// Sets how closely the cutoff follows the note (0 = not at all, 1 = fully).
+    pub fn set_key_tracking(&mut self, amount: f32) {
+        self.key_tracking = amount;
+    }
+
+    /// Sets how far a full velocity note raises the cutoff, in octaves.
+    pub fn set_filter_velocity_depth(&mut self, octaves: f32) {
+        self.filter_velocity_depth = octaves;
+    }
+
+    /// Sets the pulse width the mod matrix's pulse width modulation is added to.
+    pub fn set_pulse_width(&mut self, pulse_width: f32) {
+        self.pulse_width = pulse_width;
+        self.mixer.set_pulse_width(pulse_width + self.modulation.pulse_width);
+    }
+
+    /// Sets how far the pitch is bent, in semitones. The voice glides there over a few
+    /// milliseconds, on top of any glide between notes.
+    pub fn set_pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend_target = semitones;
+    }
+
+    /// Sets the mod wheel position (0..1), for the mod matrix's mod wheel source.
+    pub fn set_mod_wheel(&mut self, mod_wheel: f32) {
+        self.mod_wheel = mod_wheel;
+    }
+
+    /// Sets the pressure on this voice's key (0..1), for the mod matrix's aftertouch source.
+    pub fn set_aftertouch(&mut self, pressure: f32) {
+        self.aftertouch = pressure;
+    }
+
+    /// Sets the pressure on the whole channel (0..1). The aftertouch source follows whichever of
+    /// this and the key's own pressure is pressed harder.
+    pub fn set_channel_pressure(&mut self, pressure: f32) {
+        self.channel_pressure = pressure;
+    }
+
+    fn aftertouch(&self) -> f32 {
+        self.aftertouch.max(self.channel_pressure)
+    }
+
+    /// Seeds everything random about this voice (e.g. unison start phases), so voices can differ
+    /// from each other while still playing back the same every time.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.mixer.set_seed(seed);
+
+        for (index, lfo) in self.lfos.iter_mut().enumerate() {
+            lfo.set_seed(seed.wrapping_mul(NUM_LFOS as u32).wrapping_add(index as u32));
+        }
     }
 
     pub fn get_frequency(&self) -> f32 {
         self.end_frequency
     }
 
-    pub fn next_sample(&mut self) -> StereoSample {
+    /// Renders the next sample. `global_lfos` are the synth's global LFO values for this sample,
+    /// used in place of the voice's own LFOs for any LFO in global mode, and `mod_matrix` is the
+    /// synth's matrix, evaluated against this voice's own sources.
+    pub fn next_sample(
+        &mut self,
+        global_lfos: &[f32; NUM_LFOS],
+        mod_matrix: &ModMatrix,
+    ) -> StereoSample {
         if !self.active {
             return StereoSample::from_mono(0.0);
         }
 
-        // TODO: Maybe make this mono by default?
-        let frequency_diff = StereoSample::from_mono(self.end_frequency - self.start_frequency);
-        let env_sample = self.frequency_env.process_sample(frequency_diff).left;
+        // Key tracking follows the glide, so the pitch is worked out before the modulation
+        let frequency = self.next_glide_frequency();
+        self.modulate(global_lfos, mod_matrix, frequency);
+        self.set_frequency(frequency);
+        let raw = self.source().next_sample();
+        let filtered = self.filter().process_sample(raw);
+        let osc_out = self.env.process_sample(filtered);
+        let gain_out = self.gain.process_sample(osc_out);
 
-        let freq = self.start_frequency + env_sample;
-        self.osc.set_frequency(freq);
+        self.velocity_gain.set_amount(1.0 - self.velocity_depth + self.velocity_depth * self.velocity());
+        let gain_out = self.velocity_gain.process_sample(gain_out);
 
-        let raw = self.osc.next_sample();
-        let osc_out = self.env.process_sample(raw);
-        let gain_out = self.gain.process_sample(osc_out);
+        // Level and pan modulation, with balance rather than equal-power panning so an unpanned
+        // voice plays at its full level
+        let level = (1.0 + self.modulation.level).max(0.0);
+        let pan = self.modulation.pan.clamp(-1.0, 1.0);
+        let output = StereoSample {
+            left: gain_out.left * level * (1.0 - pan).min(1.0),
+            right: gain_out.right * level * (1.0 + pan).min(1.0),
+        };
 
         // If envelope is effectively done
         if self.env.is_done() {
             self.active = false;
         }
 
-        gain_out
+        output
     }
-}
\ No newline at end of file
+
+    /// Renders the next `block.len()` samples, the block equivalent of `next_sample()`, with one
+    /// set of global LFO values per sample.
+    pub fn fill_block(
+        &mut self,
+        block: &mut [StereoSample],
+        global_lfos: &[[f32; NUM_LFOS]],
+        mod_matrix: &ModMatrix,
+    ) {
+        if !self.active {
+            block.fill(StereoSample::from_mono(0.0));
+            return;
+        }
+
+        let modulated = mod_matrix.is_active(self.mod_wheel, self.aftertouch()) || self.modulation != ModTargets::default();
+        let bending = self.pitch_bend != self.pitch_bend_target;
+        // The oscillators and filter mustn't run on past the sample where the voice finishes
+        let finishing = self.env.may_finish_within(block.len());
+        if modulated || bending || finishing || !self.frequency_env.is_finished() {
+            // The pitch, levels and envelope times can all move from one sample to the next
+            for (sample, global_lfos) in block.iter_mut().zip(global_lfos.iter()) {
+                *sample = self.next_sample(global_lfos, mod_matrix);
+            }
+            return;
+        }
+
+        // Not gliding, so the oscillators can run at a fixed frequency for the whole block
+        self.set_frequency(self.end_frequency);
+        self.source().fill_block(block);
+
+        // The LFOs and the filter envelope move on every sample
+        for (sample, global_lfos) in block.iter_mut().zip(global_lfos.iter()) {
+            self.modulate(global_lfos, mod_matrix, self.end_frequency);
+            *sample = self.filter().process_sample(*sample);
+        }
+        self.env.process_block(block);
+        self.gain.process_block(block);
+
+        self.velocity_gain.set_amount(1.0 - self.velocity_depth + self.velocity_depth * self.velocity());
+        self.velocity_gain.process_block(block);
+
+        if self.env.is_done() {
+            self.active = false;
+        }
+    }
+
+    /// Advances the glide and returns the note's frequency for the next sample.
+    fn next_glide_frequency(&mut self) -> f32 {
+        if self.frequency_env.is_finished() {
+            // Exactly on the note, as `fill_block()` plays it, rather than wherever
+            // `start + (end - start)` happens to round to
+            self.end_frequency
+        } else {
+            // TODO: Maybe make this mono by default?
+            let frequency_diff = StereoSample::from_mono(self.end_frequency - self.start_frequency);
+            self.start_frequency + self.frequency_env.process_sample(frequency_diff).left
+        }
+    }
+
+    /// Advances this voice's LFOs, and picks up the global ones' values from the synth.
+    fn next_lfos(&mut self, global_lfos: &[f32; NUM_LFOS]) {
+        for (index, lfo) in self.lfos.iter_mut().enumerate() {
+            self.lfo_values[index] = match lfo.mode() {
+                LfoMode::Global => global_lfos[index],
+                LfoMode::PerVoice => lfo.next(),
+            };
+        }
+    }
+
+    /// Moves every modulation source on by a sample and applies the filter modulation and the
+    /// mod matrix. `frequency` is the note's frequency at this sample, for key tracking.
+    fn modulate(&mut self, global_lfos: &[f32; NUM_LFOS], mod_matrix: &ModMatrix, frequency: f32) {
+        self.next_lfos(global_lfos);
+        self.next_pitch_bend();
+        let filter_env = self.filter_env.process_sample(StereoSample::from_mono(1.0)).left;
+        let key = (frequency / KEY_TRACKING_CENTRE_HZ).log2();
+
+        let sources = ModSources {
+            amp_envelope: self.env.get_amplitude(),
+            filter_envelope: filter_env,
+            lfos: self.lfo_values,
+            velocity: self.velocity(),
+            key: key / KEY_SOURCE_RANGE_OCTAVES,
+            mod_wheel: self.mod_wheel,
+            aftertouch: self.aftertouch(),
+        };
+        let modulation = mod_matrix.evaluate(&sources);
+        if modulation != self.modulation {
+            self.modulation = modulation;
+            self.apply_modulation();
+        }
+
+        // The filter envelope, key tracking, velocity and matrix are all added up in octaves, so
+        // the same amount sounds the same anywhere on the keyboard and at any cutoff
+        let octaves = filter_env * self.filter_env_amount
+            + key * self.key_tracking
+            + self.velocity() * self.filter_velocity_depth
+            + self.modulation.cutoff;
+
+        self.svf.set_cutoff_modulation(octaves);
+        self.ladder.set_cutoff_modulation(octaves);
+    }
+
+    /// Moves the pitch bend one sample closer to where the wheel is.
+    fn next_pitch_bend(&mut self) {
+        let distance = self.pitch_bend_target - self.pitch_bend;
+        if distance == 0.0 {
+            return;
+        }
+
+        // Within a hundredth of a cent, close enough to land on it
+        if distance.abs() < 1e-4 {
+            self.pitch_bend = self.pitch_bend_target;
+        } else {
+            self.pitch_bend += distance * self.pitch_bend_smoothing;
+        }
+        self.update_pitch_ratio();
+    }
+
+    fn update_pitch_ratio(&mut self) {
+        self.pitch_ratio = ((self.modulation.pitch + self.pitch_bend) / 12.0).exp2();
+    }
+
+    /// Pushes the mod matrix's latest values into everything it modulates, other than the
+    /// cutoff, level and pan which are applied every sample anyway.
+    fn apply_modulation(&mut self) {
+        let modulation = self.modulation;
+
+        self.update_pitch_ratio();
+        self.svf.set_resonance_modulation(modulation.resonance);
+        self.ladder.set_resonance_modulation(modulation.resonance);
+        self.mixer.set_pulse_width(self.pulse_width + modulation.pulse_width);
+        self.env.set_time_scales(
+            modulation.amp_attack.exp2(),
+            modulation.amp_decay.exp2(),
+            modulation.amp_release.exp2(),
+        );
+        self.filter_env.set_time_scales(
+            modulation.filter_attack.exp2(),
+            modulation.filter_decay.exp2(),
+            modulation.filter_release.exp2(),
+        );
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        self.frequency = freq;
+        let modulated = freq * self.pitch_ratio;
+        self.source().set_frequency(modulated);
+    }
+
+    fn source(&mut self) -> &mut dyn AudioSource {
+        match self.engine {
+            VoiceEngine::Oscillators => &mut self.mixer,
+            VoiceEngine::Fm => &mut self.fm,
+        }
+    }
+
+    fn filter(&mut self) -> &mut dyn AudioProcessor {
+        match self.filter_type {
+            FilterType::Svf => &mut self.svf,
+            FilterType::Ladder => &mut self.ladder,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::polysynth::midi_note_to_frequency;
+
+    const NO_LFOS: [f32; NUM_LFOS] = [0.0; NUM_LFOS];
+
+    fn note(note: u8) -> NoteId {
+        NoteId { note, channel: 0, voice_id: None }
+    }
+
+    /// Renders `len` samples from `voice` in blocks of `block_len` (0 = one sample at a time).
+    fn render(voice: &mut Voice, len: usize, block_len: usize) -> Vec<StereoSample> {
+        let mut output = vec![StereoSample::from_mono(0.0); len];
+        let matrix = ModMatrix::new();
+        if block_len == 0 {
+            output.iter_mut().for_each(|sample| *sample = voice.next_sample(&NO_LFOS, &matrix));
+        } else {
+            for block in output.chunks_mut(block_len) {
+                voice.fill_block(block, &vec![NO_LFOS; block.len()], &matrix);
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn fill_block_matches_next_sample() {
+        let mut voice = Voice::new(48000, 220.0);
+        voice.env.set_release(0.01);
+        voice.frequency_env.set_ramp(0.01);
+        // So the cutoff follows the glide too
+        voice.set_key_tracking(1.0);
+
+        // Odd block lengths, so the glide and release end partway through a block
+        for block_len in [1, 37, 64] {
+            let mut by_sample = voice.clone();
+            let mut by_block = voice.clone();
+
+            // A wide glide, where `start + (end - start)` doesn't round back to exactly `end`.
+            // Playing again after it's finished shows up anything left running in the meantime.
+            let events: [fn(&mut Voice); 5] = [
+                |v| v.play(note(60), midi_note_to_frequency(60), 0.8),
+                |v| v.glide_to(note(35), midi_note_to_frequency(35)),
+                |v| v.stop(),
+                |v| v.play(note(64), midi_note_to_frequency(64), 1.0),
+                |v| v.stop(),
+            ];
+            for event in events {
+                event(&mut by_sample);
+                event(&mut by_block);
+
+                let expected = render(&mut by_sample, 4000, 0);
+                let actual = render(&mut by_block, 4000, block_len);
+                for (index, (a, b)) in actual.iter().zip(expected.iter()).enumerate() {
+                    assert!(
+                        a.left.to_bits() == b.left.to_bits() && a.right.to_bits() == b.right.to_bits(),
+                        "block length {block_len}, sample {index}: {a:?} != {b:?}"
+                    );
+                }
+            }
+
+            assert!(!by_sample.active && !by_block.active);
+        }
+    }
+
+    #[test]
+    fn filter_modulation_adds_up_in_octaves() {
+        // The cutoff once the filter envelope has reached its sustain level, from 500 Hz so
+        // there's room either way
+        let cutoff = |frequency: f32, key_tracking: f32, env_amount: f32, velocity_depth: f32| {
+            let mut voice = Voice::new(48000, frequency);
+            voice.svf.set_cutoff(500.0);
+            voice.svf.reset();
+            voice.filter_env.set_attack(0.0);
+            voice.filter_env.set_decay(0.0);
+            voice.filter_env.set_sustain(1.0);
+            voice.set_key_tracking(key_tracking);
+            voice.set_filter_env_amount(env_amount);
+            voice.set_filter_velocity_depth(velocity_depth);
+
+            voice.play(note(60), frequency, 1.0);
+            render(&mut voice, 100, 0);
+            voice.svf.cutoff_hz()
+        };
+        let centre = KEY_TRACKING_CENTRE_HZ;
+        let untouched = cutoff(centre, 0.0, 0.0, 0.0);
+        let assert_octaves = |cutoff: f32, octaves: f32| {
+            let actual = (cutoff / untouched).log2();
+            assert!((actual - octaves).abs() < 1e-3, "{actual} octaves, expected {octaves}");
+        };
+
+        // An octave up the keyboard at full key tracking doubles the cutoff, and only then
+        assert_octaves(cutoff(centre, 1.0, 0.0, 0.0), 0.0);
+        assert_octaves(cutoff(2.0 * centre, 1.0, 0.0, 0.0), 1.0);
+        assert_octaves(cutoff(2.0 * centre, 0.5, 0.0, 0.0), 0.5);
+        assert_octaves(cutoff(2.0 * centre, 0.0, 0.0, 0.0), 0.0);
+
+        assert_octaves(cutoff(centre, 0.0, 1.0, 0.0), 1.0);
+        assert_octaves(cutoff(centre, 0.0, 0.0, 1.0), 1.0);
+        assert_octaves(cutoff(2.0 * centre, 1.0, 1.0, -0.5), 1.5);
+    }
+
+    #[test]
+    fn velocity_is_curved_once_for_the_level_and_the_mod_matrix() {
+        let play = |velocity: f32, curve: VelocityCurve, depth: f32| {
+            let mut voice = Voice::new(48000, 220.0);
+            voice.set_velocity_curve(curve);
+            voice.set_velocity_depth(depth);
+            voice.play(note(57), 220.0, velocity);
+            let output = render(&mut voice, 2000, 64);
+            let output: Vec<f32> = output.iter().map(|sample| sample.left).collect();
+            (voice, output)
+        };
+        let (_, full) = play(1.0, VelocityCurve::Linear, 1.0);
+
+        // Without any depth every note plays at full level, however soft
+        for velocity in [0.0, 0.3, 1.0] {
+            let (_, output) = play(velocity, VelocityCurve::Exponential, 0.0);
+            assert_eq!(output, full, "velocity {velocity}");
+        }
+
+        // At full depth the level follows the curved velocity, which is also what the mod
+        // matrix's velocity source reads
+        let (mut voice, soft) = play(0.5, VelocityCurve::Exponential, 1.0);
+        let curved = VelocityCurve::Exponential.apply(0.5);
+        assert_eq!(voice.velocity(), curved);
+        for (soft, full) in soft.iter().zip(full.iter()) {
+            assert!((soft - curved * full).abs() < 1e-6, "{soft} {full}");
+        }
+
+        // Changing the curve reshapes the note already playing
+        voice.set_velocity_curve(VelocityCurve::Logarithmic);
+        assert_eq!(voice.velocity(), VelocityCurve::Logarithmic.apply(0.5));
+    }
+}
@@ -0,0 +1,471 @@
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use crate::fft::{fft, ifft};
+use crate::poly_blep::{self, HardSync, OscillatorQuality};
+use crate::saw_wave::SawWave;
+use crate::sine_wave::SineWave;
+use crate::square_wave::SquareWave;
+use crate::stereo_sample::StereoSample;
+use crate::traits::AudioSource;
+
+/// Every frame is stored with this many samples per cycle, whatever size it was imported at.
+pub const FRAME_SIZE: usize = 2048;
+
+/// The frame size most single-cycle wavetable WAV files use.
+pub const DEFAULT_WAV_FRAME_SIZE: usize = 2048;
+
+// The most harmonics a frame can hold
+const MAX_HARMONIC: usize = FRAME_SIZE / 2;
+// One mipmap level per octave, each with half the harmonics of the previous one, down to a
+// single sine
+const NUM_LEVELS: usize = MAX_HARMONIC.trailing_zeros() as usize + 1;
+// Every table has a copy of its first sample at the end, so interpolation never has to wrap
+const TABLE_LEN: usize = FRAME_SIZE + 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WavetableError {
+    NotWav,
+    /// Only integer PCM and 32-bit float WAV files are supported.
+    UnsupportedFormat,
+    NoSamples,
+    /// The frame size has to be a power of two, and the file at least one frame long.
+    BadFrameSize,
+}
+
+impl fmt::Display for WavetableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WavetableError::NotWav => write!(f, "not a WAV file"),
+            WavetableError::UnsupportedFormat => write!(f, "unsupported WAV sample format"),
+            WavetableError::NoSamples => write!(f, "the WAV file has no samples"),
+            WavetableError::BadFrameSize => write!(f, "the frame size doesn't fit the WAV file"),
+        }
+    }
+}
+
+/// A set of single-cycle frames, each stored as per-octave mipmaps where every level only holds
+/// the harmonics that fit below Nyquist for notes in that octave.
+pub struct Wavetable {
+    // Frame by frame, level by level, `TABLE_LEN` samples each
+    tables: Vec<f32>,
+    num_frames: usize,
+}
+
+impl Wavetable {
+    /// Builds a wavetable from single-cycle frames, `frame_size` samples each, one after the
+    /// other. Frames of other sizes than [`FRAME_SIZE`] are resampled.
+    pub fn from_samples(samples: &[f32], frame_size: usize) -> Result<Self, WavetableError> {
+        if samples.is_empty() {
+            return Err(WavetableError::NoSamples);
+        }
+        if !frame_size.is_power_of_two() || frame_size < 2 || samples.len() < frame_size {
+            return Err(WavetableError::BadFrameSize);
+        }
+
+        let num_frames = samples.len() / frame_size;
+        let mut tables = Vec::with_capacity(num_frames * NUM_LEVELS * TABLE_LEN);
+
+        for frame in samples.chunks_exact(frame_size) {
+            let (spectrum_re, spectrum_im) = frame_spectrum(frame);
+
+            for level in 0..NUM_LEVELS {
+                let max_harmonic = MAX_HARMONIC >> level;
+                let mut re = vec![0.0; FRAME_SIZE];
+                let mut im = vec![0.0; FRAME_SIZE];
+
+                // Everything above the level's highest harmonic is dropped, DC and Nyquist too
+                for harmonic in 1..max_harmonic.min(MAX_HARMONIC - 1) + 1 {
+                    re[harmonic] = spectrum_re[harmonic];
+                    im[harmonic] = spectrum_im[harmonic];
+                    re[FRAME_SIZE - harmonic] = spectrum_re[harmonic];
+                    im[FRAME_SIZE - harmonic] = -spectrum_im[harmonic];
+                }
+                ifft(&mut re, &mut im);
+
+                tables.extend(re.iter().map(|&x| x as f32));
+                tables.push(re[0] as f32);
+            }
+        }
+
+        Ok(Self { tables, num_frames })
+    }
+
+    /// Reads a single-cycle wavetable WAV file: frames of `frame_size` samples concatenated
+    /// into one file. Only the first channel is used.
+    pub fn from_wav(bytes: &[u8], frame_size: usize) -> Result<Self, WavetableError> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(WavetableError::NotWav);
+        }
+
+        let mut format = None;
+        let mut data = None;
+
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let id = &bytes[pos..pos + 4];
+            let size = read_u32(&bytes[pos + 4..]) as usize;
+            let body = &bytes[pos + 8..(pos + 8).saturating_add(size).min(bytes.len())];
+
+            match id {
+                b"fmt " if body.len() >= 16 => format = Some(body),
+                b"data" => data = Some(body),
+                _ => {}
+            }
+
+            // Chunks are padded to an even length
+            pos = pos.saturating_add(8 + size + (size & 1));
+        }
+
+        let (Some(format), Some(data)) = (format, data) else {
+            return Err(WavetableError::NotWav);
+        };
+
+        let mut audio_format = read_u16(format);
+        let channels = read_u16(&format[2..]) as usize;
+        let bits = read_u16(&format[14..]) as usize;
+
+        // WAVE_FORMAT_EXTENSIBLE keeps the actual format at the start of the sub-format GUID
+        if audio_format == 0xFFFE && format.len() >= 26 {
+            audio_format = read_u16(&format[24..]);
+        }
+
+        let bytes_per_sample = bits / 8;
+        if channels == 0 || !matches!((audio_format, bits), (1, 8 | 16 | 24 | 32) | (3, 32)) {
+            return Err(WavetableError::UnsupportedFormat);
+        }
+
+        let samples: Vec<f32> = data
+            .chunks_exact(bytes_per_sample * channels)
+            .map(|sample_frame| {
+                let sample = &sample_frame[..bytes_per_sample];
+                match (audio_format, bits) {
+                    (3, _) => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+                    // 8-bit WAV samples are the only unsigned ones
+                    (_, 8) => (sample[0] as f32 - 128.0) / 128.0,
+                    (_, 16) => i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0,
+                    (_, 24) => {
+                        i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) as f32 / 2147483648.0
+                    }
+                    _ => {
+                        i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) as f32
+                            / 2147483648.0
+                    }
+                }
+            })
+            .collect();
+
+        Self::from_samples(&samples, frame_size)
+    }
+
+    /// The built-in table: one frame each of the sine, saw and square oscillators. Built once
+    /// and shared.
+    pub fn basic_shapes() -> Arc<Wavetable> {
+        static BASIC_SHAPES: OnceLock<Arc<Wavetable>> = OnceLock::new();
+
+        BASIC_SHAPES
+            .get_or_init(|| {
+                // At a sample rate of `FRAME_SIZE`, 1 Hz renders exactly one cycle per frame.
+                // The naive shapes are fine here since mipmapping band-limits them anyway.
+                let sample_rate = FRAME_SIZE as u32;
+                let mut saw = SawWave::new(sample_rate, 1.0);
+                saw.set_quality(OscillatorQuality::Draft);
+                let mut square = SquareWave::new(sample_rate, 1.0);
+                square.set_quality(OscillatorQuality::Draft);
+                let shapes: [&mut dyn AudioSource; 3] =
+                    [&mut SineWave::new(sample_rate, 1.0), &mut saw, &mut square];
+
+                let mut samples = Vec::with_capacity(shapes.len() * FRAME_SIZE);
+                let mut frame = vec![StereoSample::from_mono(0.0); FRAME_SIZE];
+                for shape in shapes {
+                    shape.fill_block(&mut frame);
+                    samples.extend(frame.iter().map(|s| s.left));
+                }
+
+                Arc::new(Wavetable::from_samples(&samples, FRAME_SIZE).expect("Valid built-in frames"))
+            })
+            .clone()
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    fn table(&self, frame: usize, level: usize) -> &[f32] {
+        let start = (frame * NUM_LEVELS + level) * TABLE_LEN;
+        &self.tables[start..start + TABLE_LEN]
+    }
+}
+
+/// The spectrum of a single cycle, resampled to `FRAME_SIZE` bins.
+fn frame_spectrum(frame: &[f32]) -> (Vec<f64>, Vec<f64>) {
+    let mut re: Vec<f64> = frame.iter().map(|&x| x as f64).collect();
+    let mut im = vec![0.0; frame.len()];
+    fft(&mut re, &mut im);
+
+    // Normalised so the harmonic amplitudes don't depend on the frame size
+    let scale = FRAME_SIZE as f64 / frame.len() as f64;
+    let harmonics = (frame.len() / 2).min(MAX_HARMONIC);
+    let mut spectrum_re = vec![0.0; FRAME_SIZE];
+    let mut spectrum_im = vec![0.0; FRAME_SIZE];
+    for harmonic in 1..harmonics {
+        spectrum_re[harmonic] = re[harmonic] * scale;
+        spectrum_im[harmonic] = im[harmonic] * scale;
+    }
+
+    (spectrum_re, spectrum_im)
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Plays a [`Wavetable`], morphing between its frames with the wavetable position.
+#[derive(Clone)]
+pub struct WavetableOscillator {
+    wavetable: Arc<Wavetable>,
+    phase: f32,
+    pub frequency: f32,
+    sample_rate: u32,
+    // 0 is the first frame, 1 the last one
+    position: f32,
+    sync: HardSync,
+    // Where the phase wraps after the next sample, for syncing other oscillators to this one
+    next_wrap: Option<f32>,
+}
+
+impl WavetableOscillator {
+    pub fn new(sample_rate: u32, freq: f32, wavetable: Arc<Wavetable>) -> Self {
+        Self {
+            wavetable,
+            phase: 0.0,
+            frequency: freq.min(sample_rate as f32 / 2.0),
+            sample_rate,
+            position: 0.0,
+            sync: HardSync::default(),
+            next_wrap: None,
+        }
+    }
+
+    pub fn set_wavetable(&mut self, wavetable: Arc<Wavetable>) {
+        self.wavetable = wavetable;
+    }
+
+    /// Sets the position (0..1) between the first and last frame. Cheap enough to modulate
+    /// every sample.
+    pub fn set_position(&mut self, position: f32) {
+        self.position = position.clamp(0.0, 1.0);
+    }
+
+    /// The mipmap level with the most harmonics that all stay below Nyquist.
+    fn level(increment: f32) -> usize {
+        let mut level = 0;
+        while level < NUM_LEVELS - 1 && (MAX_HARMONIC >> level) as f32 * increment > 0.5 {
+            level += 1;
+        }
+        level
+    }
+
+    /// The table's value at `phase`, at the current position and mipmap `level`.
+    fn read(&self, phase: f32, level: usize) -> f32 {
+        let frame_position = self.position * (self.wavetable.num_frames() - 1) as f32;
+        let frame = (frame_position as usize).min(self.wavetable.num_frames() - 1);
+        let next_frame = (frame + 1).min(self.wavetable.num_frames() - 1);
+        let frame_mix = frame_position - frame as f32;
+
+        let table_position = phase * FRAME_SIZE as f32;
+        let index = (table_position as usize).min(FRAME_SIZE - 1);
+        let index_mix = table_position - index as f32;
+
+        let read = |table: &[f32]| table[index] + (table[index + 1] - table[index]) * index_mix;
+        let current = read(self.wavetable.table(frame, level));
+        let next = read(self.wavetable.table(next_frame, level));
+        current + (next - current) * frame_mix
+    }
+
+    fn render(&mut self, increment: f32, level: usize) -> f32 {
+        let sample = self.read(self.phase, level);
+
+        // The table is one smooth cycle, only a sync restart can jump. Its slope is read across
+        // one table step either side.
+        let step = 1.0 / FRAME_SIZE as f32;
+        let naive = |phase: f32| {
+            let slope = self.read(poly_blep::wrap_phase(phase + step), level)
+                - self.read(poly_blep::wrap_phase(phase - step), level);
+            (self.read(phase, level), slope / (2.0 * step))
+        };
+
+        // Copied out, as working out the jump needs to read the table through `self`
+        let mut sync = self.sync;
+        let (sample, restart) = sync.process(sample, self.phase, increment, OscillatorQuality::High, &[], naive);
+        self.sync = sync;
+
+        // Increment the phase by frequency / sample_rate, unless a hard sync restarted it.
+        match restart {
+            Some(phase) => self.phase = phase,
+            None => poly_blep::advance_phase(&mut self.phase, increment),
+        }
+        self.next_wrap = poly_blep::next_wrap(self.phase, increment);
+
+        sample
+    }
+}
+
+impl AudioSource for WavetableOscillator {
+    fn next_sample(&mut self) -> StereoSample {
+        let increment = self.frequency / self.sample_rate as f32;
+        let sample = self.render(increment, Self::level(increment));
+
+        StereoSample { left: sample, right: sample }
+    }
+
+    fn fill_block(&mut self, block: &mut [StereoSample]) {
+        // The frequency can't change mid-block, so neither can the mipmap level
+        let increment = self.frequency / self.sample_rate as f32;
+        let level = Self::level(increment);
+
+        for out in block.iter_mut() {
+            let sample = self.render(increment, level);
+            *out = StereoSample { left: sample, right: sample };
+        }
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        // Limit to Nyquist frequency (half the sample rate).
+        self.frequency = freq.min(self.sample_rate as f32 / 2.0);
+    }
+
+    fn set_phase(&mut self, phase: f32) {
+        self.phase = phase.rem_euclid(1.0);
+    }
+
+    fn sync(&mut self, fraction: f32) {
+        self.sync.trigger(fraction);
+    }
+
+    fn next_wrap(&self) -> Option<f32> {
+        self.next_wrap
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.sync = HardSync::default();
+        self.next_wrap = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    /// A WAV file with a single "fmt " and "data" chunk.
+    fn wav(audio_format: u16, channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut format = Vec::new();
+        format.extend(audio_format.to_le_bytes());
+        format.extend(channels.to_le_bytes());
+        format.extend(48000u32.to_le_bytes());
+        format.extend((48000 * block_align as u32).to_le_bytes());
+        format.extend(block_align.to_le_bytes());
+        format.extend(bits.to_le_bytes());
+
+        let mut bytes = Vec::new();
+        bytes.extend(b"RIFF");
+        bytes.extend((4 + 8 + format.len() as u32 + 8 + data.len() as u32).to_le_bytes());
+        bytes.extend(b"WAVE");
+        for (id, chunk) in [(b"fmt ", &format[..]), (b"data", data)] {
+            bytes.extend(id);
+            bytes.extend((chunk.len() as u32).to_le_bytes());
+            bytes.extend(chunk);
+        }
+        bytes
+    }
+
+    fn sine(frame_size: usize) -> impl Iterator<Item = f32> {
+        (0..frame_size).map(move |index| (TAU * index as f32 / frame_size as f32).sin())
+    }
+
+    /// Checks the full bandwidth table of `frame` holds a sine (at `amplitude`).
+    fn assert_sine(wavetable: &Wavetable, frame: usize, amplitude: f32) {
+        let table = wavetable.table(frame, 0);
+        for (index, (&actual, expected)) in table.iter().zip(sine(FRAME_SIZE)).enumerate() {
+            assert!((actual - amplitude * expected).abs() < 1e-3, "sample {index}: {actual} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn reads_16_bit_frames_and_resamples_them() {
+        let data: Vec<u8> = sine(256).flat_map(|x| ((x * 16384.0) as i16).to_le_bytes()).collect();
+        let wavetable = Wavetable::from_wav(&wav(1, 1, 16, &data), 256).unwrap();
+
+        assert_eq!(wavetable.num_frames(), 1);
+        assert_sine(&wavetable, 0, 0.5);
+    }
+
+    #[test]
+    fn reads_the_first_channel_of_24_bit_and_float_files() {
+        // A silent frame then a sine, with full scale noise on the second channel
+        let samples: Vec<f32> = std::iter::repeat_n(0.0, 64).chain(sine(64)).collect();
+
+        let data: Vec<u8> = samples
+            .iter()
+            .flat_map(|&x| {
+                let left = ((x * 4_194_304.0) as i32).to_le_bytes();
+                [left[0], left[1], left[2], 0xFF, 0xFF, 0x7F]
+            })
+            .collect();
+        let wavetable = Wavetable::from_wav(&wav(1, 2, 24, &data), 64).unwrap();
+        assert_eq!(wavetable.num_frames(), 2);
+        assert_sine(&wavetable, 0, 0.0);
+        assert_sine(&wavetable, 1, 0.5);
+
+        let data: Vec<u8> = samples.iter().flat_map(|&x| [x, 1.0]).flat_map(f32::to_le_bytes).collect();
+        let wavetable = Wavetable::from_wav(&wav(3, 2, 32, &data), 64).unwrap();
+        assert_sine(&wavetable, 1, 1.0);
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_read() {
+        let frame: Vec<u8> = sine(64).flat_map(|x| ((x * 16384.0) as i16).to_le_bytes()).collect();
+        let error = |bytes: &[u8], frame_size| Wavetable::from_wav(bytes, frame_size).err();
+
+        assert_eq!(error(b"not a wav file", 64), Some(WavetableError::NotWav));
+        // ADPCM
+        assert_eq!(error(&wav(2, 1, 4, &frame), 64), Some(WavetableError::UnsupportedFormat));
+        assert_eq!(error(&wav(1, 1, 16, &[]), 64), Some(WavetableError::NoSamples));
+        // Not a power of two, and longer than the file
+        assert_eq!(error(&wav(1, 1, 16, &frame), 48), Some(WavetableError::BadFrameSize));
+        assert_eq!(error(&wav(1, 1, 16, &frame), 128), Some(WavetableError::BadFrameSize));
+    }
+
+    #[test]
+    fn mipmap_keeps_every_harmonic_below_nyquist() {
+        let sample_rate = 48000.0;
+
+        // Low notes get the full table
+        assert_eq!(WavetableOscillator::level(20.0 / sample_rate), 0);
+
+        for note in 0..128 {
+            let increment = 440.0 * ((note as f32 - 69.0) / 12.0).exp2() / sample_rate;
+            let level = WavetableOscillator::level(increment);
+            let highest = |level: usize| (MAX_HARMONIC >> level) as f32 * increment;
+
+            // The level with the most harmonics that all fit, short of running out of levels
+            assert!(highest(level) <= 0.5 || level == NUM_LEVELS - 1, "note {note}");
+            assert!(level == 0 || highest(level - 1) > 0.5, "note {note}");
+        }
+
+        // The last level is a plain sine, which is all that fits right up to Nyquist
+        let basic_shapes = Wavetable::basic_shapes();
+        let saw = basic_shapes.table(1, NUM_LEVELS - 1);
+        let amplitude = saw.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+        for (&actual, expected) in saw.iter().zip(sine(FRAME_SIZE)) {
+            assert!((actual.abs() - (amplitude * expected).abs()).abs() < 1e-3);
+        }
+    }
+}